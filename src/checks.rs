@@ -0,0 +1,619 @@
+// The stages and checks a row goes through before its handler.
+
+use std::{error::Error, collections::{HashMap, HashSet, VecDeque}};
+use crate::{clock::*, config::*, options::*, rejects::*, types::*};
+
+
+
+//* Stages *//
+
+    // A step every decoded row goes through before the engine validates and applies it, in registration order.
+    // A stage can rewrite the row (enrichment), drop it silently by returning None (filtering), or reject it with an error,
+    // which observers see like any other rejection. The strict columns and blocklist checks are the built-in stages.
+    pub(crate) trait Stage {
+        fn process(&mut self, transaction : Transaction) -> Result<Option<Transaction>, Box<dyn Error>>;
+    }
+
+
+    // Renames the types found in the "[aliases]" section of the config, for inputs using another vocabulary.
+    pub(crate) struct TypeAliases<'a> {
+        pub(crate) aliases: &'a HashMap<String, String>,
+    }
+
+    impl Stage for TypeAliases<'_> {
+        fn process(&mut self, mut transaction : Transaction) -> Result<Option<Transaction>, Box<dyn Error>> {
+
+            if let Some(tx_type) = self.aliases.get(&transaction.tx_type) {
+                transaction.tx_type = tx_type.clone();
+            }
+
+            Ok(Some(transaction))
+        }
+    }
+
+
+    // In strict mode, a row carrying unrecognized columns is rejected instead of having them collected.
+    // The "--passthrough-columns" are expected, so they don't count as unrecognized.
+    pub(crate) struct StrictColumns<'a> {
+        pub(crate) passthrough_columns: &'a [String],
+    }
+
+    impl Stage for StrictColumns<'_> {
+        fn process(&mut self, transaction : Transaction) -> Result<Option<Transaction>, Box<dyn Error>> {
+
+            let mut columns: Vec<&str> = transaction.extra_fields
+                .keys()
+                .map(String::as_str)
+                .filter(|column| !self.passthrough_columns.iter().any(|passthrough| passthrough == column))
+                .collect();
+            if columns.is_empty() {
+                return Ok(Some(transaction));
+            }
+            columns.sort();
+
+            Err(Rejection::new(UNRECOGNIZED_COLUMNS, format!("Error! Transaction has unrecognized columns ({}). Ignoring.", columns.join(", "))).into())
+        }
+    }
+
+
+    pub(crate) struct BlocklistCheck<'a> {
+        pub(crate) blocklist: &'a HashMap<ClientId, String>,
+    }
+
+    impl Stage for BlocklistCheck<'_> {
+        fn process(&mut self, transaction : Transaction) -> Result<Option<Transaction>, Box<dyn Error>> {
+
+            match self.blocklist.get(&transaction.client_id) {
+                Some(reason) => Err(BlockedClient { reason: reason.clone() }.into()),
+                None => Ok(Some(transaction)),
+            }
+        }
+    }
+
+
+
+//* Checks *//
+
+    // Evaluates the "[rules]" section of the config against a row, before it reaches its handler.
+    pub(crate) fn check_rules(
+        rules : &Rules,
+        transaction : &Transaction,
+        sender : ClientId, // differs from the transaction's client for a co-owner of a joint account
+    ) -> Result<(), Box<dyn Error>> {
+
+        if rules.deny_clients.contains(&sender) || rules.deny_clients.contains(&transaction.client_id) {
+            return Err(Rejection::new(CLIENT_DENIED, "Error! Client is denied by the configured rules. Ignoring.").into());
+        }
+
+        if let (Some(max_amount), Some(amount)) = (rules.max_amount, transaction.amount) {
+            if amount > max_amount {
+                return Err(Rejection::new(AMOUNT_OVER_MAX, "Error! Amount exceeds the configured maximum. Ignoring.").into());
+            }
+        }
+
+        if rules.require_timestamp && transaction.timestamp.is_none() {
+            return Err(Rejection::new(MISSING_TIMESTAMP, "Error! Transaction has no timestamp, which the configured rules require. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // The "--blocklist" stage sees whoever sent the row, so for a co-owner the joint account itself is checked here.
+    pub(crate) fn check_blocked_account(
+        blocklist : &HashMap<ClientId, String>,
+        transaction : &Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        match blocklist.get(&transaction.client_id) {
+            Some(reason) => Err(BlockedClient { reason: reason.clone() }.into()),
+            None => Ok(()),
+        }
+    }
+
+
+    // Holds back a withdrawal that would take the client over the velocity limits within the rolling window.
+    // The window only keeps applied withdrawals, so a flagged one doesn't count against the next.
+    pub(crate) fn check_velocity(
+        velocity : &VelocityRules,
+        velocity_windows : &mut HashMap<ClientId, VecDeque<(i64, f64)>>,
+        transaction : &Transaction,
+        clock : &dyn Clock,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let (Some(timestamp), Some(amount)) = (clock.now(), transaction.amount) else { return Ok(()) };
+
+        if !velocity.is_enabled() || transaction.tx_type != "withdrawal" {
+            return Ok(());
+        }
+
+        let window = velocity_windows.entry(transaction.client_id).or_default();
+
+        while window.front().is_some_and(|(oldest, _)| timestamp.checked_sub(velocity.window_seconds).is_some_and(|start| *oldest <= start)) {
+            window.pop_front();
+        }
+
+        if let Some(max_withdrawals) = velocity.max_withdrawals {
+            if window.len() + 1 > max_withdrawals {
+                return Err(FlaggedForReview { code: FLAGGED_FOR_REVIEW, reason: format!("more than {} withdrawals within {} seconds", max_withdrawals, velocity.window_seconds) }.into());
+            }
+        }
+
+        let window_sum: f64 = window.iter().map(|(_, amount)| amount).sum();
+
+        if let Some(max_sum) = velocity.max_withdrawal_sum {
+            if window_sum + amount > max_sum {
+                return Err(FlaggedForReview { code: FLAGGED_FOR_REVIEW, reason: format!("withdrawals above {} within {} seconds", format_amount(max_sum), velocity.window_seconds) }.into());
+            }
+        }
+
+        Ok(())
+    }
+
+
+    // Unverified clients can't move funds out of their account (withdrawals or holds),
+    // and can only deposit up to the configured cumulative cap.
+    pub(crate) fn check_kyc(
+        kyc : Option<&HashMap<ClientId, KycStatus>>,
+        kyc_rules : &KycRules,
+        kyc_deposits : &HashMap<ClientId, f64>,
+        transaction : &Transaction,
+        sender : ClientId, // differs from the transaction's client for a co-owner of a joint account
+    ) -> Result<(), Box<dyn Error>> {
+
+        let Some(kyc) = kyc else { return Ok(()) };
+
+        if kyc.get(&sender) == Some(&KycStatus::Verified) {
+            return Ok(());
+        }
+
+        match transaction.tx_type.as_str() {
+            "withdrawal" | "hold" => Err(Rejection::new(KYC_WITHDRAWAL, "Error! KYC: unverified clients can't withdraw. Ignoring.").into()),
+            "deposit" => {
+                let deposited = kyc_deposits.get(&sender).copied().unwrap_or(0.0);
+                let amount = transaction.amount.unwrap_or(0.0);
+
+                match kyc_rules.unverified_deposit_cap {
+                    Some(cap) if deposited + amount > cap => Err(Rejection::new(KYC_DEPOSIT_CAP, "Error! KYC: deposit exceeds the cap for unverified clients. Ignoring.").into()),
+                    _ => Ok(()),
+                }
+            },
+            _ => Ok(()),
+        }
+    }
+
+
+    // Keeps track of an applied withdrawal for the time-based policies, when they're enabled.
+    pub(crate) fn record_timed_withdrawal(
+        config : &EngineConfig,
+        limits : &Limits, // the client's own, once overridden
+        velocity_windows : &mut HashMap<ClientId, VecDeque<(i64, f64)>>,
+        daily_withdrawals : &mut HashMap<ClientId, (i64, f64)>,
+        client_id : ClientId,
+        timestamp : i64,
+        amount : f64,
+    ) {
+
+        if config.velocity.is_enabled() {
+            velocity_windows.entry(client_id).or_default().push_back((timestamp, amount));
+        }
+
+        if limits.daily_withdrawal_cap.is_some() {
+            let day = timestamp.div_euclid(86_400);
+            let withdrawn = daily_withdrawals.entry(client_id).or_insert((day, 0.0));
+
+            if withdrawn.0 != day {
+                *withdrawn = (day, 0.0);
+            }
+            withdrawn.1 += amount;
+        }
+    }
+
+
+    // Rejects a withdrawal that would take the client's total for the (UTC) day above the configured cap.
+    pub(crate) fn check_daily_limit(
+        limits : &Limits,
+        daily_withdrawals : &HashMap<ClientId, (i64, f64)>,
+        transaction : &Transaction,
+        clock : &dyn Clock,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let (Some(cap), Some(timestamp), Some(amount)) = (limits.daily_withdrawal_cap, clock.now(), transaction.amount) else { return Ok(()) };
+
+        if transaction.tx_type != "withdrawal" {
+            return Ok(());
+        }
+
+        let withdrawn_today = match daily_withdrawals.get(&transaction.client_id) {
+            Some((day, withdrawn)) if *day == timestamp.div_euclid(86_400) => *withdrawn,
+            _ => 0.0,
+        };
+
+        if withdrawn_today + amount > cap {
+            return Err(Rejection::new(DAILY_LIMIT, "Error! Attempting to withdraw above the daily withdrawal limit. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Rejects a withdrawal that would leave less than the required reserve available in its wallet. For tiers that allow negative balances
+    // the reserve is the floor of the overdraft, so a negative reserve is how far below zero they may go.
+    // Withdrawals that can't be covered at all otherwise are left for "try_withdrawal" to reject as insufficient balance.
+    pub(crate) fn check_reserve(
+        limits : &Limits,
+        client_limits : &HashMap<ClientId, ClientLimits>,
+        client_data_map : &HashMap<ClientId, ClientData>,
+        transaction : &Transaction,
+        tier_limits : &TierLimits,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let reserve = client_limits
+            .get(&transaction.client_id)
+            .and_then(|cl| cl.reserve)
+            .or(limits.reserve);
+
+        let (Some(reserve), Some(amount), Some(cd)) = (reserve, transaction.amount, client_data_map.get(&transaction.client_id)) else { return Ok(()) };
+
+        // the row's wallet is what pays for it, so the other wallets can't count towards its reserve
+        let available = cd.wallet_available(&transaction.wallet);
+        let covered = available >= amount || tier_limits.allow_negative;
+
+        if transaction.tx_type == "withdrawal" && covered && available - amount < reserve {
+            return Err(Rejection::new(RESERVE_REQUIRED, "Error! Attempting to withdraw below the required reserve. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Deposits and withdrawals can't reuse the ID of a hold. The other way around is checked by "try_hold".
+    pub(crate) fn check_hold_ids(
+        holds : &HashMap<TxId, Hold>,
+        transaction : &Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if matches!(transaction.tx_type.as_str(), "deposit" | "withdrawal") && holds.contains_key(&transaction.tx_id) {
+            return Err(Rejection::new(DUPLICATE_TX, "Error! Transaction ID already exists. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Under the deposits-only dispute policy, the withdrawals' IDs can't be reused, nor referenced by the dispute flow or a reversal.
+    pub(crate) fn check_withdrawal_ids(
+        withdrawal_ids : &HashSet<TxId>,
+        transaction : &Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if !withdrawal_ids.contains(&transaction.tx_id) {
+            return Ok(());
+        }
+
+        match transaction.tx_type.as_str() {
+            "deposit" | "withdrawal" | "hold" => Err(Rejection::new(DUPLICATE_TX, "Error! Transaction ID already exists. Ignoring.").into()),
+            "dispute" | "resolve" | "chargeback" | "chargeback_reversal" | "reversal" =>
+                Err(Rejection::new(WITHDRAWAL_NOT_DISPUTABLE, "Error! Withdrawals can't be disputed or reversed under the deposits-only policy. Ignoring.").into()),
+            _ => Ok(()),
+        }
+    }
+
+
+    // The row types only the operator may send, as they lift the locks and freezes the engine puts on accounts, or roll the whole state back.
+    pub(crate) const ADMIN_TYPES: [&str; 6] = ["freeze", "unfreeze", "clear_dispute_locks", "clear_chargeback_freeze", "savepoint", "rollback_to"];
+
+    // Admin rows are only taken from the "--admin-input" file, and that file only takes admin rows.
+    pub(crate) fn check_admin_source(transaction : Transaction) -> Result<Transaction, Box<dyn Error>> {
+
+        match (ADMIN_TYPES.contains(&transaction.tx_type.as_str()), transaction.admin) {
+            (true, false) => Err(Rejection::new(ADMIN_ONLY, "Error! Admin rows are only taken from the --admin-input file. Ignoring.").into()),
+            (false, true) => Err(Rejection::new(NOT_ADMIN, "Error! The --admin-input file only takes admin rows. Ignoring.").into()),
+            _ => Ok(transaction),
+        }
+    }
+
+
+    // Whether a row is timestamped after the "--as-of" date. Undated rows never are.
+    pub(crate) fn is_past_as_of(as_of : Option<i64>, transaction : &Transaction) -> bool {
+        transaction.timestamp.zip(as_of).is_some_and(|(timestamp, as_of)| timestamp > as_of)
+    }
+
+
+    // Rejects transactions that reference an ID already evicted by the retention policy.
+    // Deposits and withdrawals would otherwise be able to reuse the ID of a pruned transaction.
+    pub(crate) fn check_retention(
+        pruned_ids : &HashSet<TxId>,
+        transaction : &Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if !pruned_ids.contains(&transaction.tx_id) {
+            return Ok(());
+        }
+
+        match transaction.tx_type.as_str() {
+            "deposit" | "withdrawal" => Err(Rejection::new(DUPLICATE_TX, "Error! Transaction ID already exists. Ignoring.").into()),
+            _ => Err(Rejection::new(TX_PRUNED, "Error! Transaction is too old, it was pruned by the retention policy. Ignoring.").into()),
+        }
+    }
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{engine::*, observers::*, outputs::*, testing::*};
+
+        #[test]
+        fn strict_columns_reject_rows_with_unrecognized_columns() {
+            let input = rows("\
+                type,client,tx,amount,channel
+                deposit,1,1,100,
+                deposit,1,2,50,web
+            ");
+            let mut rejections = Rejections::default();
+            let state = process_transactions(
+                &Options::default(),
+                &mut StagedOutputs::default(),
+                &mut Rows(input.into()),
+                HashMap::new(),
+                &mut [&mut StrictColumns { passthrough_columns: &[] }],
+                &mut [&mut rejections],
+            ).unwrap();
+
+            assert_eq!(rejections.0, [(1, UNRECOGNIZED_COLUMNS), (2, UNRECOGNIZED_COLUMNS)], "an empty value is still a column");
+            assert!(state.clients.is_empty());
+        }
+
+        #[test]
+        fn strict_columns_name_every_unrecognized_column() {
+            let transaction = rows("type,client,tx,amount,zone,channel\ndeposit,1,1,10,eu,web\n").remove(0);
+            let err = StrictColumns { passthrough_columns: &[] }.process(transaction).unwrap_err();
+
+            assert_eq!(err.to_string(), "Error! Transaction has unrecognized columns (channel, zone). Ignoring.");
+        }
+
+        #[test]
+        fn rules_are_checked_before_the_handlers() {
+            let mut options = Options::default();
+            options.config.rules = Rules { max_amount: Some(1000.0), deny_clients: HashSet::from([13]), require_timestamp: true, allow_zero_amounts: false };
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,1000,10
+                deposit,1,2,1000.01,20
+                deposit,13,3,50,30
+                withdrawal,1,4,10,
+                dispute,1,1,,
+                withdrawal,1,5,10,40
+            ");
+
+            assert_eq!(rejections, [(2, AMOUNT_OVER_MAX), (3, CLIENT_DENIED), (4, MISSING_TIMESTAMP), (1, MISSING_TIMESTAMP)]);
+            assert_eq!(state.clients[&1].total, 990.0);
+            assert!(!state.clients.contains_key(&13));
+        }
+
+        #[test]
+        fn velocity_flags_withdrawals_over_the_count_within_the_window() {
+            let mut options = Options::default();
+            options.config.velocity = VelocityRules { window_seconds: 600, max_withdrawals: Some(2), max_withdrawal_sum: None };
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,0
+                withdrawal,1,2,10,0
+                withdrawal,1,3,10,100
+                withdrawal,1,4,10,200
+                withdrawal,1,5,10,600
+                withdrawal,1,6,10,601
+                withdrawal,1,7,10,650
+                withdrawal,1,8,10,
+            ");
+
+            assert_eq!(rejections, [(4, FLAGGED_FOR_REVIEW), (6, FLAGGED_FOR_REVIEW), (7, FLAGGED_FOR_REVIEW)], "a withdrawal exactly a window ago has left it");
+            assert_eq!(state.clients[&1].total, 60.0, "an undated withdrawal isn't tracked");
+        }
+
+        #[test]
+        fn velocity_flags_withdrawals_over_the_sum_and_reports_them() {
+            let mut options = Options::default();
+            options.config.velocity = VelocityRules { window_seconds: 600, max_withdrawals: None, max_withdrawal_sum: Some(1000.0) };
+
+            let mut flags = FlagsReport::default();
+            let input = rows("\
+                type,client,tx,amount,timestamp
+                deposit,1,1,5000,0
+                withdrawal,1,2,600,0
+                withdrawal,1,3,500,10
+                withdrawal,1,4,400,20
+                withdrawal,1,5,0.5,30
+            ");
+            let state = process_transactions(&options, &mut StagedOutputs::default(), &mut Rows(input.into()), HashMap::new(), &mut [], &mut [&mut flags]).unwrap();
+
+            assert_eq!(state.clients[&1].total, 4000.0, "a flagged withdrawal doesn't count against the next");
+            assert_eq!(flags.rows, [
+                ["withdrawal", "1", "3", "500", "10", "withdrawals above 1000 within 600 seconds"].map(String::from),
+                ["withdrawal", "1", "5", "0.5", "30", "withdrawals above 1000 within 600 seconds"].map(String::from),
+            ]);
+        }
+
+        #[test]
+        fn blocklisted_clients_are_rejected_and_reported() {
+            let blocklist = HashMap::from([(2, "sanctions".to_string())]);
+            let input = rows("\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,2,2,50
+                dispute,1,1,
+                withdrawal,2,3,10
+            ");
+            let mut compliance = ComplianceReport::default();
+            let mut rejections = Rejections::default();
+            process_transactions(
+                &Options::default(),
+                &mut StagedOutputs::default(),
+                &mut Rows(input.into()),
+                funded(2, 80.0),
+                &mut [&mut BlocklistCheck { blocklist: &blocklist }],
+                &mut [&mut compliance, &mut rejections],
+            ).unwrap();
+
+            assert_eq!(rejections.0, [(2, BLOCKLISTED), (3, BLOCKLISTED)]);
+            assert_eq!(compliance.rows, [
+                ["deposit", "2", "2", "50", "", "sanctions"].map(String::from),
+                ["withdrawal", "2", "3", "10", "", "sanctions"].map(String::from),
+            ]);
+        }
+
+        #[test]
+        fn daily_cap_resets_at_midnight_utc() {
+            let mut options = Options::default();
+            options.config.limits.daily_withdrawal_cap = Some(100.0);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,1000,2024-03-01T08:00:00Z
+                withdrawal,1,2,60,2024-03-01T09:00:00Z
+                withdrawal,1,3,50,2024-03-01T10:00:00Z
+                withdrawal,1,4,40,2024-03-01T23:59:59Z
+                withdrawal,1,5,100,2024-03-02T00:00:00Z
+                withdrawal,1,6,0.01,2024-03-02T12:00:00Z
+                withdrawal,1,7,500,
+                deposit,2,8,1000,2024-03-02T12:00:00Z
+                withdrawal,2,9,100,2024-03-02T12:00:00Z
+            ");
+
+            assert_eq!(rejections, [(3, DAILY_LIMIT), (6, DAILY_LIMIT)]);
+            assert_eq!(state.clients[&1].total, 300.0, "an undated withdrawal isn't counted against the cap");
+            assert_eq!(state.clients[&2].total, 900.0, "the cap is per client");
+        }
+
+        #[test]
+        fn withdrawals_cant_dip_below_the_reserve() {
+            let mut options = Options::default();
+            options.config.limits.reserve = Some(50.0);
+            options.data.client_limits = HashMap::from([
+                (2, ClientLimits { reserve: Some(0.0), tier: Tier::Basic }),
+                (3, ClientLimits { reserve: None, tier: Tier::Basic }),
+            ]);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                withdrawal,1,2,60
+                withdrawal,1,3,50
+                withdrawal,1,4,200
+                deposit,2,5,100
+                withdrawal,2,6,100
+                deposit,3,7,100
+                withdrawal,3,8,51
+            ");
+
+            assert_eq!(rejections, [(2, RESERVE_REQUIRED), (4, INSUFFICIENT_FUNDS), (8, RESERVE_REQUIRED)]);
+            let totals: Vec<f64> = (1..=3).map(|client| state.clients[&client].total).collect();
+            assert_eq!(totals, [50.0, 0.0, 100.0], "a client's own reserve overrides the config's, and an empty one falls back to it");
+        }
+
+        #[test]
+        fn reserve_applies_to_each_wallet_on_its_own() {
+            let mut options = Options::default();
+            options.config.limits.reserve = Some(20.0);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,wallet
+                deposit,1,1,100,
+                deposit,1,2,30,savings
+                withdrawal,1,3,20,savings
+                withdrawal,1,4,80,main
+            ");
+
+            assert_eq!(rejections, [(3, RESERVE_REQUIRED)], "the main wallet can't count towards the reserve of savings");
+            assert_eq!(state.clients[&1].total, 50.0);
+        }
+
+        #[test]
+        fn unverified_clients_deposit_up_to_the_cap_and_cant_withdraw() {
+            let mut options = Options::default();
+            options.data.kyc = Some(HashMap::from([(1, KycStatus::Verified), (2, KycStatus::Unverified)]));
+            options.config.kyc.unverified_deposit_cap = Some(100.0);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,500
+                withdrawal,1,2,100
+                deposit,2,3,60
+                deposit,2,4,40.01
+                deposit,2,5,40
+                withdrawal,2,6,10
+                hold,2,7,10
+                deposit,3,8,100
+                deposit,3,9,1
+            ");
+
+            assert_eq!(rejections, [(4, KYC_DEPOSIT_CAP), (6, KYC_WITHDRAWAL), (7, KYC_WITHDRAWAL), (9, KYC_DEPOSIT_CAP)]);
+            let totals: Vec<f64> = (1..=3).map(|client| state.clients[&client].total).collect();
+            assert_eq!(totals, [400.0, 100.0, 100.0], "clients missing from the file are unverified");
+        }
+
+        #[test]
+        fn aliased_rows_are_processed_as_their_type() {
+            let aliases = HashMap::from([("credit".to_string(), "deposit".to_string()), ("debit".to_string(), "withdrawal".to_string())]);
+            let input = rows("\
+                type,client,tx,amount
+                Credit,1,1,100
+                DEBIT,1,2,30
+                debit,1,3,80
+                payout,1,4,5
+            ");
+            let mut rejections = Rejections::default();
+            let state = process_transactions(
+                &Options::default(),
+                &mut StagedOutputs::default(),
+                &mut Rows(input.into()),
+                HashMap::new(),
+                &mut [&mut TypeAliases { aliases: &aliases }],
+                &mut [&mut rejections],
+            ).unwrap();
+
+            assert_eq!(rejections.0, [(3, INSUFFICIENT_FUNDS), (4, UNKNOWN_TYPE)]);
+            assert_eq!(state.clients[&1].available, 70.0);
+        }
+
+        #[test]
+        fn country_and_segment_overrides_apply_to_their_clients_only() {
+            let mut options = Options::default();
+            options.config.limits.daily_withdrawal_cap = Some(100.0);
+            options.config.countries.insert("BR".to_string(), PolicyOverrides { daily_withdrawal_cap: Some(20.0), ..PolicyOverrides::default() });
+            options.config.countries.insert("PT".to_string(), PolicyOverrides { reserve: Some(960.0), ..PolicyOverrides::default() });
+            options.config.segments.insert("vip".to_string(), PolicyOverrides { daily_withdrawal_cap: Some(1000.0), ..PolicyOverrides::default() });
+            let meta = |country : &str, segment : &str| ClientMeta { name: String::new(), country: country.to_string(), segment: segment.to_string() };
+            options.data.clients_meta = Some(HashMap::from([(1, meta("BR", "retail")), (2, meta("BR", "vip")), (3, meta("PT", "")), (4, meta("PT", ""))]));
+            options.data.client_limits = HashMap::from([(4, ClientLimits { reserve: Some(0.0), tier: Tier::Basic })]);
+
+            let (_, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,1000,2024-03-01T08:00:00Z
+                deposit,2,2,1000,2024-03-01T08:00:00Z
+                deposit,3,3,1000,2024-03-01T08:00:00Z
+                deposit,4,4,1000,2024-03-01T08:00:00Z
+                deposit,5,5,1000,2024-03-01T08:00:00Z
+                withdrawal,1,6,50,2024-03-01T09:00:00Z
+                withdrawal,2,7,500,2024-03-01T09:00:00Z
+                withdrawal,3,8,50,2024-03-01T09:00:00Z
+                withdrawal,4,9,50,2024-03-01T09:00:00Z
+                withdrawal,5,10,150,2024-03-01T09:00:00Z
+            ");
+
+            assert_eq!(rejections, [(6, DAILY_LIMIT), (8, RESERVE_REQUIRED), (10, DAILY_LIMIT)], "a reserve from the limits file wins over the country's");
+        }
+
+        #[test]
+        fn strict_columns_expect_the_passthrough_columns() {
+            let columns = ["ref".to_string()];
+            let mut strict = StrictColumns { passthrough_columns: &columns };
+
+            assert!(strict.process(rows("type,client,tx,amount,ref\ndeposit,1,1,10,A-1\n").remove(0)).is_ok());
+            assert_eq!(code_of(strict.process(rows("type,client,tx,amount,ref,channel\ndeposit,1,1,10,A-1,web\n").remove(0)).map(|_| ())), UNRECOGNIZED_COLUMNS);
+        }
+    }
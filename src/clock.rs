@@ -0,0 +1,362 @@
+// The time the engine goes by, read off the rows, and the scheduling of the rows dated later.
+
+use std::collections::BTreeMap;
+use crate::{checks::*, sources::*, types::*};
+
+
+
+//* Dates *//
+
+    // Accepts either unix seconds or an ISO 8601 date ("2024-03-01"), optionally with a UTC time ("2024-03-01T10:30:00Z").
+    pub(crate) fn parse_timestamp(value : &str) -> Option<i64> {
+
+        if let Ok(seconds) = value.parse::<i64>() {
+            return Some(seconds);
+        }
+
+        let (date, time) = match value.split_once(['T', ' ']) {
+            Some((date, time)) => (date, time.trim_end_matches('Z')),
+            None => (value, "00:00:00"),
+        };
+
+        let mut date_parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+        let (year, month, day) = (date_parts.next()??, date_parts.next()??, date_parts.next()??);
+
+        let mut time_parts = time.splitn(3, ':').map(|part| part.parse::<i64>().ok());
+        let hours = time_parts.next()??;
+        let minutes = time_parts.next().unwrap_or(Some(0))?;
+        let seconds = time_parts.next().unwrap_or(Some(0))?;
+
+        let in_range = (0..=9999).contains(&year) && (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day)
+            && (0..24).contains(&hours) && (0..60).contains(&minutes) && (0..=60).contains(&seconds);
+
+        in_range.then(|| days_from_civil(year, month, day) * 86_400 + hours * 3_600 + minutes * 60 + seconds)
+    }
+
+
+    // February has 29 days in the leap years of the Gregorian calendar: every fourth, except centuries not divisible by 400.
+    fn days_in_month(year : i64, month : i64) -> i64 {
+        match month {
+            2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+
+    // The inverse of days_from_civil, formatted as an ISO 8601 date (Howard Hinnant's "civil_from_days").
+    pub(crate) fn format_date(days : i64) -> String {
+        let days = days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_prime = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_prime + 2) / 5 + 1;
+        let month = if month_prime < 10 {month_prime + 3} else {month_prime - 9};
+        let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+
+    // Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's "days_from_civil").
+    pub(crate) fn days_from_civil(year : i64, month : i64, day : i64) -> i64 {
+        let year = if month <= 2 {year - 1} else {year};
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 {-3} else {9}) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        era * 146_097 + day_of_era - 719_468
+    }
+
+
+
+//* Scheduling *//
+
+    // The time the time-window rules go by: hold expiry, velocity windows, daily limits and the chargeback reversal window,
+    // as well as the dates disputes, chargebacks and holds are recorded at. The engine never looks at the wall clock,
+    // "InputClock" reads the time off the rows, so a file is processed the same way whenever it's run.
+    pub(crate) trait Clock {
+        // Moves the clock to the row about to be applied.
+        fn tick(&mut self, transaction : &Transaction);
+        // The time of the row being applied, None when it can't be told.
+        fn now(&self) -> Option<i64>;
+        // The latest time reached so far.
+        fn latest(&self) -> Option<i64>;
+    }
+
+
+    #[derive(Default)]
+    pub(crate) struct InputClock {
+        pub(crate) now: Option<i64>,
+        pub(crate) latest: Option<i64>,
+    }
+
+    impl Clock for InputClock {
+        fn tick(&mut self, transaction : &Transaction) {
+            self.now = transaction.timestamp;
+            if let Some(timestamp) = self.now {
+                self.latest = Some(self.latest.map_or(timestamp, |latest| latest.max(timestamp)));
+            }
+        }
+
+        fn now(&self) -> Option<i64> {
+            self.now
+        }
+
+        fn latest(&self) -> Option<i64> {
+            self.latest
+        }
+    }
+
+
+    // Reorders the input rows for scheduled transactions: a row whose value date is later than the latest timestamp seen so far
+    // is queued, and released just before the first row timestamped at or after its value date, as if it arrived then.
+    // With an "--as-of" date, the rows timestamped after it are passed on as they come, to be rejected, without moving the time forward,
+    // and whatever is still queued at the end of the input is released up to that date.
+    // Recurring definitions are expanded here too, into a scheduled transaction per occurrence.
+    pub(crate) struct Scheduler<S> {
+        pub(crate) rows: S,
+        pub(crate) pending: BTreeMap<(i64, usize), Transaction>, // (value date, arrival order) -> transaction
+        pub(crate) lookahead: Option<Transaction>, // a row held back while the scheduled transactions due before it are released
+        pub(crate) now: Option<i64>,
+        pub(crate) as_of: Option<i64>,
+        pub(crate) arrivals: usize,
+    }
+
+    impl<S> Scheduler<S> {
+        pub(crate) fn new(rows : S, as_of : Option<i64>) -> Self {
+            Scheduler { rows, pending: BTreeMap::new(), lookahead: None, now: None, as_of, arrivals: 0 }
+        }
+
+        pub(crate) fn schedule(&mut self, transaction : Transaction, value_date : i64) {
+            self.pending.insert((value_date, self.arrivals), transaction);
+            self.arrivals += 1;
+        }
+
+        // Queues an occurrence of a recurring definition per interval, starting at its timestamp, with consecutive tx IDs.
+        // Definitions that can't be expanded are passed on as they are, to be rejected.
+        pub(crate) fn expand(&mut self, definition : Transaction) -> Option<Transaction> {
+            let (Some(recurrence), Some(start)) = (&definition.recurrence, definition.timestamp) else { return Some(definition) };
+
+            let valid = matches!(recurrence.kind.as_str(), "deposit" | "withdrawal")
+                && recurrence.interval > 0
+                && recurrence.count > 0
+                && recurrence.count <= MAX_RECURRENCE
+                && definition.tx_id.checked_add(recurrence.count - 1).is_some()
+                && recurrence.interval.checked_mul((recurrence.count - 1) as i64).and_then(|span| start.checked_add(span)).is_some(); // the count is capped, so the cast is lossless
+
+            if !valid {
+                return Some(definition);
+            }
+
+            let mut value_date = start;
+            for occurrence in 0..recurrence.count {
+                if occurrence > 0 {
+                    value_date += recurrence.interval; // can't overflow, the last value date was checked above
+                }
+
+                let mut transaction = definition.clone();
+                transaction.tx_id += occurrence;
+                transaction.tx_type = recurrence.kind.clone();
+                transaction.recurrence = None;
+                transaction.value_date = Some(value_date);
+                self.schedule(transaction, value_date);
+            }
+
+            None
+        }
+
+        // Pops the earliest scheduled transaction if it's due by the given date, dating it on its value date.
+        pub(crate) fn pop_due(&mut self, date : Option<i64>) -> Option<Transaction> {
+            let entry = self.pending.first_entry()?;
+            if date.is_none_or(|date| entry.key().0 > date) {
+                return None;
+            }
+
+            let mut transaction = entry.remove();
+            transaction.timestamp = transaction.value_date;
+            Some(transaction)
+        }
+    }
+
+    impl<S: InputSource> InputSource for Scheduler<S> {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            loop {
+                if let Some(transaction) = self.pop_due(self.now) {
+                    return Some(Ok(transaction));
+                }
+                if let Some(transaction) = self.lookahead.take() {
+                    return Some(Ok(transaction));
+                }
+
+                let transaction = match self.rows.next_transaction() {
+                    Some(Ok(transaction)) => transaction,
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => return self.pop_due(self.as_of).map(Ok), // end of the input
+                };
+
+                if is_past_as_of(self.as_of, &transaction) {
+                    self.lookahead = Some(transaction);
+                    continue;
+                }
+
+                if let Some(timestamp) = transaction.timestamp {
+                    self.now = Some(self.now.map_or(timestamp, |now| now.max(timestamp)));
+                }
+
+                if transaction.tx_type == "recurring" {
+                    self.lookahead = self.expand(transaction);
+                    continue;
+                }
+
+                match transaction.value_date {
+                    Some(value_date) if self.now.is_none_or(|now| value_date > now) => self.schedule(transaction, value_date),
+                    _ => self.lookahead = Some(transaction),
+                }
+            }
+        }
+    }
+
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{options::*, rejects::*, testing::*};
+
+        // The tx IDs in the order the scheduler passes them on, with the time each is applied at.
+        fn schedule(as_of : Option<i64>, csv_text : &str) -> Vec<(TxId, Option<i64>)> {
+            let mut scheduler = Scheduler::new(Rows(rows(csv_text).into()), as_of);
+            let mut order = Vec::new();
+            while let Some(row) = scheduler.next_transaction() {
+                let transaction = row.unwrap();
+                order.push((transaction.tx_id, transaction.timestamp));
+            }
+            order
+        }
+
+        #[test]
+        fn timestamps_are_unix_seconds_or_iso_dates() {
+            assert_eq!(parse_timestamp("1709283600"), Some(1709283600));
+            assert_eq!(parse_timestamp("2024-03-01"), Some(1709251200));
+            assert_eq!(parse_timestamp("2024-03-01T10:30:00Z"), Some(1709289000));
+            assert_eq!(parse_timestamp("2024-03-01 10:30"), Some(1709289000));
+            assert_eq!(parse_timestamp("March 1st"), None);
+        }
+
+        #[test]
+        fn iso_dates_outside_four_digit_years_are_refused() {
+            assert_eq!(parse_timestamp("99999999999999-01-01"), None);
+            assert_eq!(parse_timestamp("9999-12-31T23:59:59Z"), Some(253_402_300_799));
+        }
+
+        #[test]
+        fn iso_dates_past_the_end_of_their_month_are_refused() {
+            assert_eq!(parse_timestamp("2024-02-29"), Some(1709164800));
+            assert_eq!(parse_timestamp("2000-02-29"), Some(951782400));
+            assert_eq!(parse_timestamp("2024-02-31"), None);
+            assert_eq!(parse_timestamp("2023-02-29"), None);
+            assert_eq!(parse_timestamp("1900-02-29"), None);
+            assert_eq!(parse_timestamp("2023-04-31"), None);
+            assert_eq!(parse_timestamp("2023-12-31"), Some(1703980800));
+        }
+
+        #[test]
+        fn future_dated_rows_wait_for_their_value_date() {
+            let order = schedule(None, "\
+                type,client,tx,amount,timestamp,value_date
+                deposit,1,1,100,10,
+                withdrawal,1,2,50,20,100
+                withdrawal,1,3,10,30,25
+                deposit,1,4,5,40,100
+                deposit,1,5,5,,
+                deposit,1,6,5,100,
+                deposit,1,7,5,150,
+                withdrawal,1,8,5,160,500
+            ");
+
+            assert_eq!(order, [
+                (1, Some(10)), (3, Some(30)), (5, None),
+                (2, Some(100)), (4, Some(100)), (6, Some(100)), (7, Some(150)),
+            ], "the value date is only waited for when it's ahead of the input, and the last one never comes");
+        }
+
+        #[test]
+        fn as_of_releases_the_queue_up_to_its_date() {
+            let order = schedule(Some(200), "\
+                type,client,tx,amount,timestamp,value_date
+                deposit,1,1,100,10,
+                withdrawal,1,2,50,20,150
+                withdrawal,1,3,50,20,300
+                deposit,1,4,5,250,
+                deposit,1,5,5,30,
+            ");
+
+            assert_eq!(order, [(1, Some(10)), (4, Some(250)), (5, Some(30)), (2, Some(150))]);
+        }
+
+        #[test]
+        fn scheduled_rows_are_applied_when_they_fall_due() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount,timestamp,value_date
+                deposit,1,1,100,10,
+                withdrawal,1,2,150,20,100
+                deposit,1,3,100,50,
+                withdrawal,1,4,60,90,
+                deposit,1,5,1,100,
+            ");
+
+            assert_eq!(rejections, [(2, INSUFFICIENT_FUNDS)], "the withdrawal only sees the funds left by its value date");
+            assert_eq!(state.clients[&1].total, 141.0);
+        }
+
+        #[test]
+        fn recurring_definitions_expand_into_consecutive_ids() {
+            let order = schedule(None, "\
+                type,client,tx,amount,timestamp,kind,interval,count
+                deposit,1,1,100,0,,,
+                recurring,1,10,5,50,withdrawal,100,3
+                deposit,1,2,1,400,,,
+            ");
+
+            assert_eq!(order, [(1, Some(0)), (10, Some(50)), (11, Some(150)), (12, Some(250)), (2, Some(400))]);
+        }
+
+        #[test]
+        fn recurring_definitions_that_cant_be_expanded_are_rejected() {
+            let options = Options { input: InputOptions { as_of: Some(200), ..InputOptions::default() }, ..Options::default() };
+            let (state, rejections) = run(&options, &format!("\
+                type,client,tx,amount,timestamp,kind,interval,count
+                deposit,1,1,100,0,,,
+                recurring,1,10,1,10,deposit,60,3
+                recurring,1,30000,1,10,refund,60,2
+                recurring,1,30010,1,10,deposit,0,2
+                recurring,1,30020,1,10,deposit,60,0
+                recurring,1,30030,1,,deposit,60,2
+                recurring,1,{max_id},1,10,deposit,60,2
+            ", max_id = TxId::MAX));
+
+            assert_eq!(rejections, [30000, 30010, 30020, 30030, TxId::MAX].map(|tx_id| (tx_id, INVALID_RECURRENCE)));
+            assert_eq!(state.clients[&1].total, 103.0);
+        }
+
+        #[test]
+        fn recurring_definitions_out_of_bounds_are_rejected() {
+            let (state, rejections) = run(&Options::default(), &format!("\
+                type,client,tx,amount,timestamp,kind,interval,count
+                deposit,1,1,100,0,,,
+                recurring,1,10,1,10,deposit,60,10000
+                recurring,1,20000,1,10,deposit,60,10001
+                recurring,1,30040,1,{max_time},deposit,1,2
+            ", max_time = i64::MAX));
+
+            assert_eq!(rejections, [(20000, INVALID_RECURRENCE), (30040, INVALID_RECURRENCE)]);
+            assert_eq!(state.clients[&1].total, 10100.0, "a definition at the bound is still expanded");
+        }
+    }
@@ -0,0 +1,533 @@
+// The engine's policy, loaded from the "--config" file.
+
+use std::{error::Error, fs, str::FromStr, collections::{HashMap, HashSet}};
+use crate::{options::*, types::*};
+
+
+
+//* Structs *//
+
+    // Policy loaded from the "--config" file. Everything defaults to the engine's original behaviour.
+    #[derive(Debug, Default)]
+    pub(crate) struct EngineConfig {
+        pub(crate) rules: Rules,
+        pub(crate) velocity: VelocityRules,
+        pub(crate) limits: Limits,
+        pub(crate) holds: HoldRules,
+        pub(crate) chargebacks: ChargebackRules,
+        pub(crate) lock_on: LockTrigger, // "lock_on" in the "[locks]" section
+        pub(crate) tiers: TierRules,
+        pub(crate) kyc: KycRules,
+        pub(crate) risk: RiskRules,
+        pub(crate) acceptance: AcceptanceRules,
+        pub(crate) disputes: DisputePolicy, // "policy" in the "[disputes]" section
+        pub(crate) race: RacePolicy, // "race" in the "[disputes]" section
+        pub(crate) shortfall: ShortfallPolicy, // "shortfall" in the "[disputes]" section
+        pub(crate) aliases: HashMap<String, String>, // "[aliases]" section, alternative type name -> type, both lowercase
+        pub(crate) countries: HashMap<String, PolicyOverrides>, // "[countries.<country>]" sections
+        pub(crate) segments: HashMap<String, PolicyOverrides>, // "[segments.<segment>]" sections
+    }
+
+    impl EngineConfig {
+        // The limits and chargeback rules of a client, once the overrides of its country, and then of its segment, are applied.
+        pub(crate) fn client_policy(&self, meta : Option<&ClientMeta>) -> (Limits, ChargebackRules) {
+            let (mut limits, mut chargebacks) = (self.limits, self.chargebacks);
+            let Some(meta) = meta else { return (limits, chargebacks) };
+
+            for overrides in [self.countries.get(&meta.country), self.segments.get(&meta.segment)].into_iter().flatten() {
+                limits.daily_withdrawal_cap = overrides.daily_withdrawal_cap.or(limits.daily_withdrawal_cap);
+                limits.reserve = overrides.reserve.or(limits.reserve);
+                chargebacks.reversal_window_seconds = overrides.reversal_window_seconds.or(chargebacks.reversal_window_seconds);
+            }
+
+            (limits, chargebacks)
+        }
+    }
+
+
+    // A "[countries.<country>]" or "[segments.<segment>]" section, for the clients of that country or segment
+    // in the "--clients-meta" file. Anything left out keeps the value of its own section.
+    #[derive(Debug, Default)]
+    pub(crate) struct PolicyOverrides {
+        pub(crate) daily_withdrawal_cap: Option<f64>,
+        pub(crate) reserve: Option<f64>, // a reserve from the "--limits" file still takes precedence
+        pub(crate) reversal_window_seconds: Option<i64>,
+    }
+
+
+    // What happens when a dispute holds more than the client's available funds, which would leave more held than the total.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(crate) enum ShortfallPolicy {
+        #[default]
+        Allow, // the available balance goes negative
+        Reject, // the dispute is flagged for review instead
+        Cap, // only what's available is held
+        Clamp, // only what's available is held, and the rest is recorded as an operator loss until the dispute is resolved
+    }
+
+
+    // What happens when a resolve and a chargeback both reference the same dispute.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(crate) enum RacePolicy {
+        #[default]
+        FirstWins, // the first one settles the dispute, the other is rejected as not disputed
+        ChargebackWins, // a chargeback after a resolve is still applied, and a resolve after a chargeback is rejected
+        Error, // the run is stopped, the input needs to be reconciled first
+    }
+
+
+    // Which transactions can be disputed. Under "DepositsOnly", withdrawals aren't indexed, only their IDs are kept.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(crate) enum DisputePolicy {
+        #[default]
+        Any,
+        DepositsOnly,
+    }
+
+
+    // Which step of the dispute flow locks the account. Admin freezes apply regardless.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(crate) enum LockTrigger {
+        #[default]
+        Dispute, // locked while disputed, and permanently once charged back
+        Chargeback, // only locked once charged back
+        Never,
+    }
+
+
+    // The "[rules]" section, checked against every row before it's dispatched.
+    #[derive(Debug, Default)]
+    pub(crate) struct Rules {
+        pub(crate) max_amount: Option<f64>,
+        pub(crate) deny_clients: HashSet<ClientId>,
+        pub(crate) require_timestamp: bool, // rows must carry a non-empty "timestamp" column
+        pub(crate) allow_zero_amounts: bool, // zero-amount deposits and withdrawals are recorded as no-ops instead of rejected
+    }
+
+
+    // The "[velocity]" section. Withdrawals breaking either limit within the rolling window are held for review instead of applied.
+    // Only rows with a timestamp are tracked.
+    #[derive(Debug, Default)]
+    pub(crate) struct VelocityRules {
+        pub(crate) window_seconds: i64,
+        pub(crate) max_withdrawals: Option<usize>,
+        pub(crate) max_withdrawal_sum: Option<f64>,
+    }
+
+    impl VelocityRules {
+        pub(crate) fn is_enabled(&self) -> bool {
+            self.window_seconds > 0 && (self.max_withdrawals.is_some() || self.max_withdrawal_sum.is_some())
+        }
+    }
+
+
+    // The "[limits]" section, enforced on top of the account's balance.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub(crate) struct Limits {
+        pub(crate) daily_withdrawal_cap: Option<f64>, // per client and UTC day, only rows with a timestamp count
+        pub(crate) reserve: Option<f64>, // available balance that withdrawals can't dip below, unless the client has its own
+    }
+
+
+    // The "[holds]" section.
+    #[derive(Debug, Default)]
+    pub(crate) struct HoldRules {
+        pub(crate) expiry_seconds: Option<i64>, // open holds are released automatically once a later row's timestamp passes their expiry
+    }
+
+
+    // The "[chargebacks]" section.
+    #[derive(Debug, Default, Clone, Copy)]
+    pub(crate) struct ChargebackRules {
+        pub(crate) reversal_window_seconds: Option<i64>, // how long after a chargeback it may be reversed, requires timestamps when set
+        pub(crate) fee: f64, // charged to the client on each chargeback and credited to the operator's fees account
+    }
+
+
+    // The "[kyc]" section, only enforced with a "--kyc" file.
+    #[derive(Debug, Default)]
+    pub(crate) struct KycRules {
+        pub(crate) unverified_deposit_cap: Option<f64>, // cumulative, unlimited when missing
+    }
+
+
+    // The "[risk]" section, used by the "--risk-report".
+    #[derive(Debug, Default)]
+    pub(crate) struct RiskRules {
+        pub(crate) chargeback_ratio_threshold: Option<f64>, // chargebacks per deposit above which a client is flagged, 0.01 being 1%
+    }
+
+
+    // The "[acceptance]" section: the thresholds an "--atomic" run has to stay within for its report to be written.
+    #[derive(Debug, Default)]
+    pub(crate) struct AcceptanceRules {
+        pub(crate) max_reject_ratio: Option<f64>, // rejected rows per processed row, 0.001 being 0.1%
+        pub(crate) max_malformed: Option<u64>, // rows that couldn't be decoded
+    }
+
+
+    // Account tier, from the "tier" column of the "--limits" file. Clients without one are basic.
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(crate) enum Tier {
+        #[default]
+        Basic,
+        Verified,
+        Premium,
+    }
+
+    impl FromStr for Tier {
+        type Err = String;
+
+        fn from_str(value : &str) -> Result<Self, Self::Err> {
+            match value {
+                "basic" => Ok(Tier::Basic),
+                "verified" => Ok(Tier::Verified),
+                "premium" => Ok(Tier::Premium),
+                _ => Err(format!("unknown tier '{}', expected basic, verified or premium", value)),
+            }
+        }
+    }
+
+
+    // The "[tiers.basic]", "[tiers.verified]" and "[tiers.premium]" sections, enforced by the deposit and withdrawal handlers.
+    #[derive(Debug, Default)]
+    pub(crate) struct TierRules {
+        pub(crate) basic: TierLimits,
+        pub(crate) verified: TierLimits,
+        pub(crate) premium: TierLimits,
+    }
+
+    impl TierRules {
+        pub(crate) fn get(&self, tier : Tier) -> &TierLimits {
+            match tier {
+                Tier::Basic => &self.basic,
+                Tier::Verified => &self.verified,
+                Tier::Premium => &self.premium,
+            }
+        }
+
+        pub(crate) fn get_mut(&mut self, tier : Tier) -> &mut TierLimits {
+            match tier {
+                Tier::Basic => &mut self.basic,
+                Tier::Verified => &mut self.verified,
+                Tier::Premium => &mut self.premium,
+            }
+        }
+    }
+
+
+    #[derive(Debug, Default)]
+    pub(crate) struct TierLimits {
+        pub(crate) max_deposit: Option<f64>, // per transaction
+        pub(crate) max_withdrawal: Option<f64>, // per transaction
+        pub(crate) allow_negative: bool, // withdrawals may take the available balance below zero
+    }
+
+
+
+//* Configuration *//
+
+    // Reads the config file, a small subset of TOML: "[section]" headers, "key = value" pairs and "#" comments.
+    // Values can be numbers, booleans, quoted strings, or flat arrays of those.
+    // Unknown sections and keys are errors, so a typo doesn't silently disable a policy.
+    pub(crate) fn load_config(path : &str) -> Result<EngineConfig, Box<dyn Error>> {
+
+        let contents = fs::read_to_string(path).map_err(|e| format!("Error! Could not read config '{}': {}", path, e))?;
+
+        let mut config = EngineConfig::default();
+        let mut section = String::new();
+
+        for (index, raw_line) in contents.lines().enumerate() {
+
+            let line = strip_comment(raw_line).trim();
+            let line_number = index + 1;
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_string();
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("Error! Config line {} is not a 'key = value' pair.", line_number).into());
+            };
+
+            let (key, value) = (key.trim(), value.trim());
+
+            apply_config_value(&mut config, &section, key, value)
+                .map_err(|e| format!("Error! Config line {} ({}.{}): {}", line_number, section, key, e))?;
+        }
+
+        Ok(config)
+    }
+
+
+    pub(crate) fn apply_config_value(
+        config : &mut EngineConfig,
+        section : &str,
+        key : &str,
+        value : &str,
+    ) -> Result<(), Box<dyn Error>> {
+
+        match (section, key) {
+            ("rules", "max_amount") => config.rules.max_amount = Some(parse_config_number(value)?),
+            ("rules", "deny_clients") => config.rules.deny_clients = parse_config_list(value)?,
+            ("rules", "require_timestamp") => config.rules.require_timestamp = parse_config_bool(value)?,
+            ("rules", "allow_zero_amounts") => config.rules.allow_zero_amounts = parse_config_bool(value)?,
+            ("velocity", "window_seconds") => config.velocity.window_seconds = parse_config_number(value)?,
+            ("velocity", "max_withdrawals") => config.velocity.max_withdrawals = Some(parse_config_number(value)?),
+            ("velocity", "max_withdrawal_sum") => config.velocity.max_withdrawal_sum = Some(parse_config_number(value)?),
+            ("limits", "daily_withdrawal_cap") => config.limits.daily_withdrawal_cap = Some(parse_config_number(value)?),
+            ("limits", "reserve") => config.limits.reserve = Some(parse_config_number(value)?),
+            ("holds", "expiry_seconds") => config.holds.expiry_seconds = Some(parse_config_number(value)?),
+            ("locks", "lock_on") => {
+                config.lock_on = match parse_config_string(value)?.as_str() {
+                    "dispute" => LockTrigger::Dispute,
+                    "chargeback" => LockTrigger::Chargeback,
+                    "never" => LockTrigger::Never,
+                    _ => return Err("expected \"dispute\", \"chargeback\" or \"never\"".into()),
+                };
+            },
+            ("disputes", "policy") => {
+                config.disputes = match parse_config_string(value)?.as_str() {
+                    "any" => DisputePolicy::Any,
+                    "deposits-only" => DisputePolicy::DepositsOnly,
+                    _ => return Err("expected \"any\" or \"deposits-only\"".into()),
+                };
+            },
+            ("disputes", "shortfall") => {
+                config.shortfall = match parse_config_string(value)?.as_str() {
+                    "allow" => ShortfallPolicy::Allow,
+                    "reject" => ShortfallPolicy::Reject,
+                    "cap" => ShortfallPolicy::Cap,
+                    "clamp" => ShortfallPolicy::Clamp,
+                    _ => return Err("expected \"allow\", \"reject\", \"cap\" or \"clamp\"".into()),
+                };
+            },
+            ("disputes", "race") => {
+                config.race = match parse_config_string(value)?.as_str() {
+                    "first-wins" => RacePolicy::FirstWins,
+                    "chargeback-wins" => RacePolicy::ChargebackWins,
+                    "error" => RacePolicy::Error,
+                    _ => return Err("expected \"first-wins\", \"chargeback-wins\" or \"error\"".into()),
+                };
+            },
+            ("aliases", alias) => {
+                config.aliases.insert(alias.to_lowercase(), parse_config_string(value)?.to_lowercase());
+            },
+            ("chargebacks", "fee") => config.chargebacks.fee = parse_config_number(value)?,
+            ("chargebacks", "reversal_window_seconds") => config.chargebacks.reversal_window_seconds = Some(parse_config_number(value)?),
+            ("risk", "chargeback_ratio_threshold") => config.risk.chargeback_ratio_threshold = Some(parse_config_number(value)?),
+            ("acceptance", "max_reject_ratio") => config.acceptance.max_reject_ratio = Some(parse_config_number(value)?),
+            ("acceptance", "max_malformed") => config.acceptance.max_malformed = Some(parse_config_number(value)?),
+            ("kyc", "unverified_deposit_cap") => config.kyc.unverified_deposit_cap = Some(parse_config_number(value)?),
+            (section, key) if section.starts_with("countries.") || section.starts_with("segments.") => {
+                let (kind, name) = section.split_once('.').unwrap_or_default();
+                let overrides = match kind {
+                    "countries" => config.countries.entry(name.to_string()).or_default(),
+                    _ => config.segments.entry(name.to_string()).or_default(),
+                };
+                match key {
+                    "daily_withdrawal_cap" => overrides.daily_withdrawal_cap = Some(parse_config_number(value)?),
+                    "reserve" => overrides.reserve = Some(parse_config_number(value)?),
+                    "reversal_window_seconds" => overrides.reversal_window_seconds = Some(parse_config_number(value)?),
+                    _ => return Err("unknown option".into()),
+                }
+            },
+            (section, key) if section.starts_with("tiers.") => {
+                let tier_limits = config.tiers.get_mut(section["tiers.".len()..].parse()?);
+                match key {
+                    "max_deposit" => tier_limits.max_deposit = Some(parse_config_number(value)?),
+                    "max_withdrawal" => tier_limits.max_withdrawal = Some(parse_config_number(value)?),
+                    "allow_negative" => tier_limits.allow_negative = parse_config_bool(value)?,
+                    _ => return Err("unknown option".into()),
+                }
+            },
+            _ => return Err("unknown option".into()),
+        }
+
+        Ok(())
+    }
+
+
+    // Drops a trailing "# comment", ignoring any "#" inside a quoted string.
+    pub(crate) fn strip_comment(line : &str) -> &str {
+        let mut in_string = false;
+
+        for (index, character) in line.char_indices() {
+            match character {
+                '"' => in_string = !in_string,
+                '#' if !in_string => return &line[..index],
+                _ => {},
+            }
+        }
+
+        line
+    }
+
+
+    pub(crate) fn parse_config_number<T: FromStr>(value : &str) -> Result<T, Box<dyn Error>> {
+        value.replace('_', "").parse().map_err(|_| format!("expected a number, found '{}'", value).into())
+    }
+
+    pub(crate) fn parse_config_bool(value : &str) -> Result<bool, Box<dyn Error>> {
+        value.parse().map_err(|_| format!("expected true or false, found '{}'", value).into())
+    }
+
+    pub(crate) fn parse_config_string(value : &str) -> Result<String, Box<dyn Error>> {
+        value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .map(str::to_string)
+            .ok_or_else(|| format!("expected a quoted string, found '{}'", value).into())
+    }
+
+    pub(crate) fn parse_config_list<T: FromStr, C: FromIterator<T>>(value : &str) -> Result<C, Box<dyn Error>> {
+        let inner = value
+            .strip_prefix('[')
+            .and_then(|v| v.strip_suffix(']'))
+            .ok_or_else(|| format!("expected a list, found '{}'", value))?;
+
+        inner
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                let item = parse_config_string(item).unwrap_or_else(|_| item.to_string());
+                item.parse::<T>().map_err(|_| format!("invalid list item '{}'", item).into())
+            })
+            .collect()
+    }
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::testing::*;
+
+        #[test]
+        fn rules_are_read_from_their_section() {
+            let path = temp_file("rules.toml", "\
+                # policy checks
+                [rules]
+                max_amount = 10000.5
+                deny_clients = [13, 42]
+                require_timestamp = true # even for disputes
+            ");
+            let config = load_config(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(config.rules.max_amount, Some(10000.5));
+            assert_eq!(config.rules.deny_clients, HashSet::from([13, 42]));
+            assert!(config.rules.require_timestamp && !config.rules.allow_zero_amounts);
+        }
+
+        #[test]
+        fn invalid_rules_name_their_line() {
+            let path = temp_file("rules-invalid.toml", "[rules]\nmax_amount = 100\ndeny_clients = [13, x]\n");
+            let err = load_config(&path).unwrap_err().to_string();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(err, "Error! Config line 3 (rules.deny_clients): invalid list item 'x'");
+        }
+
+        #[test]
+        fn lock_trigger_takes_one_of_three_strings() {
+            for (value, trigger) in [("\"dispute\"", LockTrigger::Dispute), ("\"chargeback\"", LockTrigger::Chargeback), ("\"never\"", LockTrigger::Never)] {
+                let mut config = EngineConfig::default();
+                apply_config_value(&mut config, "locks", "lock_on", value).unwrap();
+                assert_eq!(config.lock_on, trigger);
+            }
+
+            let err = apply_config_value(&mut EngineConfig::default(), "locks", "lock_on", "\"refund\"").unwrap_err();
+            assert_eq!(err.to_string(), "expected \"dispute\", \"chargeback\" or \"never\"");
+        }
+
+        #[test]
+        fn tiers_are_read_from_their_own_sections() {
+            let path = temp_file("tiers.toml", "[tiers.basic]\nmax_deposit = 1000\nmax_withdrawal = 500\n[tiers.premium]\nallow_negative = true\n");
+            let config = load_config(&path).unwrap();
+            fs::write(&path, "[tiers.premium]\nallow_negative = true\n[tiers.gold]\nallow_negative = true\n").unwrap();
+            let err = load_config(&path).unwrap_err().to_string();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(err, "Error! Config line 4 (tiers.gold.allow_negative): unknown tier 'gold', expected basic, verified or premium");
+            let (basic, verified, premium) = (config.tiers.get(Tier::Basic), config.tiers.get(Tier::Verified), config.tiers.get(Tier::Premium));
+            assert_eq!((basic.max_deposit, basic.max_withdrawal, basic.allow_negative), (Some(1000.0), Some(500.0), false));
+            assert_eq!((verified.max_deposit, verified.allow_negative), (None, false));
+            assert_eq!((premium.max_deposit, premium.allow_negative), (None, true));
+        }
+
+        #[test]
+        fn aliases_are_read_in_lowercase() {
+            let path = temp_file("aliases.toml", "[aliases]\nCredit = \"DEPOSIT\"\ndebit = \"withdrawal\" # card payouts\n");
+            let config = load_config(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(config.aliases, HashMap::from([
+                ("credit".to_string(), "deposit".to_string()),
+                ("debit".to_string(), "withdrawal".to_string()),
+            ]));
+        }
+
+        #[test]
+        fn race_policy_takes_one_of_three_strings() {
+            for (value, race) in [("\"first-wins\"", RacePolicy::FirstWins), ("\"chargeback-wins\"", RacePolicy::ChargebackWins), ("\"error\"", RacePolicy::Error)] {
+                let mut config = EngineConfig::default();
+                apply_config_value(&mut config, "disputes", "race", value).unwrap();
+                assert_eq!(config.race, race);
+            }
+
+            let err = apply_config_value(&mut EngineConfig::default(), "disputes", "race", "\"resolve-wins\"").unwrap_err();
+            assert_eq!(err.to_string(), "expected \"first-wins\", \"chargeback-wins\" or \"error\"");
+        }
+
+        #[test]
+        fn shortfall_policy_takes_one_of_four_strings() {
+            for (value, shortfall) in [("\"allow\"", ShortfallPolicy::Allow), ("\"reject\"", ShortfallPolicy::Reject), ("\"cap\"", ShortfallPolicy::Cap), ("\"clamp\"", ShortfallPolicy::Clamp)] {
+                let mut config = EngineConfig::default();
+                apply_config_value(&mut config, "disputes", "shortfall", value).unwrap();
+                assert_eq!(config.shortfall, shortfall);
+            }
+
+            let err = apply_config_value(&mut EngineConfig::default(), "disputes", "shortfall", "\"zero\"").unwrap_err();
+            assert_eq!(err.to_string(), "expected \"allow\", \"reject\", \"cap\" or \"clamp\"");
+        }
+
+        #[test]
+        fn client_policy_applies_the_country_then_the_segment() {
+            let path = temp_file("overrides.toml", "\
+                [limits]
+                daily_withdrawal_cap = 500
+                reserve = 10
+                [countries.BR]
+                daily_withdrawal_cap = 100
+                reserve = 20
+                [segments.new-market]
+                reserve = 5
+                reversal_window_seconds = 60
+            ");
+            let config = load_config(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            let meta = |country : &str, segment : &str| ClientMeta { name: String::new(), country: country.to_string(), segment: segment.to_string() };
+            let policy = |meta : Option<&ClientMeta>| {
+                let (limits, chargebacks) = config.client_policy(meta);
+                (limits.daily_withdrawal_cap, limits.reserve, chargebacks.reversal_window_seconds)
+            };
+
+            assert_eq!(policy(None), (Some(500.0), Some(10.0), None));
+            assert_eq!(policy(Some(&meta("PT", "retail"))), (Some(500.0), Some(10.0), None));
+            assert_eq!(policy(Some(&meta("BR", "retail"))), (Some(100.0), Some(20.0), None));
+            assert_eq!(policy(Some(&meta("BR", "new-market"))), (Some(100.0), Some(5.0), Some(60)));
+            assert_eq!(policy(Some(&meta("PT", "new-market"))), (Some(500.0), Some(5.0), Some(60)));
+
+            let err = apply_config_value(&mut EngineConfig::default(), "countries.BR", "max_amount", "1").unwrap_err();
+            assert_eq!(err.to_string(), "unknown option");
+        }
+    }
@@ -0,0 +1,810 @@
+// The loop applying each row of the input, and the runs built on it.
+
+use std::{error::Error, io, collections::{HashMap, HashSet, VecDeque}};
+use crate::{checks::*, clock::*, config::*, handlers::*, observers::*, options::*, outputs::*, rejects::*, sources::*, types::*};
+
+
+
+//* Logic *//
+
+    // Uses the parsed options to read the corresponding CSV file.
+    pub(crate) fn read_csv(
+        options : &Options,
+        staged : &mut StagedOutputs,
+        control_totals : Option<&mut ControlTotals>, // counts the rows read, with "--control"
+        stages : &mut [&mut dyn Stage],
+        observers : &mut [&mut dyn EngineObserver],
+    ) -> Result<EngineState, Box<dyn Error>> {
+
+        let mut threaded = ThreadedSource::spawn(
+            CsvSource::open(&options.input.file_path)?,
+            options.input.batch_size,
+            options.input.read_ahead.unwrap_or(DEFAULT_READ_AHEAD),
+            options.display.verbose,
+        );
+        let client_data = match &options.input.prior_report {
+            Some(path) => check_settled(path, load_report(path)?)?,
+            None => HashMap::new(),
+        };
+
+        // the control totals only count the partner's rows, so the admin ones are merged in after
+        let mut source: &mut dyn InputSource = &mut threaded;
+        let mut counted;
+        if let Some(totals) = control_totals {
+            counted = ControlCount { rows: source, totals };
+            source = &mut counted;
+        }
+        let mut merged;
+        if let Some(path) = &options.input.admin_input {
+            merged = AdminMerge::new(source, CsvSource::open(path)?);
+            source = &mut merged;
+        }
+
+        process_transactions(options, staged, source, client_data, stages, observers)
+    }
+
+
+    // Applies every transaction of the source, in order.
+    // After the transaction data is parsed, the resulting state is returned, starting with a map containing the client's data.
+    // Every row goes through the stages first, and every registered observer is notified as each row is applied or rejected.
+    pub(crate) fn process_transactions(
+        options : &Options,
+        outputs : &mut StagedOutputs, // the side outputs written while processing
+        source : &mut dyn InputSource,
+        client_data : HashMap<ClientId, ClientData>, // the accounts to start from
+        stages : &mut [&mut dyn Stage],
+        observers : &mut [&mut dyn EngineObserver],
+    ) -> Result<EngineState, Box<dyn Error>> {
+
+        let mut transactions_map : HashMap<TxId, Transaction> = HashMap::new();
+        let mut client_data_map : HashMap<ClientId, ClientData> = client_data; // the return target
+        let mut operator = OperatorAccounts::default();
+
+        // Only used with a retention policy. The IDs are kept so pruned transactions can't be replayed or disputed.
+        // Each ID is queued with the time it was indexed at, the latest of the input so far.
+        let mut transactions_order : VecDeque<(TxId, Option<i64>)> = VecDeque::new();
+        let mut pruned_ids : HashSet<TxId> = HashSet::new();
+
+        // Only used with the deposits-only dispute policy: the IDs of the withdrawals, which aren't indexed
+        let mut withdrawal_ids : HashSet<TxId> = HashSet::new();
+
+        // Only used with velocity rules: the recently applied withdrawals of each client, as (timestamp, amount)
+        let mut velocity_windows : HashMap<ClientId, VecDeque<(i64, f64)>> = HashMap::new();
+
+        // Only used with a daily withdrawal cap: the day of each client's latest withdrawal, and how much was withdrawn that day
+        let mut daily_withdrawals : HashMap<ClientId, (i64, f64)> = HashMap::new();
+
+        // Only used with KYC statuses: the sum of each client's applied deposits
+        let mut kyc_deposits : HashMap<ClientId, f64> = HashMap::new();
+
+        // Holds share the tx ID space with deposits and withdrawals, but can't be disputed, so they're kept apart.
+        // The queue keeps the holds that can expire, in the order they were placed.
+        let mut holds : HashMap<TxId, Hold> = HashMap::new();
+        let mut holds_expiry_queue : VecDeque<TxId> = VecDeque::new();
+
+        // Co-owner client ID -> ID of the joint account they own, declared by "open" rows
+        let mut joint_owners : HashMap<ClientId, ClientId> = HashMap::new();
+
+        // The savepoints still valid
+        let mut savepoints : Savepoints<Savepoint> = Savepoints::default();
+
+
+        let mut end_of_day = EndOfDay::new(&options.live, outputs)?;
+        let mut live_report = LiveReport::new(options);
+        let mut clock = InputClock::default();
+
+        let mut rows = Scheduler::new(source, options.input.as_of);
+
+        for observer in observers.iter_mut() {
+            observer.on_start(&client_data_map);
+        }
+
+        while let Some(row) = rows.next_transaction() {
+
+            live_report.tick(&client_data_map, outputs)?;
+
+            let mut transaction: Transaction = match row {
+                Ok(transaction) => transaction,
+                Err(err) if options.input.strict => return Err(err),
+                Err(err) => match err.downcast::<MalformedRow>() {
+                    Ok(malformed) => {
+                        for observer in observers.iter_mut() {
+                            observer.on_malformed(&malformed);
+                        }
+                        continue;
+                    },
+                    Err(err) => return Err(err),
+                },
+            };
+
+            // the handlers take ownership of the transaction, so this copy is what the observers get to see
+            let origin = transaction.origin.take();
+            let passthrough = options.reports.passthrough_columns.iter().map(|column| transaction.extra_fields.get(column).cloned().unwrap_or_default()).collect();
+            let mut info = TransactionInfo { origin, passthrough, ..TransactionInfo::from(&transaction) };
+
+            // rows past the "--as-of" date are read, and are left out on their own, whatever they'd do
+            if is_past_as_of(options.input.as_of, &transaction) {
+                let err: Box<dyn Error> = Rejection::new(PAST_AS_OF, "Error! Transaction is timestamped after the --as-of date. Ignoring.").into();
+                for observer in observers.iter_mut() {
+                    observer.on_rejected(&info, err.as_ref());
+                }
+                continue;
+            }
+
+            // the admin file is the operator's own, so its rows skip the stages, which are there for the partner's input
+            let staged = match transaction.admin {
+                true => Ok(Some(transaction)),
+                false => stages.iter_mut().try_fold(Some(transaction), |row, stage| match row {
+                    Some(row) => stage.process(row),
+                    None => Ok(None),
+                }),
+            };
+
+            // checked once the aliases are applied, so a partner's type can't be renamed into an admin one
+            let mut transaction = match staged.and_then(|row| row.map(check_admin_source).transpose()) {
+                Ok(Some(transaction)) => transaction,
+                Ok(None) => continue, // filtered out
+                Err(err) => {
+                    for observer in observers.iter_mut() {
+                        observer.on_rejected(&info, err.as_ref());
+                    }
+                    continue;
+                }
+            };
+
+            // savepoints live outside the transaction index and the accounts, so they're taken and restored before anything looks at the row's client
+            let tx_id = transaction.tx_id;
+            match transaction.tx_type.as_str() {
+                "savepoint" => {
+                    savepoints.take(tx_id, Savepoint {
+                        transactions: transactions_map.clone(),
+                        clients: client_data_map.clone(),
+                        operator: operator.clone(),
+                        holds: holds.clone(),
+                        holds_expiry_queue: holds_expiry_queue.clone(),
+                        joint_owners: joint_owners.clone(),
+                        transactions_order: transactions_order.clone(),
+                        pruned_ids: pruned_ids.clone(),
+                        withdrawal_ids: withdrawal_ids.clone(),
+                        velocity_windows: velocity_windows.clone(),
+                        daily_withdrawals: daily_withdrawals.clone(),
+                        kyc_deposits: kyc_deposits.clone(),
+                        scheduled: rows.pending.clone(),
+                    });
+                    for observer in observers.iter_mut() {
+                        observer.on_savepoint(tx_id);
+                    }
+                    continue;
+                },
+                "rollback_to" => {
+                    let Some(savepoint) = savepoints.restore(tx_id) else {
+                        let err: Box<dyn Error> = Rejection::new(UNKNOWN_SAVEPOINT, "Error! There is no savepoint with this ID. Ignoring.").into();
+                        for observer in observers.iter_mut() {
+                            observer.on_rejected(&info, err.as_ref());
+                        }
+                        continue;
+                    };
+
+                    transactions_map = savepoint.transactions;
+                    client_data_map = savepoint.clients;
+                    operator = savepoint.operator;
+                    holds = savepoint.holds;
+                    holds_expiry_queue = savepoint.holds_expiry_queue;
+                    joint_owners = savepoint.joint_owners;
+                    transactions_order = savepoint.transactions_order;
+                    pruned_ids = savepoint.pruned_ids;
+                    withdrawal_ids = savepoint.withdrawal_ids;
+                    velocity_windows = savepoint.velocity_windows;
+                    daily_withdrawals = savepoint.daily_withdrawals;
+                    kyc_deposits = savepoint.kyc_deposits;
+                    rows.pending = savepoint.scheduled;
+
+                    for observer in observers.iter_mut() {
+                        observer.on_rollback(&info, &client_data_map);
+                    }
+                    continue;
+                },
+                _ => {},
+            }
+
+            // rows from a co-owner are applied to the joint account, so they can dispute each other's transactions.
+            // The stages and the client checks run on whoever sent the row, so a blocked or unverified co-owner can't act through the account
+            let sender = transaction.client_id;
+            if let Some(account_id) = joint_owners.get(&sender) {
+                transaction.client_id = *account_id;
+            }
+            // a stage may have rewritten it, the passthrough values stay those of the row as it was read
+            info = TransactionInfo { origin: info.origin.take(), passthrough: std::mem::take(&mut info.passthrough), ..TransactionInfo::from(&transaction) };
+
+            let is_indexed = match transaction.tx_type.as_str() { // only these are stored
+                "deposit" => true,
+                "withdrawal" => options.config.disputes == DisputePolicy::Any,
+                _ => false,
+            };
+            let was_locked = client_data_map.get(&info.client_id).is_some_and(ClientData::is_locked);
+
+            clock.tick(&transaction);
+            if let Some(now) = clock.now() {
+                end_of_day.advance(now, &client_data_map, outputs)?;
+                for hold_id in expire_holds(&mut holds, &mut holds_expiry_queue, &mut client_data_map, now) {
+                    let hold = &holds[&hold_id];
+                    let expiry = TransactionInfo {
+                        tx_id: hold_id,
+                        tx_type: "hold_expiry".to_string(),
+                        client_id: hold.client_id,
+                        amount: Some(hold.amount),
+                        timestamp: Some(now),
+                        origin: None,
+                        passthrough: Vec::new(),
+                    };
+                    if let Some(client) = client_data_map.get(&hold.client_id) {
+                        for observer in observers.iter_mut() {
+                            observer.on_hold_expired(&expiry, client);
+                        }
+                    }
+                }
+            }
+
+            let client_meta = options.data.clients_meta.as_ref().and_then(|meta| meta.get(&transaction.client_id));
+            let (limits, chargebacks) = options.config.client_policy(client_meta);
+
+            let tier = options.data.client_limits.get(&transaction.client_id).map(|cl| cl.tier).unwrap_or_default();
+            let tier_limits = options.config.tiers.get(tier);
+
+            let checked = check_blocked_account(&options.data.blocklist, &transaction)
+                .and_then(|_| check_kyc(options.data.kyc.as_ref(), &options.config.kyc, &kyc_deposits, &transaction, sender))
+                .and_then(|_| check_rules(&options.config.rules, &transaction, sender))
+                .and_then(|_| check_retention(&pruned_ids, &transaction))
+                .and_then(|_| check_hold_ids(&holds, &transaction))
+                .and_then(|_| check_withdrawal_ids(&withdrawal_ids, &transaction))
+                .and_then(|_| check_velocity(&options.config.velocity, &mut velocity_windows, &transaction, &clock))
+                .and_then(|_| check_daily_limit(&limits, &daily_withdrawals, &transaction, &clock))
+                .and_then(|_| check_reserve(&limits, &options.data.client_limits, &client_data_map, &transaction, tier_limits));
+
+            let result = match checked {
+
+                Err(err) => Err(err),
+
+                Ok(()) => match transaction.tx_type.as_str() {
+                    "deposit" => try_deposit(&mut transactions_map, &mut client_data_map, transaction, tier_limits, options.config.rules.allow_zero_amounts),
+                    "withdrawal" => try_withdrawal(&mut transactions_map, &mut client_data_map, transaction, tier_limits, options.config.disputes, options.config.rules.allow_zero_amounts),
+                    "dispute" => try_dispute(&mut transactions_map, &mut client_data_map, &mut operator, transaction, &clock, &options.config),
+                    "resolve" => try_resolve(&mut transactions_map, &mut client_data_map, &mut operator, transaction, options.config.race),
+                    "chargeback" => try_chargeback(&mut transactions_map, &mut client_data_map, &mut operator, transaction, &clock, &options.config),
+                    "chargeback_reversal" => try_chargeback_reversal(&mut transactions_map, &mut client_data_map, &mut operator, transaction, &clock, &chargebacks),
+                    "refund" => try_refund(&mut transactions_map, &mut client_data_map, transaction),
+                    "reversal" => try_reversal(&mut transactions_map, &mut client_data_map, transaction),
+                    "freeze" => try_freeze(&mut client_data_map, transaction),
+                    "unfreeze" => try_unfreeze(&mut client_data_map, transaction),
+                    "clear_dispute_locks" => try_clear_dispute_locks(&mut client_data_map, transaction),
+                    "clear_chargeback_freeze" => try_clear_chargeback_freeze(&mut client_data_map, transaction),
+                    "hold" => try_hold(&mut holds, &mut holds_expiry_queue, &transactions_map, &mut client_data_map, transaction, &clock, &options.config.holds),
+                    "capture" => try_capture(&mut holds, &mut client_data_map, transaction),
+                    "release" => try_release(&mut holds, &mut client_data_map, transaction),
+                    "open" => try_open(&mut client_data_map, &mut joint_owners, transaction, &options.data.blocklist, &options.config.rules),
+                    "recurring" => Err(Rejection::new(INVALID_RECURRENCE, "Error! Recurring definitions need a timestamp, a kind (deposit or withdrawal), and a positive interval and count (at most 10000) within the tx ID and date ranges. Ignoring.").into()),
+                    _ => Err(UnknownType.into()),
+                },
+            };
+
+            if let Err(err) = result {
+                if err.is::<DisputeRace>() {
+                    return Err(err);
+                }
+                if let Some(cd) = client_data_map.get_mut(&info.client_id) {
+                    cd.stats.record(&info, reject_code(err.as_ref()));
+                }
+                for observer in observers.iter_mut() {
+                    observer.on_rejected(&info, err.as_ref());
+                }
+                continue;
+            }
+
+            notify_applied(observers, &info, &client_data_map, was_locked);
+
+            // a dispute capped by the shortfall policy is applied, but what it left out is worth a trace
+            if let Some(te) = transactions_map.get(&tx_id).filter(|te| info.tx_type == "dispute" && te.held < te.net_amount()) {
+                let decision = Rejection::new(DISPUTE_CAPPED, format!(
+                    "Warning! The dispute only holds {} of {}, what the client had available.",
+                    format_amount(te.held),
+                    format_amount(te.net_amount()),
+                ));
+                for observer in observers.iter_mut() {
+                    observer.on_adjusted(&info, &decision);
+                }
+            }
+
+            if let (true, "deposit", Some(amount)) = (options.data.kyc.is_some(), info.tx_type.as_str(), info.amount) {
+                *kyc_deposits.entry(sender).or_default() += amount;
+            }
+
+            if let ("withdrawal", Some(timestamp), Some(amount)) = (info.tx_type.as_str(), clock.now(), info.amount) {
+                record_timed_withdrawal(&options.config, &limits, &mut velocity_windows, &mut daily_withdrawals, info.client_id, timestamp, amount);
+            }
+
+            if info.tx_type == "withdrawal" && !is_indexed {
+                withdrawal_ids.insert(tx_id);
+            }
+
+            if let Some(retain) = options.input.retain_transactions {
+                if is_indexed {
+                    transactions_order.push_back((tx_id, clock.latest()));
+                }
+                prune_transactions(&mut transactions_map, &mut transactions_order, &mut pruned_ids, retain, clock.latest());
+            }
+        }
+
+        end_of_day.close_day(&client_data_map, outputs)?;
+        live_report.finish(&client_data_map, outputs)?;
+
+        Ok(EngineState {
+            clients: client_data_map,
+            transactions: transactions_map,
+            holds,
+            operator,
+            latest_timestamp: clock.latest(),
+            still_scheduled: rows.pending.len(),
+        })
+    }
+
+
+    pub(crate) fn notify_applied(
+        observers : &mut [&mut dyn EngineObserver],
+        info : &TransactionInfo,
+        client_data_map : &HashMap<ClientId, ClientData>,
+        was_locked : bool,
+    ) {
+
+        let Some(client) = client_data_map.get(&info.client_id) else { return };
+
+        for observer in observers.iter_mut() {
+            observer.on_applied(info, client);
+
+            if !was_locked && client.is_locked() {
+                observer.on_account_locked(info.client_id, client);
+            }
+            if info.tx_type == "chargeback" {
+                observer.on_chargeback(info, client);
+            }
+        }
+    }
+
+
+    // Everything that keeps an "--atomic" run from being written: broken invariants of the final state,
+    // and the "[acceptance]" thresholds that were exceeded. Empty when the run can be committed.
+    pub(crate) fn check_acceptance(state : &EngineState, summary : &RunSummary, rules : &AcceptanceRules) -> Vec<String> {
+
+        let mut failures = Vec::new();
+
+        let mut client_ids: Vec<&ClientId> = state.clients.keys().collect();
+        client_ids.sort();
+
+        for client_id in client_ids {
+            let client = &state.clients[client_id];
+            if (client.available + client.held - client.total).abs() > 1e-9 {
+                failures.push(format!("The balances of client {} don't add up.", client_id));
+            }
+            if client.held < -1e-9 {
+                failures.push(format!("Client {} has negative held funds.", client_id));
+            }
+            let wallets_total: f64 = client.wallets.values().map(|wallet| wallet.total).sum();
+            if !client.wallets.is_empty() && (wallets_total - client.total).abs() > 1e-9 {
+                failures.push(format!("The wallets of client {} don't add up to its total.", client_id));
+            }
+        }
+
+        if let Some(max_ratio) = rules.max_reject_ratio {
+            let ratio = if summary.processed == 0 {0.0} else {summary.rejected as f64 / summary.processed as f64};
+            if ratio > max_ratio {
+                failures.push(format!("{} of {} rows were rejected, above the max_reject_ratio of {}.", summary.rejected, summary.processed, max_ratio));
+            }
+        }
+        if let Some(max_malformed) = rules.max_malformed {
+            if summary.malformed > max_malformed {
+                failures.push(format!("{} rows couldn't be decoded, above the max_malformed of {}.", summary.malformed, max_malformed));
+            }
+        }
+
+        failures
+    }
+
+
+    // Runs the file with the policy of the options, with the built-in stages and only a summary observing it, for "whatif".
+    pub(crate) fn simulate(options : &Options) -> Result<(EngineState, RunSummary), Box<dyn Error>> {
+
+        let mut summary = RunSummary { lang: options.display.lang, ..RunSummary::default() };
+        let mut observers: Vec<&mut dyn EngineObserver> = vec![&mut summary];
+
+        let mut type_aliases = TypeAliases { aliases: &options.config.aliases };
+        let mut strict_columns = StrictColumns { passthrough_columns: &options.reports.passthrough_columns };
+        let mut blocklist_check = BlocklistCheck { blocklist: &options.data.blocklist };
+        let mut stages: Vec<&mut dyn Stage> = Vec::new();
+
+        if !options.config.aliases.is_empty() {
+            stages.push(&mut type_aliases);
+        }
+        if options.input.strict {
+            stages.push(&mut strict_columns);
+        }
+        if !options.data.blocklist.is_empty() {
+            stages.push(&mut blocklist_check);
+        }
+
+        let state = read_csv(options, &mut StagedOutputs::default(), None, &mut stages, &mut observers)?;
+        Ok((state, summary))
+    }
+
+
+    // Enabled by the "whatif" subcommand: replays the file under the "--baseline" policy and under the "--config" one,
+    // and writes the totals of both runs side by side. The fees collected are the operator's revenue, the balances are summed over every client.
+    pub(crate) fn write_whatif(mut options : Options) -> Result<(), Box<dyn Error>> {
+
+        // the runs are only compared, so the files written while processing are left alone
+        options.live = LiveOutputs::default();
+
+        let (proposed, proposed_summary) = simulate(&options)?;
+        options.config = options.command.baseline.take().unwrap_or_default();
+        let (baseline, baseline_summary) = simulate(&options)?;
+
+        let totals = |state : &EngineState, summary : &RunSummary| {
+            let sum = |balance : fn(&ClientData) -> f64| state.clients.values().map(balance).sum::<f64>();
+            let mut totals = vec![
+                ("processed", summary.processed as f64),
+                ("accepted", summary.accepted as f64),
+                ("rejected", summary.rejected as f64),
+                ("locked", state.clients.values().filter(|client| client.is_locked()).count() as f64),
+                ("available", sum(|client| client.available)),
+                ("held", sum(|client| client.held)),
+                ("total", sum(|client| client.total)),
+            ];
+            totals.extend(state.operator.balances());
+            totals
+        };
+
+        let mut writer = csv::Writer::from_writer(io::stdout());
+
+        writer.write_record(["metric", "baseline", "proposed", "change"])?;
+        for ((metric, before), (_, after)) in totals(&baseline, &baseline_summary).into_iter().zip(totals(&proposed, &proposed_summary)) {
+            writer.write_record([metric, &format_amount(before), &format_amount(after), &format_amount(after - before)])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+
+//* Auxiliary Functions *//
+
+    // Evicts the oldest transactions once more than the retained count are indexed, or once they're older than the retained age.
+    // Transactions under dispute or charged back are never evicted, since a resolve, chargeback or chargeback reversal still needs them,
+    // and so does the scan for a client's other chargebacks. Instead, they are moved to the back of the queue and checked again
+    // once they age out a second time.
+    pub(crate) fn prune_transactions(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        transactions_order : &mut VecDeque<(TxId, Option<i64>)>,
+        pruned_ids : &mut HashSet<TxId>,
+        retain : Retention,
+        now : Option<i64>,
+    ) {
+
+        let mut checked = 0;
+
+        while checked < transactions_order.len() {
+            let Some(&(tx_id, indexed_at)) = transactions_order.front() else { break };
+
+            let aged_out = match retain {
+                Retention::Transactions(count) => transactions_order.len() > count,
+                Retention::Seconds(seconds) => indexed_at.zip(now).is_some_and(|(indexed_at, now)| now.saturating_sub(indexed_at) > seconds),
+            };
+            if !aged_out {
+                break;
+            }
+
+            transactions_order.pop_front();
+            checked += 1;
+
+            let still_needed = transactions_map
+                .get(&tx_id)
+                .is_some_and(|te| matches!(te.dispute_status, DisputeStatus::UnderDispute | DisputeStatus::ChargedBack));
+
+            if still_needed {
+                transactions_order.push_back((tx_id, indexed_at));
+                continue;
+            }
+
+            transactions_map.remove(&tx_id);
+            pruned_ids.insert(tx_id);
+        }
+    }
+
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::{fs, time::Duration};
+        use crate::format_summary_line;
+        use crate::testing::*;
+
+        #[test]
+        fn time_windows_saturate_at_the_ends_of_the_timestamp_range() {
+            let mut options = Options::default();
+            options.config.velocity = VelocityRules { window_seconds: 60, max_withdrawals: Some(1), max_withdrawal_sum: None };
+            options.config.chargebacks.reversal_window_seconds = Some(i64::MAX);
+
+            let (state, rejections) = run(&options, &format!("\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,{min}
+                withdrawal,1,2,10,{min}
+                withdrawal,1,3,10,{min}
+                dispute,1,1,,{min}
+                chargeback,1,1,,{min}
+                chargeback_reversal,1,1,,{max}
+            ", min = i64::MIN, max = i64::MAX));
+
+            assert_eq!(rejections, [(3, FLAGGED_FOR_REVIEW)]);
+            assert_eq!(state.clients[&1].total, 90.0);
+        }
+
+        #[test]
+        fn rows_past_the_as_of_date_are_rejected_and_the_rest_is_still_read() {
+            let options = Options { input: InputOptions { as_of: parse_timestamp("2024-03-01"), ..InputOptions::default() }, ..Options::default() };
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp,value_date
+                deposit,1,1,100,2024-02-28,
+                deposit,1,2,50,2024-03-02,
+                withdrawal,1,3,10,2024-02-29,
+                deposit,1,4,5,,
+                withdrawal,1,5,1,2024-02-29,2024-02-29T12:00:00Z
+                withdrawal,1,6,1,2024-02-29,2024-03-05
+            ");
+
+            assert_eq!(rejections, [(2, PAST_AS_OF)]);
+            assert_eq!(state.clients[&1].total, 94.0);
+            assert_eq!(state.still_scheduled, 1, "the withdrawal due after the as-of date is left queued");
+        }
+
+        #[test]
+        fn rollback_restores_the_holds() {
+            let (state, rejections) = run_rows(&Options::default(), as_operator(rows("\
+                type,client,tx,amount
+                deposit,1,1,100
+                savepoint,1,10,
+                hold,1,2,30
+                rollback_to,1,10,
+                capture,1,2,
+            ")));
+
+            assert_eq!(rejections, [(2, HOLD_NOT_FOUND)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (100.0, 0.0, 100.0));
+        }
+
+        #[test]
+        fn admin_rows_from_the_input_are_rejected() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,10
+                freeze,1,2,,15
+                withdrawal,1,3,10,20
+                deposit,2,4,50,25
+                clear_chargeback_freeze,2,5,,30
+                unfreeze,1,6,,35
+                clear_dispute_locks,1,7,,40
+            ");
+
+            assert_eq!(rejections, [(2, ADMIN_ONLY), (5, ADMIN_ONLY), (6, ADMIN_ONLY), (7, ADMIN_ONLY)]);
+            assert!(!state.clients[&1].is_locked());
+            assert_eq!(state.clients[&1].total, 90.0);
+        }
+
+        #[test]
+        fn aliases_cant_turn_a_row_into_an_admin_row() {
+            let aliases = HashMap::from([("lift".to_string(), "unfreeze".to_string())]);
+            let mut rejections = Rejections::default();
+            process_transactions(
+                &Options::default(),
+                &mut StagedOutputs::default(),
+                &mut Rows(rows("type,client,tx,amount\ndeposit,1,1,100\nlift,1,2,\n").into()),
+                HashMap::new(),
+                &mut [&mut TypeAliases { aliases: &aliases }],
+                &mut [&mut rejections],
+            ).unwrap();
+
+            assert_eq!(rejections.0, [(2, ADMIN_ONLY)]);
+        }
+
+        #[test]
+        fn the_admin_input_only_takes_admin_rows() {
+            let path = temp_file("admin-rows.csv", "type,client,tx,amount,timestamp\nfreeze,1,100,,15\ndeposit,1,101,1000,16\nunfreeze,1,102,,30\n");
+            let input = rows("type,client,tx,amount,timestamp\ndeposit,1,1,100,10\nwithdrawal,1,2,10,20\nwithdrawal,1,3,10,40\n");
+            let mut rejections = Rejections::default();
+            let state = process_transactions(
+                &Options::default(),
+                &mut StagedOutputs::default(),
+                &mut AdminMerge::new(Rows(input.into()), CsvSource::open(&path).unwrap()),
+                HashMap::new(),
+                &mut [],
+                &mut [&mut rejections],
+            ).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(rejections.0, [(101, NOT_ADMIN), (2, ACCOUNT_LOCKED)]);
+            assert_eq!(state.clients[&1].total, 90.0);
+        }
+
+        #[test]
+        fn savepoints_from_the_input_are_rejected() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount
+                deposit,1,1,100
+                savepoint,1,10,
+                withdrawal,1,2,30
+                rollback_to,1,10,
+            ");
+
+            assert_eq!(rejections, [(10, ADMIN_ONLY), (10, ADMIN_ONLY)]);
+            assert_eq!(state.clients[&1].total, 70.0);
+        }
+
+        #[test]
+        fn control_rows_skip_the_client_stages_and_checks() {
+            let options = Options { data: ReferenceData { blocklist: HashMap::from([(7, "sanctions".to_string())]), ..ReferenceData::default() }, ..Options::default() };
+            let mut rejections = Rejections::default();
+            let input = as_operator(rows("\
+                type,client,tx,amount
+                savepoint,7,10,
+                deposit,1,1,100
+                rollback_to,7,10,
+                deposit,7,2,5
+            "));
+            let state = process_transactions(
+                &options,
+                &mut StagedOutputs::default(),
+                &mut Rows(input.into()),
+                HashMap::new(),
+                &mut [&mut StrictColumns { passthrough_columns: &[] }, &mut BlocklistCheck { blocklist: &options.data.blocklist }],
+                &mut [&mut rejections],
+            ).unwrap();
+
+            assert_eq!(rejections.0, [(2, BLOCKLISTED)], "only the partner's row of the blocked client is rejected");
+            assert!(state.clients.is_empty(), "the deposit was rolled back");
+        }
+
+        #[test]
+        fn control_rows_are_counted_on_their_own() {
+            let mut summary = RunSummary::default();
+            let input = as_operator(rows("\
+                type,client,tx,amount
+                savepoint,9,10,
+                deposit,1,1,100
+                rollback_to,9,10,
+                rollback_to,9,11,
+            "));
+            process_transactions(&Options::default(), &mut StagedOutputs::default(), &mut Rows(input.into()), HashMap::new(), &mut [], &mut [&mut summary]).unwrap();
+
+            assert_eq!((summary.processed, summary.accepted, summary.rejected, summary.control), (4, 1, 1, 2));
+            assert!(format_summary_line(&HashMap::new(), &summary, Duration::ZERO).contains(" control=2"));
+        }
+
+        #[test]
+        fn retention_evicts_the_oldest_transactions_and_remembers_their_ids() {
+            let options = Options { input: InputOptions { retain_transactions: Some(Retention::Transactions(2)), ..InputOptions::default() }, ..Options::default() };
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                deposit,1,3,25
+                dispute,1,1,
+                deposit,1,1,100
+                dispute,1,3,
+            ");
+
+            assert_eq!(rejections, [(1, TX_PRUNED), (1, DUPLICATE_TX)]);
+            assert!(!state.transactions.contains_key(&1) && state.transactions.contains_key(&3));
+            assert_eq!(state.clients[&1].held, 25.0);
+        }
+
+        #[test]
+        fn disputed_transactions_stay_indexed_past_the_retention() {
+            let options = Options { input: InputOptions { retain_transactions: Some(Retention::Transactions(1)), ..InputOptions::default() }, ..Options::default() };
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                dispute,1,1,
+                deposit,2,2,50
+                deposit,2,3,25
+                resolve,1,1,
+                dispute,2,2,
+            ");
+
+            assert_eq!(rejections, [(2, TX_PRUNED)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held), (100.0, 0.0));
+        }
+
+        #[test]
+        fn retention_by_age_goes_by_the_latest_timestamp() {
+            let options = Options { input: InputOptions { retain_transactions: Some(Retention::Seconds(60)), ..InputOptions::default() }, ..Options::default() };
+
+            let (_, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,0
+                deposit,1,2,50,30
+                deposit,1,3,25,100
+                dispute,1,2,,100
+                dispute,1,3,,160
+                dispute,1,1,,
+            ");
+
+            assert_eq!(rejections, [(2, TX_PRUNED), (1, TX_PRUNED)]);
+        }
+
+        #[test]
+        fn acceptance_fails_above_the_thresholds() {
+            let path = temp_file("acceptance.csv", "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,x,10\nwithdrawal,1,3,50\ndeposit,1,4,5\nwithdrawal,1,5,1\n");
+            let mut summary = RunSummary::default();
+            let state = process_transactions(&Options::default(), &mut StagedOutputs::default(), &mut CsvSource::open(&path).unwrap(), HashMap::new(), &mut [], &mut [&mut summary]).unwrap();
+            fs::remove_file(path).unwrap();
+
+            let within = AcceptanceRules { max_reject_ratio: Some(0.25), max_malformed: Some(1) };
+            assert_eq!(check_acceptance(&state, &summary, &within), Vec::<String>::new(), "1 of 4 rows rejected is exactly the max");
+
+            let above = AcceptanceRules { max_reject_ratio: Some(0.2), max_malformed: Some(0) };
+            assert_eq!(check_acceptance(&state, &summary, &above), [
+                "1 of 4 rows were rejected, above the max_reject_ratio of 0.2.",
+                "1 rows couldn't be decoded, above the max_malformed of 0.",
+            ]);
+            assert!(check_acceptance(&state, &summary, &AcceptanceRules::default()).is_empty(), "no thresholds by default");
+        }
+
+        #[test]
+        fn acceptance_checks_the_invariants_of_every_client() {
+            let (mut state, _) = run(&Options::default(), "type,client,tx,amount,wallet\ndeposit,1,1,10,\ndeposit,2,2,10,\ndeposit,3,3,10,savings\n");
+            state.clients.get_mut(&2).unwrap().available = 12.0;
+            state.clients.get_mut(&3).unwrap().held = -1.0;
+            state.clients.get_mut(&3).unwrap().available = 11.0;
+            state.clients.get_mut(&1).unwrap().wallets.values_mut().for_each(|wallet| wallet.total = 4.0);
+
+            assert_eq!(check_acceptance(&state, &RunSummary::default(), &AcceptanceRules::default()), [
+                "The wallets of client 1 don't add up to its total.",
+                "The balances of client 2 don't add up.",
+                "Client 3 has negative held funds.",
+            ]);
+        }
+
+        #[test]
+        fn simulate_runs_the_file_under_the_policy_of_the_options() {
+            let path = temp_file("whatif.csv", "type,client,tx,amount\ndeposit,1,1,100\ndeposit,2,2,50\nwithdrawal,2,3,30\ndispute,1,1,\nchargeback,1,1,\n");
+            let mut options = Options { input: InputOptions { file_path: path.clone(), ..InputOptions::default() }, ..Options::default() };
+            let (baseline, baseline_summary) = simulate(&options).unwrap();
+
+            options.config.chargebacks.fee = 15.0;
+            options.config.limits.daily_withdrawal_cap = Some(10.0);
+            let (proposed, proposed_summary) = simulate(&options).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!((baseline_summary.accepted, proposed_summary.accepted), (5, 5), "the cap only applies to timestamped rows");
+            assert_eq!((baseline.operator.fees, proposed.operator.fees), (0.0, 15.0));
+            assert_eq!((baseline.clients[&1].total, proposed.clients[&1].total), (0.0, -15.0));
+        }
+
+        #[test]
+        fn whatif_writes_none_of_the_live_outputs() {
+            let path = temp_file("whatif-live.csv", "type,client,tx,amount,timestamp\ndeposit,1,1,100,2024-03-01T08:00:00Z\ndeposit,1,2,5,2024-03-02T08:00:00Z\n");
+            let (live, eod) = (format!("{}.live", path), format!("{}.eod", path));
+            let mut options = Options { input: InputOptions { file_path: path.clone(), ..InputOptions::default() }, ..Options::default() };
+            options.live = LiveOutputs { report: Some(live.clone()), every: LiveInterval::Rows(1), eod_report: Some(eod.clone()), ..LiveOutputs::default() };
+
+            write_whatif(options).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert!(!fs::exists(&live).unwrap() && !fs::exists(&eod).unwrap() && !fs::exists(format!("{}.partial", eod)).unwrap());
+        }
+    }
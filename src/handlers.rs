@@ -0,0 +1,1419 @@
+// The handler of each transaction type, applying its row to the accounts.
+
+use std::{error::Error, collections::{BTreeMap, BTreeSet, HashMap, VecDeque}};
+use crate::{clock::*, config::*, rejects::*, types::*};
+
+
+
+//* Handlers *//
+
+    // Releases the open holds whose expiry has been reached by the given timestamp, returning their tx IDs.
+    // The queue is in placement order, which matches the expiry order as long as the input is chronological.
+    pub(crate) fn expire_holds(
+        holds : &mut HashMap<TxId, Hold>,
+        holds_expiry_queue : &mut VecDeque<TxId>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        timestamp : i64,
+    ) -> Vec<TxId> {
+
+        let mut expired = Vec::new();
+
+        while let Some(tx_id) = holds_expiry_queue.front() {
+            let Some(hold) = holds.get_mut(tx_id) else { break };
+
+            if hold.expires_at.is_some_and(|expires_at| expires_at > timestamp) {
+                break;
+            }
+
+            if hold.status == HoldStatus::Open {
+                if let Some(cd) = client_data_map.get_mut(&hold.client_id) {
+                    cd.adjust(&hold.wallet, hold.amount, -hold.amount);
+                }
+                hold.status = HoldStatus::Expired;
+                expired.push(*tx_id);
+            }
+
+            holds_expiry_queue.pop_front();
+        }
+
+        expired
+    }
+
+
+    // Tries to deposit funds into an account.
+    // A new account is created if none exist with the given ID.
+    // This is currently the only way to create a new user entry, besides opening a joint account.
+    // Zero amounts are only accepted with "allow_zero_amounts", as gateways use them to verify an account.
+    pub(crate) fn try_deposit(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+        tier_limits : &TierLimits,
+        allow_zero : bool,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if transactions_map.contains_key(&transaction.tx_id) {   
+            return Err(Rejection::new(DUPLICATE_TX, "Error! Transaction ID already exists. Ignoring.").into());  
+        }
+
+        let amount = transaction.amount.ok_or(Rejection::new(MISSING_AMOUNT, "Error! Attempting to deposit without an amount. Ignoring."))?;
+        if amount < 0.0f64 || (amount == 0.0 && !allow_zero) {
+            return Err(Rejection::new(NON_POSITIVE_AMOUNT, "Error! Attempting to deposit a zero or negative balance. Ignoring.").into());
+        }    
+        if tier_limits.max_deposit.is_some_and(|max_deposit| amount > max_deposit) {
+            return Err(Rejection::new(TIER_LIMIT, "Error! Deposit exceeds the limit of the account's tier. Ignoring.").into());
+        }
+
+        let client_data = client_data_map.get_mut(&transaction.client_id);
+
+        if let Some(cd) = client_data {
+            if cd.is_locked() {
+                return Err(Rejection::new(ACCOUNT_LOCKED, "Error! Attempting to deposit into a locked account. Ignoring.").into());  
+            }
+
+            cd.adjust(&transaction.wallet, amount, 0.0);
+        }
+
+        else {
+            let mut cd = ClientData { 
+                available: 0.0f64, 
+                held: 0.0f64, 
+                total: 0.0f64, 
+                locks: BTreeSet::new(),
+                frozen: false,
+                wallets: BTreeMap::new(),
+                owners: BTreeSet::new(),
+                stats: ClientStats::default(),
+            }; 
+            cd.adjust(&transaction.wallet, amount, 0.0);
+
+            client_data_map.insert(transaction.client_id, cd);
+        }
+
+        transactions_map.insert(transaction.tx_id, transaction);
+
+        Ok(())
+    }
+
+
+    // Tries to withdraw funds from an account.
+    // If no matching accounts exist, the transaction is ignored.
+    // Tiers allowed to go negative can withdraw past their available balance.
+    // Under the deposits-only dispute policy, the withdrawal isn't indexed, since nothing can reference it later.
+    pub(crate) fn try_withdrawal(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+        tier_limits : &TierLimits,
+        dispute_policy : DisputePolicy,
+        allow_zero : bool,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if transactions_map.contains_key(&transaction.tx_id) {   
+            return Err(Rejection::new(DUPLICATE_TX, "Error! Transaction ID already exists. Ignoring.").into());  
+        }
+
+        let amount = transaction.amount.ok_or(Rejection::new(MISSING_AMOUNT, "Error! Attempting to withdraw without an amount. Ignoring."))?;
+        if amount < 0.0f64 || (amount == 0.0 && !allow_zero) { 
+            return Err(Rejection::new(NON_POSITIVE_AMOUNT, "Error! Attempting to withdraw a zero or negative balance. Ignoring.").into());   
+        }
+        if tier_limits.max_withdrawal.is_some_and(|max_withdrawal| amount > max_withdrawal) {
+            return Err(Rejection::new(TIER_LIMIT, "Error! Withdrawal exceeds the limit of the account's tier. Ignoring.").into());
+        }
+
+        let client_data = client_data_map.get_mut(&transaction.client_id);
+
+        if let Some(cd) = client_data {
+            if cd.is_locked() {
+                return Err(Rejection::new(ACCOUNT_LOCKED, "Error! Attempting to withdraw from a locked account. Ignoring.").into());  
+            }
+            if cd.available < 0.0 && !tier_limits.allow_negative { // in case a dispute was filed against an already withdrawn balance
+                return Err(Rejection::new(INSUFFICIENT_FUNDS, "Error! Attempting to withdraw with negative balance. Ignoring.").into());   
+            }
+
+            // only the funds of the transaction's wallet can be withdrawn
+            if cd.wallet_available(&transaction.wallet) >= amount || tier_limits.allow_negative {
+                cd.adjust(&transaction.wallet, -amount, 0.0);
+            }
+            else {
+                return Err(Rejection::new(INSUFFICIENT_FUNDS, "Error! Attempting to withdraw with insufficient balance. Ignoring.").into()); 
+            }
+        }
+
+        else {
+            return Err(Rejection::new(UNKNOWN_ACCOUNT, "Error! Attempting to withdraw from nonexistent account. Ignoring.").into()); 
+        }
+
+        if dispute_policy == DisputePolicy::Any {
+            transactions_map.insert(transaction.tx_id, transaction);
+        }
+
+        Ok(())
+    }
+
+
+
+    // I am allowing disputes against both deposits and withdrawals.
+    // This should allow the available balance to be negative, since a withdrawal may occur before its dispute.
+    // I'm assuming that the client ID for a dispute must match the client's ID in the disputed transaction.
+    // Each time a client is flagged with a dispute, they gain a lock for that transaction, which freezes their account.
+    // That's unless the config only locks on chargebacks (or never).
+    pub(crate) fn try_dispute(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        operator : &mut OperatorAccounts,
+        transaction : Transaction,
+        clock : &dyn Clock,
+        config : &EngineConfig,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+
+        if let Some(te) = transaction_entry {
+            
+            if te.dispute_status == DisputeStatus::Reversed {
+                return Err(Rejection::new(TX_SETTLED, "Error! Transaction was reversed and can't be disputed! Ignoring.").into()); 
+            }
+            else if te.dispute_status != DisputeStatus::NotDisputed {
+                return Err(Rejection::new(ALREADY_DISPUTED, "Error! Transaction is already disputed! Ignoring.").into()); 
+            }
+            else if te.client_id != transaction.client_id {
+                return Err(Rejection::new(CLIENT_MISMATCH, "Error! Transaction is being disputed by an unrelated user! Ignoring.").into()); 
+            }
+
+            let client_data = client_data_map.get_mut(&transaction.client_id);
+
+            if let Some(cd) = client_data {
+                let mut amount = te.net_amount();
+                if amount > cd.available { // holding it all would leave more held than the total
+                    match config.shortfall {
+                        ShortfallPolicy::Allow => {},
+                        ShortfallPolicy::Reject => return Err(FlaggedForReview { code: DISPUTE_SHORTFALL, reason: "dispute would hold more than the client's total".to_string() }.into()),
+                        ShortfallPolicy::Cap => amount = cd.available.max(0.0),
+                        ShortfallPolicy::Clamp => {
+                            te.shortfall = amount - cd.available.max(0.0);
+                            operator.losses += te.shortfall;
+                            amount -= te.shortfall;
+                        },
+                    }
+                }
+
+                te.dispute_status = DisputeStatus::UnderDispute;
+                te.disputed_at = clock.now();
+                te.held = amount;
+                cd.adjust(&te.wallet, -amount, amount);
+                operator.escrow += amount;
+                if config.lock_on == LockTrigger::Dispute {
+                    cd.locks.insert(LockReason::OpenDispute(transaction.tx_id));
+                }
+            }
+
+            else {
+                return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the disputed transaction! Ignoring.").into()); 
+            }
+        }
+
+        else {
+            return Err(Rejection::new(TX_NOT_FOUND, "Error! There is no transaction to dispute! Ignoring.").into()); 
+        }
+
+
+        Ok(())
+    }
+
+
+    // I am assuming that the client ID for a resolve must match the client's ID in the disputed transaction.
+    // Each time a client's dispute is resolved, they lose the lock for that transaction.
+    // Their account is only unfrozen once no locks remain.
+    pub(crate) fn try_resolve(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        operator : &mut OperatorAccounts,
+        transaction : Transaction,
+        race : RacePolicy,
+    ) -> Result<(), Box<dyn Error>> {
+
+
+
+        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+
+        if let Some(te) = transaction_entry {
+            
+            if te.dispute_status == DisputeStatus::ChargedBack && race == RacePolicy::Error {
+                return Err(DisputeRace { tx_id: transaction.tx_id }.into());
+            }
+            else if te.dispute_status != DisputeStatus::UnderDispute {
+                return Err(Rejection::new(NOT_DISPUTED, "Error! Transaction is not disputed! Ignoring.").into()); 
+            }
+            else if te.client_id != transaction.client_id {
+                return Err(Rejection::new(CLIENT_MISMATCH, "Error! Transaction is being resolved by an unrelated user! Ignoring.").into()); 
+            }
+
+            let client_data = client_data_map.get_mut(&transaction.client_id);
+
+            if let Some(cd) = client_data {
+                let amount = te.held;
+                cd.adjust(&te.wallet, amount, -amount);
+                operator.escrow -= amount;
+                operator.losses -= te.shortfall; // the client's funds covered the dispute after all
+                cd.locks.remove(&LockReason::OpenDispute(transaction.tx_id));
+                te.dispute_status = DisputeStatus::Resolved;
+            }
+
+            else {
+                return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the disputed transaction! Ignoring.").into()); 
+            }
+        }
+
+        else {
+            return Err(Rejection::new(TX_NOT_FOUND, "Error! There is no transaction to resolve! Ignoring.").into()); 
+        }
+
+
+        Ok(())
+    }
+
+
+
+    // I am assuming that the client ID for a chargeback must match the client's ID in the disputed transaction.
+    // Each time a client's dispute is charged back, the dispute's lock is replaced by the chargeback freeze, which no resolve can remove.
+    // This means their account is permanently frozen (we could assume they would need to contact the service provider).
+    // With "lock_on" set to "never", the account is left unlocked instead.
+    // The charged back funds move from escrow to the operator's losses, and the configured chargeback fee is charged to the client.
+    // Under the "chargeback-wins" race policy, an already resolved dispute can still be charged back, taking the funds
+    // the resolve returned out of available instead.
+    pub(crate) fn try_chargeback(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        operator : &mut OperatorAccounts,
+        transaction : Transaction,
+        clock : &dyn Clock,
+        config : &EngineConfig,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+
+        if let Some(te) = transaction_entry {
+            
+            let was_resolved = te.dispute_status == DisputeStatus::Resolved;
+
+            if was_resolved && config.race == RacePolicy::Error {
+                return Err(DisputeRace { tx_id: transaction.tx_id }.into());
+            }
+            else if te.dispute_status != DisputeStatus::UnderDispute && !(was_resolved && config.race == RacePolicy::ChargebackWins) {
+                return Err(Rejection::new(NOT_DISPUTED, "Error! Transaction is not disputed! Ignoring.").into()); 
+            }
+            else if te.client_id != transaction.client_id {
+                return Err(Rejection::new(CLIENT_MISMATCH, "Error! Transaction is being charged back by an unrelated user! Ignoring.").into()); 
+            }
+
+            let client_data = client_data_map.get_mut(&transaction.client_id);
+
+            if let Some(cd) = client_data {
+                let amount = te.held;
+                if was_resolved {
+                    cd.adjust(&te.wallet, -amount, 0.0);
+                    operator.losses += te.shortfall; // taken back out by the resolve
+                }
+                else {
+                    cd.adjust(&te.wallet, 0.0, -amount);
+                    operator.escrow -= amount;
+                }
+                operator.losses += amount;
+
+                cd.adjust(&te.wallet, -config.chargebacks.fee, 0.0);
+                operator.fees += config.chargebacks.fee;
+
+                cd.locks.remove(&LockReason::OpenDispute(transaction.tx_id));
+                if config.lock_on != LockTrigger::Never {
+                    cd.frozen = true;
+                }
+                te.dispute_status = DisputeStatus::ChargedBack;
+                te.charged_back_at = clock.now();
+            }
+
+            else {
+                return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the disputed transaction! Ignoring.").into()); 
+            }
+        }
+
+        else {
+            return Err(Rejection::new(TX_NOT_FOUND, "Error! There is no transaction to charge back! Ignoring.").into()); 
+        }
+
+        Ok(())
+    }
+
+
+    // Reverses a chargeback after a successful representment (the merchant won): the charged back funds return
+    // to available, and the chargeback freeze is lifted, unless another of the client's transactions is still charged back.
+    // With a reversal window configured, both the
+    // chargeback and this row need a timestamp, and this row must be dated within the window after the chargeback.
+    pub(crate) fn try_chargeback_reversal(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        operator : &mut OperatorAccounts,
+        transaction : Transaction,
+        clock : &dyn Clock,
+        chargeback_rules : &ChargebackRules,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+
+        if let Some(te) = transaction_entry {
+
+            if te.dispute_status != DisputeStatus::ChargedBack {
+                return Err(Rejection::new(NOT_CHARGED_BACK, "Error! Transaction was not charged back! Ignoring.").into());
+            }
+            else if te.client_id != transaction.client_id {
+                return Err(Rejection::new(CLIENT_MISMATCH, "Error! Chargeback is being reversed by an unrelated user! Ignoring.").into());
+            }
+
+            if let Some(window) = chargeback_rules.reversal_window_seconds {
+                let (Some(charged_back_at), Some(timestamp)) = (te.charged_back_at, clock.now()) else {
+                    return Err(Rejection::new(REVERSAL_WINDOW, "Error! Chargeback reversal window can't be checked without timestamps! Ignoring.").into());
+                };
+
+                if !(0..=window).contains(&timestamp.saturating_sub(charged_back_at)) {
+                    return Err(Rejection::new(REVERSAL_WINDOW, "Error! Chargeback reversal is outside of the reversal window! Ignoring.").into());
+                }
+            }
+
+            te.dispute_status = DisputeStatus::ChargebackReversed;
+            let (amount, shortfall) = (te.held, te.shortfall);
+            let wallet = te.wallet.clone();
+
+            // Reversals are rare enough that scanning for the client's other chargebacks is fine.
+            let other_chargebacks = transactions_map
+                .values()
+                .any(|te| te.client_id == transaction.client_id && te.dispute_status == DisputeStatus::ChargedBack);
+
+            let client_data = client_data_map.get_mut(&transaction.client_id);
+
+            if let Some(cd) = client_data {
+                cd.adjust(&wallet, amount, 0.0);
+                operator.losses -= amount + shortfall; // the fee isn't refunded
+                if !other_chargebacks {
+                    cd.frozen = false;
+                }
+            }
+
+            else {
+                return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the charged back transaction! Ignoring.").into());
+            }
+        }
+
+        else {
+            return Err(Rejection::new(TX_NOT_FOUND, "Error! There is no charged back transaction to reverse! Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Undoes the balance effect of a prior deposit or withdrawal, as sent by gateways when a payment is voided.
+    // Transactions under dispute or charged back can't be reversed, since the dispute flow already moved their funds.
+    // A reversal is a correction rather than client activity, so it's applied to locked accounts too.
+    pub(crate) fn try_reversal(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+
+        if let Some(te) = transaction_entry {
+
+            if !matches!(te.dispute_status, DisputeStatus::NotDisputed | DisputeStatus::Resolved) {
+                return Err(Rejection::new(TX_SETTLED, "Error! Transaction is disputed, charged back or already reversed! Ignoring.").into());
+            }
+            else if te.client_id != transaction.client_id {
+                return Err(Rejection::new(CLIENT_MISMATCH, "Error! Transaction is being reversed by an unrelated user! Ignoring.").into());
+            }
+
+            let client_data = client_data_map.get_mut(&transaction.client_id);
+
+            if let Some(cd) = client_data {
+                let amount = te.net_amount();
+                let signed_amount = if te.tx_type == "withdrawal" {-amount} else {amount}; // the original effect on the balance
+                cd.adjust(&te.wallet, -signed_amount, 0.0);
+                te.dispute_status = DisputeStatus::Reversed;
+            }
+
+            else {
+                return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the reversed transaction! Ignoring.").into());
+            }
+        }
+
+        else {
+            return Err(Rejection::new(TX_NOT_FOUND, "Error! There is no transaction to reverse! Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Refunds part or all of a prior deposit, referenced by its tx ID. A deposit can't be refunded past its amount,
+    // and a later dispute of it only holds what's left. Deposits under dispute, charged back or reversed can't be refunded.
+    pub(crate) fn try_refund(
+        transactions_map : &mut HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let amount = transaction.amount.ok_or(Rejection::new(MISSING_AMOUNT, "Error! Attempting to refund without an amount. Ignoring."))?;
+        if amount <= 0.0f64 {
+            return Err(Rejection::new(NON_POSITIVE_AMOUNT, "Error! Attempting to refund a zero or negative balance. Ignoring.").into());
+        }
+
+        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+
+        if let Some(te) = transaction_entry {
+
+            if te.tx_type != "deposit" {
+                return Err(Rejection::new(INVALID_REFUND, "Error! Only deposits can be refunded! Ignoring.").into());
+            }
+            else if !matches!(te.dispute_status, DisputeStatus::NotDisputed | DisputeStatus::Resolved) {
+                return Err(Rejection::new(TX_SETTLED, "Error! Transaction is disputed, charged back or reversed! Ignoring.").into());
+            }
+            else if te.client_id != transaction.client_id {
+                return Err(Rejection::new(CLIENT_MISMATCH, "Error! Transaction is being refunded by an unrelated user! Ignoring.").into());
+            }
+            else if amount > te.net_amount() {
+                return Err(Rejection::new(INVALID_REFUND, "Error! Attempting to refund more than the remaining deposit amount. Ignoring.").into());
+            }
+
+            let client_data = client_data_map.get_mut(&transaction.client_id);
+
+            if let Some(cd) = client_data {
+                if cd.is_locked() {
+                    return Err(Rejection::new(ACCOUNT_LOCKED, "Error! Attempting to refund from a locked account. Ignoring.").into());
+                }
+
+                cd.adjust(&te.wallet, -amount, 0.0);
+                te.refunded += amount;
+            }
+
+            else {
+                return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the refunded deposit! Ignoring.").into());
+            }
+        }
+
+        else {
+            return Err(Rejection::new(TX_NOT_FOUND, "Error! There is no deposit to refund! Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Admin rows freezing and unfreezing an account by hand. Their tx ID isn't used.
+    // Unfreezing only clears the admin freeze, any dispute locks or chargeback freeze stay in place.
+    // Those are cleared independently, by the "clear_dispute_locks" and "clear_chargeback_freeze" admin rows.
+    pub(crate) fn try_freeze(
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(UNKNOWN_ACCOUNT, "Error! Attempting to freeze a nonexistent account. Ignoring.").into());
+        };
+
+        if !cd.locks.insert(LockReason::AdminFreeze) {
+            return Err(Rejection::new(ALREADY_FROZEN, "Error! Account is already frozen by an admin. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    pub(crate) fn try_unfreeze(
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(UNKNOWN_ACCOUNT, "Error! Attempting to unfreeze a nonexistent account. Ignoring.").into());
+        };
+
+        if !cd.locks.remove(&LockReason::AdminFreeze) {
+            return Err(Rejection::new(NOT_FROZEN, "Error! Account is not frozen by an admin. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    // Lifts the dispute locks without touching the disputes themselves, which keep their funds held until resolved or charged back.
+    pub(crate) fn try_clear_dispute_locks(
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(UNKNOWN_ACCOUNT, "Error! Attempting to clear the locks of a nonexistent account. Ignoring.").into());
+        };
+
+        let lock_count = cd.locks.len();
+        cd.locks.retain(|reason| !matches!(reason, LockReason::OpenDispute(_)));
+
+        if cd.locks.len() == lock_count {
+            return Err(Rejection::new(NO_DISPUTE_LOCKS, "Error! Account has no dispute locks. Ignoring.").into());
+        }
+
+        Ok(())
+    }
+
+
+    pub(crate) fn try_clear_chargeback_freeze(
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(UNKNOWN_ACCOUNT, "Error! Attempting to clear the freeze of a nonexistent account. Ignoring.").into());
+        };
+
+        if !cd.frozen {
+            return Err(Rejection::new(NOT_FROZEN, "Error! Account is not frozen by a chargeback. Ignoring.").into());
+        }
+
+        cd.frozen = false;
+
+        Ok(())
+    }
+
+
+    // Opens a joint account, owned by the row's client and the co-owners in its "owners" column.
+    // The account is created empty if the client has none yet. A co-owner can't have an account of their own,
+    // nor own another joint account, since their rows couldn't be told apart.
+    // Blocked and denied clients can't become co-owners either.
+    pub(crate) fn try_open(
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        joint_owners : &mut HashMap<ClientId, ClientId>,
+        transaction : Transaction,
+        blocklist : &HashMap<ClientId, String>,
+        rules : &Rules,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if transaction.owners.is_empty() {
+            return Err(Rejection::new(INVALID_JOINT_ACCOUNT, "Error! Attempting to open a joint account without co-owners. Ignoring.").into());
+        }
+
+        for owner in &transaction.owners {
+            if *owner == transaction.client_id {
+                return Err(Rejection::new(INVALID_JOINT_ACCOUNT, "Error! A client can't be a co-owner of their own account. Ignoring.").into());
+            }
+            if client_data_map.contains_key(owner) || joint_owners.contains_key(owner) {
+                return Err(Rejection::new(INVALID_JOINT_ACCOUNT, "Error! A co-owner already has an account. Ignoring.").into());
+            }
+            if let Some(reason) = blocklist.get(owner) {
+                return Err(BlockedClient { reason: reason.clone() }.into());
+            }
+            if rules.deny_clients.contains(owner) {
+                return Err(Rejection::new(CLIENT_DENIED, "Error! A co-owner is denied by the configured rules. Ignoring.").into());
+            }
+        }
+
+        let cd = client_data_map.entry(transaction.client_id).or_default();
+        if cd.is_locked() {
+            return Err(Rejection::new(ACCOUNT_LOCKED, "Error! Attempting to add co-owners to a locked account. Ignoring.").into());
+        }
+
+        for owner in transaction.owners {
+            cd.owners.insert(owner);
+            joint_owners.insert(owner, transaction.client_id);
+        }
+
+        Ok(())
+    }
+
+
+    // Places a hold (pre-authorization), moving the amount from available to held.
+    // The hold expires after the configured time, if both the row and the config have one.
+    pub(crate) fn try_hold(
+        holds : &mut HashMap<TxId, Hold>,
+        holds_expiry_queue : &mut VecDeque<TxId>,
+        transactions_map : &HashMap<TxId, Transaction>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+        clock : &dyn Clock,
+        hold_rules : &HoldRules,
+    ) -> Result<(), Box<dyn Error>> {
+
+        if transactions_map.contains_key(&transaction.tx_id) || holds.contains_key(&transaction.tx_id) {
+            return Err(Rejection::new(DUPLICATE_TX, "Error! Transaction ID already exists. Ignoring.").into());
+        }
+
+        let amount = transaction.amount.ok_or(Rejection::new(MISSING_AMOUNT, "Error! Attempting to hold without an amount. Ignoring."))?;
+        if amount <= 0.0f64 {
+            return Err(Rejection::new(NON_POSITIVE_AMOUNT, "Error! Attempting to hold a zero or negative balance. Ignoring.").into());
+        }
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(UNKNOWN_ACCOUNT, "Error! Attempting to hold funds of a nonexistent account. Ignoring.").into());
+        };
+
+        if cd.is_locked() {
+            return Err(Rejection::new(ACCOUNT_LOCKED, "Error! Attempting to hold funds of a locked account. Ignoring.").into());
+        }
+        if cd.wallet_available(&transaction.wallet) < amount {
+            return Err(Rejection::new(INSUFFICIENT_FUNDS, "Error! Attempting to hold with insufficient balance. Ignoring.").into());
+        }
+
+        let expires_at = match clock.now().zip(hold_rules.expiry_seconds) {
+            Some((timestamp, expiry)) => Some(timestamp.checked_add(expiry).ok_or(Rejection::new(TIMESTAMP_OUT_OF_RANGE, "Error! The hold would expire past the latest supported timestamp. Ignoring."))?),
+            None => None,
+        };
+
+        cd.adjust(&transaction.wallet, -amount, amount);
+
+        if expires_at.is_some() {
+            holds_expiry_queue.push_back(transaction.tx_id);
+        }
+
+        holds.insert(transaction.tx_id, Hold {
+            client_id: transaction.client_id,
+            wallet: transaction.wallet,
+            placed_at: clock.now(),
+            amount,
+            expires_at,
+            status: HoldStatus::Open,
+        });
+
+        Ok(())
+    }
+
+
+    // Looks up the open hold a capture or release refers to. It must belong to the same client.
+    pub(crate) fn open_hold<'a>(
+        holds : &'a mut HashMap<TxId, Hold>,
+        transaction : &Transaction,
+    ) -> Result<&'a mut Hold, Box<dyn Error>> {
+
+        let Some(hold) = holds.get_mut(&transaction.tx_id) else {
+            return Err(Rejection::new(HOLD_NOT_FOUND, "Error! There is no hold with this transaction ID. Ignoring.").into());
+        };
+
+        if hold.client_id != transaction.client_id {
+            return Err(Rejection::new(CLIENT_MISMATCH, "Error! Hold belongs to an unrelated user. Ignoring.").into());
+        }
+
+        match hold.status {
+            HoldStatus::Open => Ok(hold),
+            HoldStatus::Expired => Err(Rejection::new(HOLD_EXPIRED, "Error! Hold has expired. Ignoring.").into()),
+            _ => Err(Rejection::new(HOLD_SETTLED, "Error! Hold was already captured or released. Ignoring.").into()),
+        }
+    }
+
+
+    // Captures a hold, taking the held funds out of the account.
+    // A capture may carry a smaller amount than the hold, in which case the rest is released back to available.
+    pub(crate) fn try_capture(
+        holds : &mut HashMap<TxId, Hold>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let hold = open_hold(holds, &transaction)?;
+        let captured = transaction.amount.unwrap_or(hold.amount);
+
+        if captured <= 0.0f64 || captured > hold.amount {
+            return Err(Rejection::new(INVALID_CAPTURE, "Error! Capture amount must be positive and within the held amount. Ignoring.").into());
+        }
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the hold! Ignoring.").into());
+        };
+
+        if cd.is_locked() {
+            return Err(Rejection::new(ACCOUNT_LOCKED, "Error! Attempting to capture from a locked account. Ignoring.").into());
+        }
+
+        cd.adjust(&hold.wallet, hold.amount - captured, -hold.amount);
+        hold.status = HoldStatus::Captured;
+
+        Ok(())
+    }
+
+
+    // Releases a hold, returning the held funds to available. This is allowed on locked accounts, since no money leaves.
+    pub(crate) fn try_release(
+        holds : &mut HashMap<TxId, Hold>,
+        client_data_map : &mut HashMap<ClientId, ClientData>,
+        transaction : Transaction,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let hold = open_hold(holds, &transaction)?;
+
+        let Some(cd) = client_data_map.get_mut(&transaction.client_id) else {
+            return Err(Rejection::new(ORPHANED_TX, "Error! There was no client associated with the hold! Ignoring.").into());
+        };
+
+        cd.adjust(&hold.wallet, hold.amount, -hold.amount);
+        hold.status = HoldStatus::Released;
+
+        Ok(())
+    }
+
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{engine::*, options::*, outputs::*};
+        use crate::testing::*;
+
+        // Places a hold from a row, ticking the clock to it first like the engine does.
+        fn hold(
+            holds : &mut HashMap<TxId, Hold>,
+            queue : &mut VecDeque<TxId>,
+            clients : &mut HashMap<ClientId, ClientData>,
+            csv_line : &str,
+            expiry_seconds : Option<i64>,
+        ) -> Result<(), Box<dyn Error>> {
+            let transaction = row(csv_line);
+            let mut clock = InputClock::default();
+            clock.tick(&transaction);
+            try_hold(holds, queue, &HashMap::new(), clients, transaction, &clock, &HoldRules { expiry_seconds })
+        }
+
+        #[test]
+        fn hold_moves_the_amount_from_available_to_held() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,7,40,", None).unwrap();
+
+            let client = &clients[&1];
+            assert_eq!((client.available, client.held, client.total), (60.0, 40.0, 100.0));
+            assert_eq!(holds[&7].status, HoldStatus::Open);
+            assert!(queue.is_empty(), "holds without a timestamp never expire");
+        }
+
+        #[test]
+        fn hold_is_rejected_without_the_funds_or_the_account() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 10.0));
+
+            assert_eq!(code_of(hold(&mut holds, &mut queue, &mut clients, "hold,1,1,10.5,", None)), INSUFFICIENT_FUNDS);
+            assert_eq!(code_of(hold(&mut holds, &mut queue, &mut clients, "hold,1,2,,", None)), MISSING_AMOUNT);
+            assert_eq!(code_of(hold(&mut holds, &mut queue, &mut clients, "hold,1,3,0,", None)), NON_POSITIVE_AMOUNT);
+            assert_eq!(code_of(hold(&mut holds, &mut queue, &mut clients, "hold,2,4,1,", None)), UNKNOWN_ACCOUNT);
+
+            clients.get_mut(&1).unwrap().frozen = true;
+            assert_eq!(code_of(hold(&mut holds, &mut queue, &mut clients, "hold,1,5,1,", None)), ACCOUNT_LOCKED);
+
+            assert!(holds.is_empty());
+            assert_eq!(clients[&1].available, 10.0);
+        }
+
+        #[test]
+        fn hold_reusing_an_id_is_a_duplicate() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,10,", None).unwrap();
+            assert_eq!(code_of(hold(&mut holds, &mut queue, &mut clients, "hold,1,1,10,", None)), DUPLICATE_TX);
+            assert_eq!(clients[&1].held, 10.0);
+        }
+
+        #[test]
+        fn hold_only_uses_the_funds_of_its_wallet() {
+            let (mut holds, mut queue) = (HashMap::new(), VecDeque::new());
+            let mut clients = funded(1, 100.0);
+            clients.get_mut(&1).unwrap().adjust("savings", 5.0, 0.0);
+
+            let mut clock = InputClock::default();
+            let mut transaction = row("hold,1,1,10,");
+            transaction.wallet = "savings".to_string();
+            clock.tick(&transaction);
+
+            let result = try_hold(&mut holds, &mut queue, &HashMap::new(), &mut clients, transaction, &clock, &HoldRules::default());
+            assert_eq!(code_of(result), INSUFFICIENT_FUNDS);
+        }
+
+        #[test]
+        fn hold_expires_only_with_a_timestamp_and_an_expiry() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,10,1000", None).unwrap();
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,2,10,", Some(60)).unwrap();
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,3,10,1000", Some(60)).unwrap();
+
+            assert_eq!((holds[&1].expires_at, holds[&2].expires_at, holds[&3].expires_at), (None, None, Some(1060)));
+            assert_eq!(queue, VecDeque::from([3]));
+        }
+
+        #[test]
+        fn hold_expiring_past_the_timestamp_range_is_rejected() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+
+            let result = hold(&mut holds, &mut queue, &mut clients, &format!("hold,1,1,10,{}", i64::MAX), Some(60));
+
+            assert_eq!(code_of(result), TIMESTAMP_OUT_OF_RANGE);
+            assert!(holds.is_empty() && queue.is_empty());
+            assert_eq!(clients[&1].available, 100.0);
+        }
+
+        #[test]
+        fn full_capture_takes_the_held_funds_out() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,40,", None).unwrap();
+
+            try_capture(&mut holds, &mut clients, row("capture,1,1,,")).unwrap();
+
+            let client = &clients[&1];
+            assert_eq!((client.available, client.held, client.total), (60.0, 0.0, 60.0));
+            assert_eq!(holds[&1].status, HoldStatus::Captured);
+        }
+
+        #[test]
+        fn partial_capture_releases_the_rest() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,40,", None).unwrap();
+
+            try_capture(&mut holds, &mut clients, row("capture,1,1,25,")).unwrap();
+
+            let client = &clients[&1];
+            assert_eq!((client.available, client.held, client.total), (75.0, 0.0, 75.0));
+        }
+
+        #[test]
+        fn capture_is_rejected_outside_the_held_amount() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,40,", None).unwrap();
+
+            assert_eq!(code_of(try_capture(&mut holds, &mut clients, row("capture,1,1,40.01,"))), INVALID_CAPTURE);
+            assert_eq!(code_of(try_capture(&mut holds, &mut clients, row("capture,1,1,-1,"))), INVALID_CAPTURE);
+            assert_eq!(holds[&1].status, HoldStatus::Open);
+            assert_eq!(clients[&1].held, 40.0);
+        }
+
+        #[test]
+        fn capture_is_rejected_on_a_locked_account_but_release_is_not() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,40,", None).unwrap();
+            clients.get_mut(&1).unwrap().frozen = true;
+
+            assert_eq!(code_of(try_capture(&mut holds, &mut clients, row("capture,1,1,,"))), ACCOUNT_LOCKED);
+
+            try_release(&mut holds, &mut clients, row("release,1,1,,")).unwrap();
+            let client = &clients[&1];
+            assert_eq!((client.available, client.held, client.total), (100.0, 0.0, 100.0));
+            assert_eq!(holds[&1].status, HoldStatus::Released);
+        }
+
+        #[test]
+        fn settled_or_unrelated_holds_are_rejected() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+            clients.extend(funded(2, 100.0));
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,40,", None).unwrap();
+
+            assert_eq!(code_of(try_release(&mut holds, &mut clients, row("release,1,9,,"))), HOLD_NOT_FOUND);
+            assert_eq!(code_of(try_release(&mut holds, &mut clients, row("release,2,1,,"))), CLIENT_MISMATCH);
+
+            try_release(&mut holds, &mut clients, row("release,1,1,,")).unwrap();
+            assert_eq!(code_of(try_release(&mut holds, &mut clients, row("release,1,1,,"))), HOLD_SETTLED);
+            assert_eq!(code_of(try_capture(&mut holds, &mut clients, row("capture,1,1,,"))), HOLD_SETTLED);
+            assert_eq!(clients[&1].available, 100.0);
+        }
+
+        #[test]
+        fn open_holds_expire_once_their_expiry_is_reached() {
+            let (mut holds, mut queue, mut clients) = (HashMap::new(), VecDeque::new(), funded(1, 100.0));
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,1,10,1000", Some(60)).unwrap();
+            hold(&mut holds, &mut queue, &mut clients, "hold,1,2,20,1030", Some(60)).unwrap();
+            try_capture(&mut holds, &mut clients, row("capture,1,1,,")).unwrap();
+
+            assert!(expire_holds(&mut holds, &mut queue, &mut clients, 1059).is_empty());
+            assert_eq!(queue.len(), 2, "nothing has expired yet");
+
+            assert_eq!(expire_holds(&mut holds, &mut queue, &mut clients, 1090), vec![2]);
+            assert!(queue.is_empty());
+            assert_eq!(holds[&1].status, HoldStatus::Captured, "a settled hold doesn't expire");
+            assert_eq!(holds[&2].status, HoldStatus::Expired);
+
+            let client = &clients[&1];
+            assert_eq!((client.available, client.held, client.total), (90.0, 0.0, 90.0));
+            assert_eq!(code_of(try_capture(&mut holds, &mut clients, row("capture,1,2,,"))), HOLD_EXPIRED);
+        }
+
+        #[test]
+        fn engine_expires_holds_by_the_timestamps_of_the_rows() {
+            let mut options = Options::default();
+            options.config.holds.expiry_seconds = Some(60);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,1000
+                hold,1,2,30,1000
+                deposit,1,3,1,1059
+                capture,1,2,,1060
+            ");
+
+            assert_eq!(rejections, [(2, HOLD_EXPIRED)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (101.0, 0.0, 101.0));
+        }
+
+        #[test]
+        fn holds_share_the_tx_id_space_but_cant_be_disputed() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount
+                deposit,1,1,100
+                hold,1,2,30
+                deposit,1,2,5
+                hold,1,1,5
+                dispute,1,2,
+            ");
+
+            assert_eq!(rejections, [(2, DUPLICATE_TX), (1, DUPLICATE_TX), (2, TX_NOT_FOUND)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (70.0, 30.0, 100.0));
+        }
+
+        #[test]
+        fn reversal_undoes_the_original_and_blocks_later_disputes() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount
+                deposit,1,1,100
+                withdrawal,1,2,30
+                reversal,1,2,
+                reversal,1,2,
+                dispute,1,2,
+                reversal,2,1,
+                reversal,1,9,
+            ");
+
+            assert_eq!(rejections, [(2, TX_SETTLED), (2, TX_SETTLED), (1, CLIENT_MISMATCH), (9, TX_NOT_FOUND)]);
+            assert_eq!(state.transactions[&2].dispute_status, DisputeStatus::Reversed);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.total, client.is_locked()), (100.0, 100.0, false));
+        }
+
+        #[test]
+        fn reversal_waits_for_the_dispute_to_be_resolved() {
+            let options = Options { config: EngineConfig { lock_on: LockTrigger::Never, ..EngineConfig::default() }, ..Options::default() };
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                dispute,1,1,
+                reversal,1,1,
+                resolve,1,1,
+                reversal,1,1,
+                dispute,1,2,
+                chargeback,1,2,
+                reversal,1,2,
+            ");
+
+            assert_eq!(rejections, [(1, TX_SETTLED), (2, TX_SETTLED)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn refunds_are_capped_at_what_is_left_of_the_deposit() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount
+                deposit,1,1,100
+                withdrawal,1,2,10
+                refund,1,1,30
+                refund,1,1,70.01
+                refund,1,1,50
+                refund,1,2,5
+                refund,1,1,0
+                refund,1,1,
+            ");
+
+            assert_eq!(rejections, [(1, INVALID_REFUND), (2, INVALID_REFUND), (1, NON_POSITIVE_AMOUNT), (1, MISSING_AMOUNT)]);
+            assert_eq!(state.transactions[&1].refunded, 80.0);
+            assert_eq!(state.clients[&1].total, 10.0);
+        }
+
+        #[test]
+        fn dispute_of_a_refunded_deposit_only_holds_the_rest() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount
+                deposit,1,1,100
+                refund,1,1,40
+                dispute,1,1,
+                refund,1,1,10
+            ");
+
+            assert_eq!(rejections, [(1, TX_SETTLED)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (0.0, 60.0, 60.0));
+        }
+
+        #[test]
+        fn chargeback_reversal_returns_the_funds_and_lifts_the_freeze() {
+            let mut options = Options::default();
+            options.config.chargebacks.fee = 5.0;
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                dispute,1,1,
+                chargeback_reversal,1,1,
+                chargeback,1,1,
+                chargeback_reversal,2,1,
+                chargeback_reversal,1,1,
+                chargeback_reversal,1,1,
+                withdrawal,1,3,10
+            ");
+
+            assert_eq!(rejections, [(1, NOT_CHARGED_BACK), (1, CLIENT_MISMATCH), (1, NOT_CHARGED_BACK)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.total, client.is_locked()), (135.0, 135.0, false), "the fee isn't refunded");
+            assert_eq!((state.operator.losses, state.operator.fees), (0.0, 5.0));
+        }
+
+        #[test]
+        fn chargeback_reversal_keeps_the_freeze_of_another_chargeback() {
+            let (state, _) = run(&Options::default(), "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                dispute,1,1,
+                chargeback,1,1,
+                dispute,1,2,
+                chargeback,1,2,
+                chargeback_reversal,1,1,
+            ");
+
+            let client = &state.clients[&1];
+            assert_eq!((client.total, client.is_locked()), (100.0, true));
+        }
+
+        #[test]
+        fn chargeback_reversal_must_be_within_the_window() {
+            let mut options = Options::default();
+            options.config.chargebacks.reversal_window_seconds = Some(3600);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,0
+                deposit,2,2,100,0
+                deposit,3,3,100,0
+                dispute,1,1,,0
+                dispute,2,2,,0
+                dispute,3,3,,0
+                chargeback,1,1,,100
+                chargeback,2,2,,100
+                chargeback,3,3,,100
+                chargeback_reversal,1,1,,3700
+                chargeback_reversal,2,2,,3701
+                chargeback_reversal,3,3,,
+            ");
+
+            assert_eq!(rejections, [(2, REVERSAL_WINDOW), (3, REVERSAL_WINDOW)]);
+            assert_eq!(state.clients[&1].total, 100.0);
+            assert_eq!((state.clients[&2].total, state.clients[&3].total), (0.0, 0.0));
+        }
+
+        #[test]
+        fn chargeback_reversal_cant_be_dated_before_the_chargeback() {
+            let mut options = Options::default();
+            options.config.chargebacks.reversal_window_seconds = Some(60);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,1000
+                dispute,1,1,,2000
+                chargeback,1,1,,3000
+                chargeback_reversal,1,1,,100
+            ");
+
+            assert_eq!(rejections, [(1, REVERSAL_WINDOW)]);
+            assert_eq!((state.clients[&1].total, state.clients[&1].is_locked()), (0.0, true));
+        }
+
+        #[test]
+        fn each_lock_is_lifted_on_its_own() {
+            let (state, rejections) = run_rows(&Options::default(), as_operator(rows("\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                dispute,1,1,
+                dispute,1,2,
+                freeze,1,90,
+                freeze,1,91,
+                resolve,1,1,
+                unfreeze,1,92,
+            ")));
+
+            assert_eq!(rejections, [(91, ALREADY_FROZEN)]);
+            assert_eq!(state.clients[&1].lock_reasons(), "dispute:2", "the other dispute still locks the account");
+        }
+
+        #[test]
+        fn admin_rows_clear_the_dispute_locks_or_the_chargeback_freeze() {
+            let (state, rejections) = run_rows(&Options::default(), as_operator(rows("\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                dispute,1,1,
+                chargeback,1,1,
+                dispute,1,2,
+                freeze,1,90,
+                clear_dispute_locks,1,91,
+                clear_dispute_locks,1,92,
+                clear_chargeback_freeze,1,93,
+                clear_chargeback_freeze,1,94,
+                unfreeze,1,95,
+                unfreeze,2,96,
+            ")));
+
+            assert_eq!(rejections, [(92, NO_DISPUTE_LOCKS), (94, NOT_FROZEN), (96, UNKNOWN_ACCOUNT)]);
+            let client = &state.clients[&1];
+            assert_eq!((client.is_locked(), client.held), (false, 50.0), "the dispute itself stays open");
+        }
+
+        #[test]
+        fn lock_trigger_decides_which_step_locks_the_account() {
+            let input = "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,50
+                dispute,1,1,
+                withdrawal,1,3,10
+                chargeback,1,1,
+                withdrawal,1,4,10
+            ";
+
+            for (lock_on, locked_rows, total, locked) in [
+                (LockTrigger::Dispute, vec![(3, ACCOUNT_LOCKED), (4, ACCOUNT_LOCKED)], 50.0, true),
+                (LockTrigger::Chargeback, vec![(4, ACCOUNT_LOCKED)], 40.0, true),
+                (LockTrigger::Never, vec![], 30.0, false),
+            ] {
+                let options = Options { config: EngineConfig { lock_on, ..EngineConfig::default() }, ..Options::default() };
+                let (state, rejections) = run(&options, input);
+
+                assert_eq!(rejections, locked_rows, "{:?}", lock_on);
+                assert_eq!((state.clients[&1].total, state.clients[&1].is_locked()), (total, locked), "{:?}", lock_on);
+            }
+        }
+
+        #[test]
+        fn operator_accounts_take_the_other_side_of_disputes() {
+            let mut options = Options::default();
+            options.config.chargebacks.fee = 2.5;
+
+            let (state, _) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,2,2,40
+                deposit,3,3,30
+                dispute,1,1,
+                dispute,2,2,
+                dispute,3,3,
+                resolve,2,2,
+                chargeback,3,3,
+            ");
+
+            let operator = &state.operator;
+            assert_eq!((operator.escrow, operator.losses, operator.fees), (100.0, 30.0, 2.5));
+            assert_eq!(state.clients[&3].total, -2.5, "the fee is taken from the client");
+        }
+
+        #[test]
+        fn withdrawals_and_disputes_stay_within_their_wallet() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount,wallet
+                deposit,1,1,100,
+                deposit,1,2,30,bonus
+                withdrawal,1,3,40,bonus
+                withdrawal,1,4,40,main
+                dispute,1,2,,
+            ");
+
+            assert_eq!(rejections, [(3, INSUFFICIENT_FUNDS)]);
+            let client = &state.clients[&1];
+            let (main, bonus) = (&client.wallets["main"], &client.wallets["bonus"]);
+            assert_eq!((main.available, main.held), (60.0, 0.0));
+            assert_eq!((bonus.available, bonus.held), (0.0, 30.0));
+            assert_eq!((client.available, client.held, client.total), (60.0, 30.0, 90.0));
+        }
+
+        #[test]
+        fn any_owner_of_a_joint_account_can_use_it() {
+            let (state, rejections) = run(&Options::default(), "\
+                type,client,tx,amount,owners
+                open,1,1,,2;3
+                deposit,2,2,100,
+                deposit,1,3,50,
+                withdrawal,3,4,30,
+                dispute,3,2,,
+                resolve,2,2,,
+            ");
+
+            assert!(rejections.is_empty(), "{:?}", rejections);
+            assert_eq!(state.clients.len(), 1, "the co-owners have no accounts of their own");
+            let account = &state.clients[&1];
+            assert_eq!((account.total, account.owners.iter().copied().collect::<Vec<_>>()), (120.0, vec![2, 3]));
+        }
+
+        #[test]
+        fn joint_accounts_need_new_co_owners() {
+            let mut options = Options::default();
+            options.config.rules.deny_clients = [9].into();
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount,owners
+                deposit,5,1,10,
+                open,1,2,,
+                open,1,3,,1
+                open,1,4,,5
+                open,1,5,,9
+                open,1,6,,2
+                open,4,7,,2
+            ");
+
+            assert_eq!(rejections, [(2, INVALID_JOINT_ACCOUNT), (3, INVALID_JOINT_ACCOUNT), (4, INVALID_JOINT_ACCOUNT), (5, CLIENT_DENIED), (7, INVALID_JOINT_ACCOUNT)]);
+            assert_eq!(state.clients[&1].total, 0.0, "opening creates the account empty");
+        }
+
+        #[test]
+        fn tiers_cap_the_amounts_and_may_go_negative() {
+            let mut options = Options::default();
+            options.config.tiers.get_mut(Tier::Basic).max_deposit = Some(100.0);
+            options.config.tiers.get_mut(Tier::Basic).max_withdrawal = Some(50.0);
+            options.config.tiers.get_mut(Tier::Premium).allow_negative = true;
+            options.data.client_limits = HashMap::from([
+                (2, ClientLimits { reserve: None, tier: Tier::Premium }),
+                (3, ClientLimits { reserve: Some(-25.0), tier: Tier::Premium }),
+            ]);
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,1,2,100.5
+                withdrawal,1,3,60
+                withdrawal,1,4,50
+                deposit,2,5,500
+                withdrawal,2,6,900
+                deposit,3,7,10
+                withdrawal,3,8,36
+                withdrawal,3,9,35
+            ");
+
+            assert_eq!(rejections, [(2, TIER_LIMIT), (3, TIER_LIMIT), (8, RESERVE_REQUIRED)]);
+            let totals: Vec<f64> = (1..=3).map(|client| state.clients[&client].total).collect();
+            assert_eq!(totals, [50.0, -400.0, -25.0], "a negative reserve is how far below zero a tier may go");
+        }
+
+        #[test]
+        fn deposits_and_withdrawals_without_an_amount_are_rejected() {
+            let (_, rejections) = run(&Options::default(), "type,client,tx,amount\ndeposit,1,1,\ndeposit,1,2,10\nwithdrawal,1,3,\n");
+
+            assert_eq!(rejections, [(1, MISSING_AMOUNT), (3, MISSING_AMOUNT)]);
+        }
+
+        #[test]
+        fn race_policy_decides_between_a_resolve_and_a_chargeback() {
+            let input = "\
+                type,client,tx,amount
+                deposit,1,1,100
+                deposit,2,2,50
+                dispute,1,1,
+                dispute,2,2,
+                resolve,1,1,
+                chargeback,1,1,
+                chargeback,2,2,
+                resolve,2,2,
+            ";
+
+            for (race, rejected, total) in [
+                (RacePolicy::FirstWins, vec![(1, NOT_DISPUTED), (2, NOT_DISPUTED)], 100.0),
+                (RacePolicy::ChargebackWins, vec![(2, NOT_DISPUTED)], 0.0),
+            ] {
+                let mut options = Options::default();
+                options.config.race = race;
+                let (state, rejections) = run(&options, input);
+
+                assert_eq!(rejections, rejected, "{:?}", race);
+                assert_eq!((state.clients[&1].total, state.clients[&1].is_locked()), (total, total == 0.0), "{:?}", race);
+                assert_eq!((state.clients[&2].total, state.operator.escrow), (0.0, 0.0), "{:?}", race);
+            }
+
+            let mut options = Options::default();
+            options.config.race = RacePolicy::Error;
+            let err = process_transactions(&options, &mut StagedOutputs::default(), &mut Rows(rows(input).into()), HashMap::new(), &mut [], &mut []).unwrap_err();
+            assert!(err.is::<DisputeRace>() && err.to_string().contains('1'), "{}", err);
+        }
+
+        #[test]
+        fn shortfall_policy_rejects_or_caps_disputes_of_withdrawn_funds() {
+            let input = "\
+                type,client,tx,amount
+                deposit,1,1,100
+                withdrawal,1,2,70
+                dispute,1,1,
+                resolve,1,1,
+                deposit,2,3,50
+                withdrawal,2,4,20
+                dispute,2,3,
+                chargeback,2,3,
+            ";
+
+            for (shortfall, rejected, totals, losses) in [
+                (ShortfallPolicy::Allow, vec![], (30.0, -20.0), 50.0),
+                (ShortfallPolicy::Reject, vec![(1, DISPUTE_SHORTFALL), (1, NOT_DISPUTED), (3, DISPUTE_SHORTFALL), (3, NOT_DISPUTED)], (30.0, 30.0), 0.0),
+                (ShortfallPolicy::Cap, vec![], (30.0, 0.0), 30.0),
+            ] {
+                let mut options = Options::default();
+                options.config.shortfall = shortfall;
+                let (state, rejections) = run(&options, input);
+
+                assert_eq!(rejections, rejected, "{:?}", shortfall);
+                assert_eq!((state.clients[&1].total, state.clients[&2].total, state.operator.losses), (totals.0, totals.1, losses), "{:?}", shortfall);
+            }
+        }
+
+        #[test]
+        fn capped_disputes_hold_only_what_was_available() {
+            let mut options = Options::default();
+            options.config.shortfall = ShortfallPolicy::Cap;
+            let (state, _) = run(&options, "type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,70\ndispute,1,1,\n");
+
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (0.0, 30.0, 30.0));
+            assert_eq!((state.transactions[&1].held, state.operator.escrow), (30.0, 30.0));
+        }
+
+        #[test]
+        fn clamped_disputes_book_the_shortfall_as_an_operator_loss_until_settled() {
+            let mut options = Options::default();
+            options.config.shortfall = ShortfallPolicy::Clamp;
+
+            let (state, _) = run(&options, "type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,70\ndispute,1,1,\n");
+            let client = &state.clients[&1];
+            assert_eq!((client.available, client.held, client.total), (0.0, 30.0, 30.0), "available is clamped at zero");
+            assert_eq!((state.transactions[&1].shortfall, state.operator.losses, state.operator.escrow), (70.0, 70.0, 30.0));
+
+            let (state, rejections) = run(&options, "\
+                type,client,tx,amount
+                deposit,1,1,100
+                withdrawal,1,2,70
+                dispute,1,1,
+                resolve,1,1,
+                deposit,2,3,50
+                withdrawal,2,4,20
+                dispute,2,3,
+                chargeback,2,3,
+                deposit,3,5,40
+                withdrawal,3,6,30
+                dispute,3,5,
+                chargeback,3,5,
+                chargeback_reversal,3,5,
+            ");
+
+            assert!(rejections.is_empty(), "{:?}", rejections);
+            assert_eq!(state.clients.values().filter(|client| client.held != 0.0).count(), 0);
+            assert_eq!((state.clients[&1].available, state.clients[&2].total, state.clients[&3].total), (30.0, 0.0, 10.0));
+            assert_eq!((state.operator.losses, state.operator.escrow), (50.0, 0.0), "only client 2's chargeback is still a loss, its shortfall included");
+        }
+    }
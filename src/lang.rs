@@ -0,0 +1,133 @@
+// The human-readable messages, per language.
+
+
+
+
+//* Structs *//
+
+    // Language of the human-readable outputs: the HTML report, the charts, "--verbose" and the rejection messages.
+    // The CSV reports, the outbox and the summary line are read by programs, so they stay as they are.
+    #[derive(Debug, Default, PartialEq, Clone, Copy)]
+    pub(crate) enum Lang {
+        #[default]
+        En,
+        Pt,
+    }
+
+    impl Lang {
+        pub(crate) fn catalog(self) -> &'static [(&'static str, &'static str)] {
+            match self {
+                Lang::En => MESSAGES_EN,
+                Lang::Pt => MESSAGES_PT,
+            }
+        }
+
+        pub(crate) fn lookup(self, id : &str) -> Option<&'static str> {
+            self.catalog().iter().find(|(message_id, _)| *message_id == id).map(|(_, text)| *text)
+        }
+
+        // Messages missing from a catalog fall back to English, and then to their ID, so a gap never hides a value.
+        pub(crate) fn text(self, id : &'static str) -> &'static str {
+            self.lookup(id).or_else(|| Lang::En.lookup(id)).unwrap_or(id)
+        }
+    }
+
+
+    // Messages by ID. The rejection messages are keyed by their reject code, and only translated ones are listed:
+    // in English a rejection keeps its original message, which can be more specific than the one of its code.
+    pub(crate) const MESSAGES_EN: &[(&str, &str)] = &[
+        ("lang", "en"),
+        ("accounts-report", "Accounts report"),
+        ("operator-accounts", "Operator accounts"),
+        ("summary-processed", "Processed"),
+        ("summary-accepted", "Accepted"),
+        ("summary-rejected", "Rejected"),
+        ("summary-clients", "Clients"),
+        ("summary-locked", "Locked"),
+        ("column-client", "client"),
+        ("column-available", "available"),
+        ("column-held", "held"),
+        ("column-total", "total"),
+        ("column-locked", "locked"),
+        ("column-name", "name"),
+        ("column-country", "country"),
+        ("column-segment", "segment"),
+        ("chart-top-clients", "Top 10 clients by total balance"),
+        ("chart-held-funds", "Clients by held funds"),
+        ("chart-reject-reasons", "Reject reasons"),
+        ("label-client", "client"),
+        ("label-amount", "amount"),
+        ("log-account-locked", "account locked"),
+        ("log-charged-back", "charged back"),
+    ];
+
+    pub(crate) const MESSAGES_PT: &[(&str, &str)] = &[
+        ("lang", "pt"),
+        ("accounts-report", "Relatório de contas"),
+        ("operator-accounts", "Contas do operador"),
+        ("summary-processed", "Processadas"),
+        ("summary-accepted", "Aceites"),
+        ("summary-rejected", "Rejeitadas"),
+        ("summary-clients", "Clientes"),
+        ("summary-locked", "Bloqueadas"),
+        ("column-client", "cliente"),
+        ("column-available", "disponível"),
+        ("column-held", "retido"),
+        ("column-total", "total"),
+        ("column-locked", "bloqueada"),
+        ("column-name", "nome"),
+        ("column-country", "país"),
+        ("column-segment", "segmento"),
+        ("chart-top-clients", "Top 10 clientes por saldo total"),
+        ("chart-held-funds", "Clientes por fundos retidos"),
+        ("chart-reject-reasons", "Motivos de rejeição"),
+        ("label-client", "cliente"),
+        ("label-amount", "montante"),
+        ("log-account-locked", "conta bloqueada"),
+        ("log-charged-back", "estornada"),
+        ("E1001", "Erro! O ID da transação já existe. Ignorada."),
+        ("E1002", "Erro! Tipo de transação inválido. Ignorada."),
+        ("E1003", "Erro! A transação não tem montante. Ignorada."),
+        ("E1004", "Erro! O montante é nulo ou negativo. Ignorada."),
+        ("E1005", "Erro! A transação tem colunas desconhecidas. Ignorada."),
+        ("E1006", "Erro! A transação não tem data, exigida pelas regras configuradas. Ignorada."),
+        ("E1007", "Erro! A transação é demasiado antiga, foi descartada pela política de retenção. Ignorada."),
+        ("E1008", "Erro! A definição recorrente é inválida. Ignorada."),
+        ("E1009", "Erro! Não existe um ponto de restauro com este ID. Ignorada."),
+        ("E1010", "Erro! A transação é posterior à data de --as-of. Ignorada."),
+        ("E1011", "Erro! A data resultante da transação está fora do intervalo suportado. Ignorada."),
+        ("E1012", "Erro! As operações de administração só são aceites do ficheiro de --admin-input. Ignorada."),
+        ("E1013", "Erro! O ficheiro de --admin-input só aceita operações de administração. Ignorada."),
+        ("E2001", "Erro! A conta está bloqueada. Ignorada."),
+        ("E2002", "Erro! A conta não existe. Ignorada."),
+        ("E2003", "Erro! O saldo é insuficiente. Ignorada."),
+        ("E2004", "Erro! A conta já está congelada por um administrador. Ignorada."),
+        ("E2005", "Erro! A conta não está congelada. Ignorada."),
+        ("E2006", "Erro! A conta não tem bloqueios por disputa. Ignorada."),
+        ("E2007", "Erro! A conta conjunta é inválida. Ignorada."),
+        ("E3001", "Erro! O montante excede o máximo configurado. Ignorada."),
+        ("E3002", "Erro! O cliente é recusado pelas regras configuradas. Ignorada."),
+        ("E3003", "Erro! O montante excede o limite do escalão da conta. Ignorada."),
+        ("E3004", "Erro! O levantamento excede o limite diário. Ignorada."),
+        ("E3005", "Erro! O levantamento deixaria o saldo abaixo da reserva exigida. Ignorada."),
+        ("E3006", "Erro! KYC: o depósito excede o limite para clientes não verificados. Ignorada."),
+        ("E3007", "Erro! KYC: clientes não verificados não podem levantar. Ignorada."),
+        ("E3008", "Erro! O cliente está na lista de bloqueio. Ignorada."),
+        ("E3009", "Sinalizada para revisão."),
+        ("E4001", "Erro! A transação referida não existe. Ignorada."),
+        ("E4002", "Erro! A transação referida pertence a outro cliente. Ignorada."),
+        ("E4003", "Erro! A transação já está em disputa. Ignorada."),
+        ("E4004", "Erro! A transação não está em disputa. Ignorada."),
+        ("E4005", "Erro! A transação não foi estornada. Ignorada."),
+        ("E4006", "Erro! A transação está em disputa, estornada ou revertida. Ignorada."),
+        ("E4007", "Erro! Os levantamentos não podem ser disputados nem revertidos com a política \"deposits-only\". Ignorada."),
+        ("E4008", "Erro! A reversão do estorno está fora da janela de reversão. Ignorada."),
+        ("E4009", "Erro! A transação referida não tem cliente associado. Ignorada."),
+        ("E4010", "Erro! O reembolso é inválido. Ignorada."),
+        ("E4011", "Sinalizada para revisão: a disputa reteria mais do que o total do cliente."),
+        ("E4012", "Aviso! A disputa só retém o saldo que o cliente tinha disponível."),
+        ("E5001", "Erro! Não existe uma retenção com este ID de transação. Ignorada."),
+        ("E5002", "Erro! A retenção expirou. Ignorada."),
+        ("E5003", "Erro! A retenção já foi capturada ou libertada. Ignorada."),
+        ("E5004", "Erro! O montante capturado tem de ser positivo e não exceder o montante retido. Ignorada."),
+    ];
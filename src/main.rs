@@ -1,410 +1,386 @@
-use std::{error::Error, io, process, env, collections::HashMap};
-use csv::Trim;
+use std::{io, process, collections::HashMap, time::{Duration, Instant}};
 
+mod checks;
+mod clock;
+mod config;
+mod engine;
+mod handlers;
+mod lang;
+mod observers;
+mod options;
+mod outputs;
+mod rejects;
+mod sources;
+#[cfg(test)]
+mod testing;
+mod types;
 
+use crate::{checks::*, engine::*, observers::*, options::*, outputs::*, sources::*, types::*};
 
 
 
-//* Structs *//
-
-    #[derive(Debug, serde::Deserialize)]
-    struct Transaction {
-        #[serde(rename = "tx")] // Redundant, since it's already the key of the dictionary. Could be upgraded for performance.
-        tx_id: u32,
-        #[serde(rename = "type")] // Due to Rust naming conventions, this field cannot be called "type".
-        tx_type: String,
-        #[serde(rename = "client")]
-        client_id: u16,
-        #[serde(rename = "amount")]
-        amount: Option<f64>, // Option since some transaction types don't have values for "amount"
-        #[serde(default)]
-        dispute_status: DisputeStatus,
-    }
-
-
-    #[derive(Debug)]
-    struct ClientData {
-        available: f64,
-        held: f64,
-        total: f64,
-        total_locks: u16, // There can be more than one simultaneous lock
-    }
-
-
-    #[derive(Debug, serde::Deserialize, PartialEq)]
-    enum DisputeStatus {
-        NotDisputed,
-        UnderDispute,
-        Resolved,
-        ChargedBack
-    } 
-
-    impl Default for DisputeStatus {
-        fn default() -> Self {
-            DisputeStatus::NotDisputed
-        }
-    }
-
-
+//* Main *//
 
-//* Logic *//
+    fn main() {
 
-    // Parses a file path as an argument, then uses it to read the corresponding CSV file.
-    // After the transaction data is parsed, a map containing the client's data is then returned.
-    fn read_csv() -> Result<HashMap<u16, ClientData>, Box<dyn Error>> {
+        let started = Instant::now();
 
-        let args: Vec<String> = env::args().collect();
+        let options = match parse_args() {
+            Ok(o) => o,
+            Err(e) => exit_with_error(&e.to_string(), ColorChoice::Auto),
+        };
 
-        if args.len() != 2 {
-            return Err("Error! Incorrect call, the usage is 'cargo run -- <file-path> > <output-destination>'".into());
+        // compared straight from the balances of both reports, nothing is processed
+        if let Some((before, after)) = &options.command.diff_reports {
+            let compared = load_report(before).and_then(|before_data| {
+                let after_data = load_report(after)?;
+                let changes = diff_reports(&before_data, &after_data);
+                write_diff(&changes)?;
+
+                let mut differing: Vec<ClientId> = changes.iter().map(|change| change.client).collect();
+                differing.sort();
+                differing.dedup();
+                eprintln!("clients={} differing={}", before_data.keys().chain(after_data.keys().filter(|client_id| !before_data.contains_key(client_id))).count(), differing.len());
+                Ok(())
+            });
+            if let Err(e) = compared {
+                exit_with_error(&format!("Error Writing Diff: {}", e), options.display.color);
+            }
+            return;
         }
 
-        let file_path = &args[1];
-
-        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(file_path).unwrap(); // remove whitespaces
-
-        let mut transactions_map : HashMap<u32, Transaction> = HashMap::new();
-        let mut client_data_map : HashMap<u16, ClientData> = HashMap::new(); // the return target
-
-
-        for row in reader.deserialize() {
-               
-            let transaction: Transaction = row?;
-
-            match transaction.tx_type.as_str() {
-
-                "deposit" => {
-                    if let Err(_err) = try_deposit(&mut transactions_map, &mut client_data_map, transaction) {
-                        // println!("{}", err);
-                        continue;
+        // rebuilt straight from the balances of the outbox events, nothing is processed
+        if options.command.command == Command::Rebuild {
+            let rebuilt = load_outbox(&options.input.file_path, options.command.undo_count).and_then(|client_data| {
+                if let Some(path) = &options.command.verify_report {
+                    let changes = diff_reports(&load_report(path)?, &client_data);
+                    for change in &changes {
+                        eprintln!("client={} field={} report={} rebuilt={}", change.client, change.field, change.before, change.after);
                     }
-                },
-
-                "withdrawal" => {
-                    if let Err(_err) = try_withdrawal(&mut transactions_map, &mut client_data_map, transaction) {
-                        // println!("{}", err);
-                        continue;
-                    }
-                },
-
-                "dispute" => {
-                    if let Err(_err) = try_dispute(&mut transactions_map, &mut client_data_map, transaction) {
-                        // println!("{}", err);
-                        continue;
+                    if !changes.is_empty() {
+                        return Err(format!("Error! The balances rebuilt from the outbox don't match report '{}'.", path).into());
                     }
-                },
-
-                "resolve" => {
-                    if let Err(_err) = try_resolve(&mut transactions_map, &mut client_data_map, transaction) {
-                        // println!("{}", err);
-                        continue;
-                    }
-                },
-
-                "chargeback" => {
-                    if let Err(_err) = try_chargeback(&mut transactions_map, &mut client_data_map, transaction) {
-                        // println!("{}", err);
-                        continue;
-                    }
-                },
-
-                _ => {
-                    // println!("Error! Transaction {}'s type is invalid! Ignoring.", transaction.tx_id);
-                    continue;
-                },
-            };
+                }
+                write_csv(client_data, false, &options.report.filter, options.report.output.as_deref(), options.report.split_rows, None)
+            });
+            if let Err(e) = rebuilt {
+                exit_with_error(&format!("Error Rebuilding Report: {}", e), options.display.color);
+            }
+            return;
         }
 
-        // println!("Transactions list: {:#?}\n", transactions_map);
-        // println!("Client Data: {:#?}\n", client_data_map);
-
-        Ok(client_data_map)
-    }
-
-
-    // Receives the client data map as an input, then sanitizes the data before exporting to the target path. 
-    fn write_csv(
-        client_data : HashMap<u16, ClientData>,
-    ) -> Result<(), Box<dyn Error>> {
-
-        let mut writer = csv::Writer::from_writer(io::stdout());
-
-        // create the header
-        writer.write_record(&["client", "available", "held", "total", "locked"])?;
-
-        for (client_id, client) in client_data {
-            
-            // This formats an f64 with 4 digits of precision
-            let formatted_available = (client.available * 10_000.0).round() / 10_000.0; 
-            let formatted_held = (client.held * 10_000.0).round() / 10_000.0;
-            let formatted_total = (client.total * 10_000.0).round() / 10_000.0;
-
-            let formatted_locked = if client.total_locks > 0u16 {true} else {false};
-
-            writer.write_record(&[
-                client_id.to_string(),
-                formatted_available.to_string(),
-                formatted_held.to_string(),
-                formatted_total.to_string(),
-                formatted_locked.to_string(),
-            ])?;
+        if options.command.command == Command::Compact {
+            let compacted = read_outbox(&options.input.file_path).and_then(|events| {
+                let read = events.len();
+                let kept = compact_outbox(events);
+                write_events(&kept)?;
+                eprintln!("events={} kept={}", read, kept.len());
+                Ok(())
+            });
+            if let Err(e) = compacted {
+                exit_with_error(&format!("Error Compacting Outbox: {}", e), options.display.color);
+            }
+            return;
         }
 
-        writer.flush()?;
-        Ok(())
-    }
-
-
-
-
-
-//* Auxiliary Functions *//
-
-    // Tries to deposit funds into an account.
-    // A new account is created if none exist with the given ID.
-    // This is currently the only way to create a new user entry.
-    fn try_deposit(
-        transactions_map : &mut HashMap<u32, Transaction>,
-        client_data_map : &mut HashMap<u16, ClientData>,
-        transaction : Transaction,
-    ) -> Result<(), Box<dyn Error>> {
-
-        if transactions_map.contains_key(&transaction.tx_id) {   
-            return Err("Error! Transaction ID already exists. Ignoring.".into());  
+        // ranked straight from the balances of the report, nothing is processed
+        if let Some(path) = &options.command.top_report {
+            let ranked = load_report(path).and_then(|client_data| {
+                write_top(&client_data, &RiskMonitor::default(), options.command.top_by, options.command.top_count.unwrap_or(DEFAULT_TOP_COUNT))
+            });
+            if let Err(e) = ranked {
+                exit_with_error(&format!("Error Writing CSV: {}", e), options.display.color);
+            }
+            return;
         }
 
-        let amount = transaction.amount.unwrap();
-        if amount <= 0.0f64 {
-            return Err("Error! Attempting to deposit a zero or negative balance. Ignoring.".into());
-        }    
-
-        let client_data = client_data_map.get_mut(&transaction.client_id);
-
-        if let Some(cd) = client_data {
-            if cd.total_locks > 0u16 {
-                return Err("Error! Attempting to deposit into a locked account. Ignoring.".into());  
+        // the file is run once per policy, so none of the other outputs apply
+        if options.command.command == Command::Whatif {
+            let color = options.display.color;
+            if let Err(e) = write_whatif(options) {
+                exit_with_error(&format!("Error Writing Simulation: {}", e), color);
             }
-
-            cd.available += amount;
-            cd.total += amount;
+            return;
         }
 
-        else {
-            let cd = ClientData { 
-                available: amount, 
-                held: 0.0f64, 
-                total: amount, 
-                total_locks: 0u16,
-            }; 
+        let mut summary = RunSummary { lang: options.display.lang, ..RunSummary::default() };
+
+        let redactor = options.display.redact.then(Redactor::new);
+
+        let mut verbose_logger = VerboseLogger { redactor, file_path: options.input.file_path.clone(), lang: options.display.lang };
+        let mut flags_report = FlagsReport::default();
+        let mut compliance_report = ComplianceReport::default();
+        let mut control_totals = ControlTotals::default();
+        let mut rejects_report = RejectsReport { lang: options.display.lang, file_path: options.input.file_path.clone(), ..RejectsReport::default() };
+        let mut quarantine = Quarantine::default();
+        let mut rollup = Rollup::new(options.command.period);
+        let mut risk_monitor = RiskMonitor::default();
+        let mut negative_balances = NegativeBalances::default();
+        let mut id_analysis = IdAnalysis::default();
+        let mut staged = StagedOutputs::default();
+        let mut outbox = match options.reports.outbox.as_deref().map(|path| Outbox::create(&staged.stage(path), options.data.clients_meta.as_ref(), &options.reports.passthrough_columns)).transpose() {
+            Ok(outbox) => outbox,
+            Err(e) => exit_with_error(&e.to_string(), options.display.color),
+        };
+        let mut observers: Vec<&mut dyn EngineObserver> = vec![&mut summary];
 
-            client_data_map.insert(transaction.client_id, cd);
+        if options.display.verbose {
+            observers.push(&mut verbose_logger);
+        }
+        if options.reports.flags_report.is_some() {
+            observers.push(&mut flags_report);
+        }
+        if options.reports.compliance_report.is_some() {
+            observers.push(&mut compliance_report);
+        }
+        if options.reports.rejects.is_some() {
+            observers.push(&mut rejects_report);
+        }
+        if options.reports.quarantine.is_some() || options.reports.malformed.is_some() {
+            observers.push(&mut quarantine);
+        }
+        if options.reports.risk_report.is_some() || options.command.command == Command::Top {
+            observers.push(&mut risk_monitor);
+        }
+        if options.reports.negative_report.is_some() {
+            observers.push(&mut negative_balances);
+        }
+        if options.reports.id_report.is_some() || options.reports.history.is_some() {
+            observers.push(&mut id_analysis);
+        }
+        if let Some(outbox) = outbox.as_mut() {
+            observers.push(outbox);
+        }
+        if let Command::Rollup = options.command.command {
+            observers.push(&mut rollup);
         }
 
-        transactions_map.insert(transaction.tx_id, transaction);
-
-        Ok(())
-    }
-
-
-    // Tries to withdraw funds from an account.
-    // If no matching accounts exist, the transaction is ignored.
-    fn try_withdrawal(
-        transactions_map : &mut HashMap<u32, Transaction>,
-        client_data_map : &mut HashMap<u16, ClientData>,
-        transaction : Transaction,
-    ) -> Result<(), Box<dyn Error>> {
+        // built-in stages, in the order they run
+        let mut type_aliases = TypeAliases { aliases: &options.config.aliases };
+        let mut strict_columns = StrictColumns { passthrough_columns: &options.reports.passthrough_columns };
+        let mut blocklist_check = BlocklistCheck { blocklist: &options.data.blocklist };
+        let mut stages: Vec<&mut dyn Stage> = Vec::new();
 
-        if transactions_map.contains_key(&transaction.tx_id) {   
-            return Err("Error! Transaction ID already exists. Ignoring.".into());  
+        if !options.config.aliases.is_empty() {
+            stages.push(&mut type_aliases);
         }
-
-        let amount = transaction.amount.unwrap();
-        if amount <= 0.0f64 { 
-            return Err("Error! Attempting to withdraw a zero or negative balance. Ignoring.".into());   
+        if options.input.strict {
+            stages.push(&mut strict_columns);
+        }
+        if !options.data.blocklist.is_empty() {
+            stages.push(&mut blocklist_check);
         }
 
-        let client_data = client_data_map.get_mut(&transaction.client_id);
+        let state = match read_csv(&options, &mut staged, options.data.control.is_some().then_some(&mut control_totals), &mut stages, &mut observers) {
+            Ok(state) => state,
+            Err(e) => abort_run(staged, &e.to_string(), options.display.color),
+        };
 
-        if let Some(cd) = client_data {
-            if cd.total_locks > 0u16 {
-                return Err("Error! Attempting to withdraw from a locked account. Ignoring.".into());  
-            }
-            if cd.available < 0.0 { // in case a dispute was filed against an already withdrawn balance
-                return Err("Error! Attempting to withdraw with negative balance. Ignoring.".into());   
+        // a mismatch usually means the transfer was truncated, so none of it is trusted
+        if let Some(expected) = &options.data.control {
+            let mismatches = control_totals.mismatches(expected);
+            if !mismatches.is_empty() {
+                for mismatch in &mismatches {
+                    eprintln!("{}", mismatch);
+                }
+                abort_run(staged, "Error! The input doesn't match its control totals, and its report wasn't written.", options.display.color);
             }
+        }
 
-            if cd.available >= amount {
-                cd.available -= amount;
-                cd.total -= amount;
-            }
-            else {
-                return Err("Error! Attempting to withdraw with insufficient balance. Ignoring.".into()); 
+        // the run is applied in memory first, and the files written while processing are removed, so an aborted one leaves nothing behind
+        if options.report.atomic {
+            let failures = check_acceptance(&state, &summary, &options.config.acceptance);
+            if !failures.is_empty() {
+                for failure in &failures {
+                    eprintln!("{}", failure);
+                }
+                eprintln!("{}", format_summary_line(&state.clients, &summary, started.elapsed()));
+                abort_run(staged, "Error! The run was aborted, and its report wasn't written.", options.display.color);
             }
         }
 
-        else {
-            return Err("Error! Attempting to withdraw from nonexistent account. Ignoring.".into()); 
+        // only runs that made it this far are recorded, since an aborted run's file is expected to be sent again
+        if let Some(path) = &options.reports.history {
+            let run = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+            match record_run_history(path, &options.input.file_path, run, &id_analysis) {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        eprintln!("{}", paint(&warning, RED, options.display.color.enabled_for(&io::stderr())));
+                    }
+                },
+                Err(e) => abort_run(staged, &format!("Error Writing History: {}", e), options.display.color),
+            }
         }
 
-        transactions_map.insert(transaction.tx_id, transaction);
-
-        Ok(())
-    }
-
-
-
-    // I am allowing disputes against both deposits and withdrawals.
-    // This should allow the available balance to be negative, since a withdrawal may occur before its dispute.
-    // I'm assuming that the client ID for a dispute must match the client's ID in the disputed transaction.
-    // Each time a client is flagged with a dispute, they gain 1u16 "total_locks" increment, which freezes their account.
-    fn try_dispute(
-        transactions_map : &mut HashMap<u32, Transaction>,
-        client_data_map : &mut HashMap<u16, ClientData>,
-        transaction : Transaction,
-    ) -> Result<(), Box<dyn Error>> {
+        if let Some(Err(e)) = outbox.map(Outbox::finish) {
+            abort_run(staged, &format!("Error Writing Outbox: {}", e), options.display.color);
+        }
 
-        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+        // from here on the run stands, so the files written while processing take their final names
+        if let Err(e) = staged.commit() {
+            exit_with_error(&e.to_string(), options.display.color);
+        }
 
-        if let Some(te) = transaction_entry {
-            
-            if te.dispute_status != DisputeStatus::NotDisputed {
-                return Err("Error! Transaction {} is already disputed! Ignoring.".into()); 
+        if let Some(path) = &options.reports.operator_report {
+            if let Err(e) = write_operator_report(path, &state.operator) {
+                exit_with_error(&format!("Error Writing Operator Report: {}", e), options.display.color);
             }
-            else if te.client_id != transaction.client_id {
-                return Err("Error! Transaction {} is being disputed by an unrelated user! Ignoring.\n".into()); 
-            }
-
-            let client_data = client_data_map.get_mut(&transaction.client_id);
+        }
 
-            if let Some(cd) = client_data {
-                let amount = te.amount.unwrap();
-                te.dispute_status = DisputeStatus::UnderDispute;
-                cd.available -= amount;
-                cd.held += amount;
-                cd.total_locks = cd.total_locks.checked_add(1u16).unwrap_or(u16::MAX); // prevent overflow
+        if let Some(path) = &options.reports.held_report {
+            if let Err(e) = write_held_report(path, &state) {
+                exit_with_error(&format!("Error Writing Held Funds Report: {}", e), options.display.color);
             }
+        }
 
-            else {
-                return Err("Error! There was no client associated with transaction {}! Ignoring.".into()); 
+        if let Some(path) = &options.reports.risk_report {
+            if let Err(e) = write_risk_report(path, &risk_monitor, &options.config.risk) {
+                exit_with_error(&format!("Error Writing Risk Report: {}", e), options.display.color);
             }
         }
 
-        else {
-            return Err("Error! There is no transaction {} to dispute! Ignoring.".into()); 
+        if let Some(path) = &options.reports.id_report {
+            if let Err(e) = write_id_report(path, &id_analysis, options.reports.ordered_ids) {
+                exit_with_error(&format!("Error Writing ID Report: {}", e), options.display.color);
+            }
         }
 
+        if let Some(path) = &options.reports.open_disputes {
+            if let Err(e) = write_open_disputes(path, &state.transactions) {
+                exit_with_error(&format!("Error Writing Open Disputes: {}", e), options.display.color);
+            }
+        }
 
-        Ok(())
-    }
-
-
-    // I am assuming that the client ID for a resolve must match the client's ID in the disputed transaction.
-    // Each time a client's dispute is resolved, they lose 1u16 "total_locks" increment.
-    // Their account is only unfrozen if they have 0 "total_locks" increments.
-    fn try_resolve(
-        transactions_map : &mut HashMap<u32, Transaction>,
-        client_data_map : &mut HashMap<u16, ClientData>,
-        transaction : Transaction,
-    ) -> Result<(), Box<dyn Error>> {
-
-
+        if state.still_scheduled > 0 {
+            eprintln!("{} scheduled transactions were still pending at the end of the input and were not applied.", state.still_scheduled);
+        }
 
-        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
+        let client_data = state.clients;
 
-        if let Some(te) = transaction_entry {
-            
-            if te.dispute_status != DisputeStatus::UnderDispute {
-                return Err("Error! Transaction {} is not disputed! Ignoring.".into()); 
-            }
-            else if te.client_id != transaction.client_id {
-                return Err("Error! Transaction {} is being disputed by an unrelated user! Ignoring.".into()); 
+        if let Some(path) = &options.reports.locked_report {
+            if let Err(e) = write_locked_report(path, &client_data) {
+                exit_with_error(&format!("Error Writing Locked Accounts Report: {}", e), options.display.color);
             }
+        }
 
-            let client_data = client_data_map.get_mut(&transaction.client_id);
-
-            if let Some(cd) = client_data {
-                let amount = te.amount.unwrap();
-                cd.available += amount;
-                cd.held -= amount;
-                cd.total_locks = cd.total_locks.checked_sub(1u16).unwrap_or(0u16);
-                te.dispute_status = DisputeStatus::Resolved;
+        if let Some(path) = &options.reports.negative_report {
+            if let Err(e) = write_negative_report(path, &client_data, &negative_balances) {
+                exit_with_error(&format!("Error Writing Negative Balances Report: {}", e), options.display.color);
             }
+        }
 
-            else {
-                return Err("Error! There was no client associated with transaction {}! Ignoring.".into()); 
+        if let Some(path) = &options.reports.wallets_report {
+            if let Err(e) = write_wallets_report(path, &client_data) {
+                exit_with_error(&format!("Error Writing Wallets Report: {}", e), options.display.color);
             }
         }
 
-        else {
-            return Err("Error! There is no transaction {} to dispute! Ignoring.".into()); 
+        if let Some(path) = &options.reports.flags_report {
+            if let Err(e) = write_review_report(path, &flags_report.rows) {
+                exit_with_error(&format!("Error Writing Flags Report: {}", e), options.display.color);
+            }
         }
 
-
-        Ok(())
-    }
-
-
-
-    // I am assuming that the client ID for a chargeback must match the client's ID in the disputed transaction.
-    // Each time a client's dispute is charged back, they can no longer lose that 1u16 "total_locks" increment.
-    // This means their account is permanently frozen (we could assume they would need to contact the service provider).
-    fn try_chargeback(
-        transactions_map : &mut HashMap<u32, Transaction>,
-        client_data_map : &mut HashMap<u16, ClientData>,
-        transaction : Transaction,
-    ) -> Result<(), Box<dyn Error>> {
-
-        let transaction_entry = transactions_map.get_mut(&transaction.tx_id);
-
-        if let Some(te) = transaction_entry {
-            
-            if te.dispute_status != DisputeStatus::UnderDispute{
-                return Err("Error! Transaction {} is not disputed! Ignoring.".into()); 
+        if let Some(path) = &options.reports.compliance_report {
+            if let Err(e) = write_review_report(path, &compliance_report.rows) {
+                exit_with_error(&format!("Error Writing Compliance Report: {}", e), options.display.color);
             }
-            else if te.client_id != transaction.client_id {
-                return Err("Error! Transaction {} is being disputed by an unrelated user! Ignoring.".into()); 
+        }
+
+        if let Some(path) = &options.reports.rejects {
+            if let Err(e) = write_rejects(path, &options.reports.passthrough_columns, &rejects_report.rows) {
+                exit_with_error(&format!("Error Writing Rejects: {}", e), options.display.color);
             }
+        }
 
-            let client_data = client_data_map.get_mut(&transaction.client_id);
+        if let Some(path) = &options.reports.quarantine {
+            if let Err(e) = write_quarantine(path, &options.input.file_path, &quarantine.records) {
+                exit_with_error(&format!("Error Writing Quarantine: {}", e), options.display.color);
+            }
+        }
 
-            if let Some(cd) = client_data {
-                let amount = te.amount.unwrap();
-                cd.held -= amount;
-                cd.total -= amount;
-                te.dispute_status = DisputeStatus::ChargedBack;
+        if let Some(path) = &options.reports.malformed {
+            if let Err(e) = write_quarantine(path, &options.input.file_path, &quarantine.malformed) {
+                exit_with_error(&format!("Error Writing Malformed Rows: {}", e), options.display.color);
             }
+        }
 
-            else {
-                return Err("Error! There was no client associated with transaction {}! Ignoring.".into()); 
+        if options.display.visualize {
+            let color = options.display.color.enabled_for(&io::stderr());
+
+            if let Err(e) = write_viz(&client_data, &summary, color, redactor.as_ref(), options.display.lang) {
+                exit_with_error(&format!("Error Writing Charts: {}", e), options.display.color);
             }
         }
 
-        else {
-            return Err("Error! There is no transaction {} to dispute! Ignoring.".into()); 
+        let summary_line = format_summary_line(&client_data, &summary, started.elapsed());
+
+        let written = match (&options.command.command, options.report.format) {
+            (Command::Rollup, _) => write_rollup(&rollup),
+            (Command::Top, _) => write_top(&client_data, &risk_monitor, options.command.top_by, options.command.top_count.unwrap_or(DEFAULT_TOP_COUNT)),
+            (_, ReportFormat::Csv) => write_csv(client_data, false, &options.report.filter, options.report.output.as_deref(), options.report.split_rows, options.data.clients_meta.as_ref()),
+            (_, ReportFormat::Extended) => write_csv(client_data, true, &options.report.filter, options.report.output.as_deref(), options.report.split_rows, options.data.clients_meta.as_ref()),
+            (_, ReportFormat::Html) => {
+                let kept = client_data.into_iter().filter(|(_, client)| options.report.filter.keeps(client)).collect();
+                write_html(kept, &state.operator, &summary, options.display.lang, options.data.clients_meta.as_ref())
+            },
+        };
+
+        if let Err(e) = written {
+            exit_with_error(&format!("Error Writing CSV: {}", e), options.display.color);
         }
 
-        Ok(())
-    }
+        eprintln!("{}", summary_line);
 
+    }
 
 
+    // One line of key=value pairs, so every cron log line carries the outcome of the run and can be grepped or parsed.
+    fn format_summary_line(
+        client_data : &HashMap<ClientId, ClientData>,
+        summary : &RunSummary,
+        elapsed : Duration,
+    ) -> String {
+
+        let locked_count = client_data.values().filter(|client| client.is_locked()).count();
+
+        let mut line = format!(
+            "processed={} accepted={} rejected={} clients={} locked={} elapsed={:.3}s",
+            summary.processed,
+            summary.accepted,
+            summary.rejected,
+            client_data.len(),
+            locked_count,
+            elapsed.as_secs_f64(),
+        );
+
+        // only shown when there are some, since they usually mean the input has a new transaction type
+        if summary.unknown_types > 0 {
+            line.push_str(&format!(" unknown_types={}", summary.unknown_types));
+        }
+        if summary.malformed > 0 {
+            line.push_str(&format!(" malformed={}", summary.malformed));
+        }
+        if summary.control > 0 {
+            line.push_str(&format!(" control={}", summary.control));
+        }
+        for (code, count) in &summary.reject_codes {
+            line.push_str(&format!(" {}_{}={}", code.id, code.name, count));
+        }
 
+        line
+    }
 
-//* Main *//
 
-    fn main() {
+    // Errors go to stderr, so they never end up mixed into a redirected report.
+    fn exit_with_error(message : &str, color : ColorChoice) -> ! {
+        eprintln!("{}", paint(message, RED, color.enabled_for(&io::stderr())));
+        process::exit(1);
+    }
 
-        let client_data = match read_csv() {
-            Ok(cd) => cd,
-            Err(e) => {
-                println!("{}", e);
-                process::exit(1);
-            }
-        };
 
-        if let Err(e) = write_csv(client_data) {
-            println!("Error Writing CSV: {}", e);
-            process::exit(1);
-        }
-        
-    }
\ No newline at end of file
+    // Exits before the run is committed, removing what it wrote while processing.
+    fn abort_run(staged : StagedOutputs, message : &str, color : ColorChoice) -> ! {
+        staged.discard();
+        exit_with_error(message, color);
+    }
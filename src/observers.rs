@@ -0,0 +1,794 @@
+// The observers notified as rows are applied or rejected, which most reports are built from.
+
+use std::{error::Error, io::{self, Write}, process, fs, collections::{BTreeMap, BTreeSet, HashMap}};
+use crate::{clock::*, lang::*, options::*, rejects::*, types::*};
+
+
+
+//* Observers *//
+
+    #[derive(Debug, Default)]
+    pub(crate) struct RunSummary {
+        pub(crate) processed: u64, // every row decoded, accepted or not
+        pub(crate) accepted: u64,
+        pub(crate) rejected: u64,
+        pub(crate) unknown_types: u64, // rejected because the engine doesn't know their type
+        pub(crate) malformed: u64, // skipped because they couldn't be decoded
+        pub(crate) control: u64, // savepoints taken and rolled back to, which aren't any client's transactions
+        pub(crate) lang: Lang,
+        pub(crate) reject_reasons: HashMap<String, u64>, // rejection message, in the "--lang" of the charts -> occurrences
+        pub(crate) reject_codes: BTreeMap<RejectCode, u64>, // rejection code -> occurrences, in code order for the summary line
+    }
+
+
+    // The parts of a transaction that observers are told about. The transaction itself is consumed by its handler.
+    #[derive(Debug)]
+    pub(crate) struct TransactionInfo {
+        pub(crate) tx_id: TxId,
+        pub(crate) tx_type: String,
+        pub(crate) client_id: ClientId,
+        pub(crate) amount: Option<f64>,
+        pub(crate) timestamp: Option<i64>,
+        pub(crate) origin: Option<RowOrigin>,
+        pub(crate) passthrough: Vec<String>, // the values of the "--passthrough-columns", in their order, empty when missing
+    }
+
+    impl From<&Transaction> for TransactionInfo {
+        fn from(transaction : &Transaction) -> Self {
+            TransactionInfo {
+                tx_id: transaction.tx_id,
+                tx_type: transaction.tx_type.clone(),
+                client_id: transaction.client_id,
+                amount: transaction.amount,
+                timestamp: transaction.timestamp,
+                origin: None,
+                passthrough: Vec::new(),
+            }
+        }
+    }
+
+
+    // Callbacks fired while the transactions are processed, allowing metrics, alerting and similar features
+    // to be plugged in without touching the core loop. Every callback defaults to doing nothing.
+    // "on_account_locked" and "on_chargeback" are fired after the "on_applied" of the transaction that caused them.
+    // "on_savepoint" and "on_rollback" are fired before the "on_applied" of their row, the latter once the engine state is restored,
+    // so observers keeping state derived from the applied transactions can roll it back too.
+    // "on_hold_expired" is fired for each hold released by its expiry, before the row whose timestamp reached it.
+    // "on_start" is fired once before the first row, with the accounts the run starts from, those of the prior report with "merge".
+    pub(crate) trait EngineObserver {
+        fn on_start(&mut self, _clients : &HashMap<ClientId, ClientData>) {}
+        fn on_applied(&mut self, _transaction : &TransactionInfo, _client : &ClientData) {}
+        fn on_rejected(&mut self, _transaction : &TransactionInfo, _reason : &(dyn Error + 'static)) {}
+        fn on_account_locked(&mut self, _client_id : ClientId, _client : &ClientData) {}
+        fn on_chargeback(&mut self, _transaction : &TransactionInfo, _client : &ClientData) {}
+        fn on_malformed(&mut self, _row : &MalformedRow) {}
+        fn on_savepoint(&mut self, _savepoint_id : TxId) {}
+        fn on_rollback(&mut self, _transaction : &TransactionInfo, _clients : &HashMap<ClientId, ClientData>) {}
+        fn on_adjusted(&mut self, _transaction : &TransactionInfo, _decision : &Rejection) {} // applied, but a policy changed what it does
+        fn on_hold_expired(&mut self, _hold : &TransactionInfo, _client : &ClientData) {}
+    }
+
+
+    impl EngineObserver for RunSummary {
+        fn on_applied(&mut self, _transaction : &TransactionInfo, _client : &ClientData) {
+            self.processed += 1;
+            self.accepted += 1;
+        }
+
+        fn on_rejected(&mut self, _transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            self.processed += 1;
+            self.rejected += 1;
+            if reason.is::<UnknownType>() {
+                self.unknown_types += 1;
+            }
+            *self.reject_reasons.entry(reject_message(self.lang, reason)).or_insert(0) += 1;
+            *self.reject_codes.entry(reject_code(reason)).or_insert(0) += 1;
+        }
+
+        fn on_malformed(&mut self, _row : &MalformedRow) {
+            self.malformed += 1;
+        }
+
+        fn on_savepoint(&mut self, _savepoint_id : TxId) {
+            self.processed += 1;
+            self.control += 1;
+        }
+
+        fn on_rollback(&mut self, _transaction : &TransactionInfo, _clients : &HashMap<ClientId, ClientData>) {
+            self.processed += 1;
+            self.control += 1;
+        }
+    }
+
+
+    // Enabled by "--verbose", logs every rejection and every account lock or chargeback to stderr.
+    // Rejections point at the file, line and byte offset of their row, followed by the raw record unless redacting.
+    pub(crate) struct VerboseLogger {
+        pub(crate) redactor: Option<Redactor>,
+        pub(crate) file_path: String,
+        pub(crate) lang: Lang,
+    }
+
+    impl VerboseLogger {
+        pub(crate) fn describe(&self, transaction : &TransactionInfo) -> String {
+            let client = redact_client(self.redactor.as_ref(), transaction.client_id);
+            match transaction.amount {
+                Some(amount) => format!(
+                    "tx {} ({}, {} {}, {} {})",
+                    transaction.tx_id,
+                    transaction.tx_type,
+                    self.lang.text("label-client"),
+                    client,
+                    self.lang.text("label-amount"),
+                    format_amount(redact_amount(self.redactor.as_ref(), amount)),
+                ),
+                None => format!("tx {} ({}, {} {})", transaction.tx_id, transaction.tx_type, self.lang.text("label-client"), client),
+            }
+        }
+    }
+
+    impl EngineObserver for VerboseLogger {
+        fn on_malformed(&mut self, row : &MalformedRow) {
+            match (&row.origin, self.redactor) {
+                (Some(origin), None) => eprintln!("{}: {} [{}]", origin.file, row, origin.record.iter().collect::<Vec<_>>().join(",")),
+                _ => eprintln!("{}: {}", self.file_path, row),
+            }
+        }
+
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            let reason = reject_message(self.lang, reason);
+            let Some(origin) = &transaction.origin else {
+                eprintln!("{}: {}", self.describe(transaction), reason);
+                return;
+            };
+
+            let location = format!("{}:{} (byte {})", origin.file, origin.line, origin.byte);
+            match self.redactor {
+                Some(_) => eprintln!("{} {}: {}", location, self.describe(transaction), reason),
+                None => eprintln!("{} {}: {} [{}]", location, self.describe(transaction), reason, origin.record.iter().collect::<Vec<_>>().join(",")),
+            }
+        }
+
+        fn on_account_locked(&mut self, client_id : ClientId, _client : &ClientData) {
+            eprintln!("{} {}: {}", self.lang.text("label-client"), redact_client(self.redactor.as_ref(), client_id), self.lang.text("log-account-locked"));
+        }
+
+        fn on_chargeback(&mut self, transaction : &TransactionInfo, _client : &ClientData) {
+            eprintln!("{}: {}", self.describe(transaction), self.lang.text("log-charged-back"));
+        }
+    }
+
+
+    // Enabled by "--outbox", publishes a normalized event per applied transaction as a line of JSON, describing the change
+    // to the client's balances and the balances after it, so downstream consumers can build their own projections.
+    // Rejected dispute, resolve, chargeback and chargeback reversal rows are published too, with their code, reason and no change,
+    // so the audit trail shows every attempt to dispute a transaction. Other rejections aren't.
+    // Writing can't fail the run halfway, so the first error is kept and reported once processing is done.
+    pub(crate) struct Outbox<'a> {
+        pub(crate) writer: io::BufWriter<fs::File>,
+        pub(crate) meta: Option<&'a ClientsMeta>, // adds the client's name, country and segment to every event
+        pub(crate) passthrough_columns: &'a [String], // adds a "passthrough" object with the values of those input columns to every event
+        pub(crate) balances: HashMap<ClientId, ([f64; 3], bool)>, // each client's (available, held, total) and lock after its latest event
+        pub(crate) error: Option<io::Error>,
+    }
+
+    impl<'a> Outbox<'a> {
+        pub(crate) fn create(path : &str, meta : Option<&'a ClientsMeta>, passthrough_columns : &'a [String]) -> Result<Self, Box<dyn Error>> {
+            let file = fs::File::create(path).map_err(|e| format!("Error! Could not create outbox '{}': {}", path, e))?;
+            Ok(Outbox { writer: io::BufWriter::new(file), meta, passthrough_columns, balances: HashMap::new(), error: None })
+        }
+
+        pub(crate) fn finish(mut self) -> Result<(), Box<dyn Error>> {
+            match self.error.take() {
+                Some(e) => Err(e.into()),
+                None => Ok(self.writer.flush()?),
+            }
+        }
+
+        pub(crate) fn publish(&mut self, transaction : &TransactionInfo, reason : Option<(RejectCode, String)>, balances : [f64; 3], previous : [f64; 3], locked : bool) {
+            if self.error.is_some() {
+                return;
+            }
+
+            let optional = |value : Option<String>| value.unwrap_or_else(|| "null".to_string());
+
+            // unknown clients get nulls, so every event has the same fields
+            let meta_fields = self.meta.map(|meta| {
+                let client_meta = meta.get(&transaction.client_id);
+                format!(
+                    ",\"name\":{},\"country\":{},\"segment\":{}",
+                    optional(client_meta.map(|m| json_string(&m.name))),
+                    optional(client_meta.map(|m| json_string(&m.country))),
+                    optional(client_meta.map(|m| json_string(&m.segment))),
+                )
+            });
+
+            // under their own object, since the partner's column names could clash with the fields of the event
+            let passthrough_fields = (!self.passthrough_columns.is_empty()).then(|| {
+                let fields: Vec<String> = self.passthrough_columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, column)| format!("{}:{}", json_string(column), optional(transaction.passthrough.get(i).map(|value| json_string(value)))))
+                    .collect();
+                format!(",\"passthrough\":{{{}}}", fields.join(","))
+            });
+
+            let written = writeln!(
+                self.writer,
+                "{{\"tx\":{},\"type\":{},\"client\":{},\"amount\":{},\"timestamp\":{},\"outcome\":{},\"code\":{},\"code_name\":{},\"reason\":{},\
+                \"available_change\":{},\"held_change\":{},\"total_change\":{},\
+                \"available\":{},\"held\":{},\"total\":{},\"locked\":{}{}{}}}",
+                transaction.tx_id,
+                json_string(&transaction.tx_type),
+                transaction.client_id,
+                json_number(transaction.amount),
+                optional(transaction.timestamp.map(|t| t.to_string())),
+                json_string(if reason.is_some() { "rejected" } else { "applied" }),
+                optional(reason.as_ref().map(|(code, _)| json_string(code.id))),
+                optional(reason.as_ref().map(|(code, _)| json_string(code.name))),
+                optional(reason.as_ref().map(|(_, reason)| json_string(reason))),
+                json_number(Some(balances[0] - previous[0])),
+                json_number(Some(balances[1] - previous[1])),
+                json_number(Some(balances[2] - previous[2])),
+                json_number(Some(balances[0])),
+                json_number(Some(balances[1])),
+                json_number(Some(balances[2])),
+                locked,
+                meta_fields.unwrap_or_default(),
+                passthrough_fields.unwrap_or_default(),
+            );
+
+            if let Err(e) = written {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    impl EngineObserver for Outbox<'_> {
+        // The changes of the first event of an account carried over by "merge" are from its prior balances, not from zero.
+        fn on_start(&mut self, clients : &HashMap<ClientId, ClientData>) {
+            for (client_id, client) in clients {
+                self.balances.insert(*client_id, ([client.available, client.held, client.total], client.is_locked()));
+            }
+        }
+
+        fn on_applied(&mut self, transaction : &TransactionInfo, client : &ClientData) {
+            let balances = [client.available, client.held, client.total];
+            let (previous, _) = self.balances.insert(transaction.client_id, (balances, client.is_locked())).unwrap_or_default();
+
+            self.publish(transaction, None, balances, previous, client.is_locked());
+        }
+
+        // Not a row of the input, but it moves the funds back to available, so consumers' balances would drift without it.
+        fn on_hold_expired(&mut self, hold : &TransactionInfo, client : &ClientData) {
+            self.on_applied(hold, client);
+        }
+
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            if !matches!(transaction.tx_type.as_str(), "dispute" | "resolve" | "chargeback" | "chargeback_reversal") {
+                return;
+            }
+
+            let (balances, locked) = self.balances.get(&transaction.client_id).copied().unwrap_or_default();
+            self.publish(transaction, Some((reject_code(reason), reason.to_string())), balances, balances, locked);
+        }
+
+        // Every account the rollback changed gets an event under the "rollback_to" row, so the changes still add up for consumers.
+        fn on_rollback(&mut self, transaction : &TransactionInfo, clients : &HashMap<ClientId, ClientData>) {
+            let mut client_ids: Vec<ClientId> = self.balances.keys().chain(clients.keys()).copied().collect();
+            client_ids.sort();
+            client_ids.dedup();
+
+            for client_id in client_ids {
+                let restored = clients.get(&client_id).map_or(([0.0; 3], false), |client| ([client.available, client.held, client.total], client.is_locked()));
+                let cached = self.balances.get(&client_id).copied().unwrap_or_default();
+                if restored == cached {
+                    continue;
+                }
+
+                match clients.contains_key(&client_id) {
+                    true => self.balances.insert(client_id, restored),
+                    false => self.balances.remove(&client_id),
+                };
+
+                let event = TransactionInfo {
+                    tx_id: transaction.tx_id,
+                    tx_type: transaction.tx_type.clone(),
+                    client_id,
+                    amount: None,
+                    timestamp: transaction.timestamp,
+                    origin: None,
+                    passthrough: transaction.passthrough.clone(),
+                };
+                self.publish(&event, None, restored.0, cached.0, restored.1);
+            }
+        }
+    }
+
+
+    // Quotes a string for JSON, escaping what needs to be.
+    // JSON has no NaN or infinity, so those are written as null, like a missing amount.
+    pub(crate) fn json_number(value : Option<f64>) -> String {
+        match value {
+            Some(value) if value.is_finite() => format_amount(value),
+            _ => "null".to_string(),
+        }
+    }
+
+
+    pub(crate) fn json_string(value : &str) -> String {
+        let mut quoted = String::with_capacity(value.len() + 2);
+        quoted.push('"');
+        for character in value.chars() {
+            match character {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                character if character.is_control() => quoted.push_str(&format!("\\u{:04x}", character as u32)),
+                character => quoted.push(character),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+
+    // Enabled by "--redact", keeps account data out of stderr (logs, charts) so it can be shared with vendors. The report is untouched.
+    // Client IDs are replaced by a hash salted per run, so they can be followed within a run but not across runs,
+    // which matters since there are few enough IDs to hash them all. Amounts are truncated to whole units.
+    #[derive(Clone, Copy)]
+    pub(crate) struct Redactor {
+        pub(crate) salt: u64,
+    }
+
+    impl Redactor {
+        pub(crate) fn new() -> Self {
+            let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+            Redactor { salt: u64::from(nanos) << 32 | u64::from(process::id()) }
+        }
+
+        // FNV-1a over the ID and the salt, the salt last so it mixes in the ID
+        pub(crate) fn client(&self, client_id : ClientId) -> String {
+            let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+            for byte in client_id.to_le_bytes().into_iter().chain(self.salt.to_le_bytes()) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+            format!("#{:016x}", hash)
+        }
+    }
+
+    pub(crate) fn redact_client(redactor : Option<&Redactor>, client_id : ClientId) -> String {
+        redactor.map_or_else(|| client_id.to_string(), |redactor| redactor.client(client_id))
+    }
+
+    pub(crate) fn redact_amount(redactor : Option<&Redactor>, amount : f64) -> f64 {
+        if redactor.is_some() {amount.trunc()} else {amount}
+    }
+
+
+    // Enabled by "--flags-report", collects the transactions that a rule held back for review.
+    #[derive(Default)]
+    pub(crate) struct FlagsReport {
+        pub(crate) rows: Vec<[String; 6]>,
+    }
+
+    impl EngineObserver for FlagsReport {
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            let Some(flag) = reason.downcast_ref::<FlaggedForReview>() else { return };
+
+            self.rows.push([
+                transaction.tx_type.clone(),
+                transaction.client_id.to_string(),
+                transaction.tx_id.to_string(),
+                transaction.amount.map(format_amount).unwrap_or_default(),
+                transaction.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+                flag.reason.clone(),
+            ]);
+        }
+    }
+
+    // Enabled by "--compliance-report", collects every transaction rejected because its client is on the blocklist.
+    #[derive(Default)]
+    pub(crate) struct ComplianceReport {
+        pub(crate) rows: Vec<[String; 6]>,
+    }
+
+    impl EngineObserver for ComplianceReport {
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            let Some(blocked) = reason.downcast_ref::<BlockedClient>() else { return };
+
+            self.rows.push([
+                transaction.tx_type.clone(),
+                transaction.client_id.to_string(),
+                transaction.tx_id.to_string(),
+                transaction.amount.map(format_amount).unwrap_or_default(),
+                transaction.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+                blocked.reason.clone(),
+            ]);
+        }
+    }
+
+
+    // Enabled by "--rejects", collects every rejected transaction with the code and message of its rejection,
+    // and where it was read from, like the "--verbose" logs, followed by the values of the "--passthrough-columns".
+    #[derive(Default)]
+    pub(crate) struct RejectsReport {
+        pub(crate) lang: Lang, // of the "reason" column
+        pub(crate) file_path: String,
+        pub(crate) rows: Vec<Vec<String>>,
+    }
+
+    impl EngineObserver for RejectsReport {
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            let code = reject_code(reason);
+
+            let mut row = vec![
+                transaction.tx_type.clone(),
+                transaction.client_id.to_string(),
+                transaction.tx_id.to_string(),
+                transaction.amount.map(format_amount).unwrap_or_default(),
+                transaction.timestamp.map(|t| t.to_string()).unwrap_or_default(),
+                code.id.to_string(),
+                code.name.to_string(),
+                reject_message(self.lang, reason),
+                transaction.origin.as_ref().map_or_else(|| self.file_path.clone(), |origin| origin.file.to_string()),
+                transaction.origin.as_ref().map(|origin| origin.line.to_string()).unwrap_or_default(),
+                transaction.origin.as_ref().map(|origin| origin.byte.to_string()).unwrap_or_default(),
+            ];
+            row.extend(transaction.passthrough.iter().cloned());
+            self.rows.push(row);
+        }
+
+        // listed like a rejection, under the code of the decision
+        fn on_adjusted(&mut self, transaction : &TransactionInfo, decision : &Rejection) {
+            self.on_rejected(transaction, decision);
+        }
+    }
+
+
+    // Enabled by "--quarantine" and "--malformed", keeps the input records of the rows rejected for an unknown type
+    // and of the rows that couldn't be decoded, so they aren't lost.
+    #[derive(Default)]
+    pub(crate) struct Quarantine {
+        pub(crate) records: Vec<csv::StringRecord>,
+        pub(crate) malformed: Vec<csv::StringRecord>,
+    }
+
+    impl EngineObserver for Quarantine {
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            if let (true, Some(origin)) = (reason.is::<UnknownType>(), &transaction.origin) {
+                self.records.push(origin.record.clone());
+            }
+        }
+
+        fn on_malformed(&mut self, row : &MalformedRow) {
+            if let Some(origin) = &row.origin {
+                self.malformed.push(origin.record.clone());
+            }
+        }
+    }
+
+
+    // Enabled by the "rollup" subcommand: the raw material for customer statements, per client and period.
+    // Rows without a timestamp count towards the period of the latest timestamped row, and aren't rolled up before the first one.
+    pub(crate) struct Rollup {
+        pub(crate) period: Period,
+        pub(crate) rows: RollupRows,
+        pub(crate) last_total: HashMap<ClientId, f64>, // each client's total after its latest applied row, the opening balance of its next period
+        pub(crate) last_timestamp: Option<i64>,
+        pub(crate) savepoints: Savepoints<(RollupRows, HashMap<ClientId, f64>, Option<i64>)>,
+    }
+
+    pub(crate) type RollupRows = BTreeMap<(ClientId, String), RollupRow>; // (client, period) -> totals, in the order they're written
+
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct RollupRow {
+        pub(crate) opening_balance: f64,
+        pub(crate) deposits: f64,
+        pub(crate) withdrawals: f64,
+        pub(crate) disputes_opened: u64,
+        pub(crate) disputes_closed: u64,
+        pub(crate) closing_balance: f64,
+    }
+
+    impl Rollup {
+        pub(crate) fn new(period : Period) -> Self {
+            Rollup { period, rows: BTreeMap::new(), last_total: HashMap::new(), last_timestamp: None, savepoints: Savepoints::default() }
+        }
+    }
+
+    impl EngineObserver for Rollup {
+        fn on_applied(&mut self, transaction : &TransactionInfo, client : &ClientData) {
+            self.last_timestamp = transaction.timestamp.or(self.last_timestamp);
+            let opening_balance = self.last_total.insert(transaction.client_id, client.total).unwrap_or(0.0);
+
+            let Some(timestamp) = self.last_timestamp else { return };
+
+            let date = format_date(timestamp.div_euclid(86_400));
+            let period = match self.period {
+                Period::Daily => date,
+                Period::Monthly => date[..7].to_string(),
+            };
+
+            let row = self.rows.entry((transaction.client_id, period)).or_insert_with(|| RollupRow { opening_balance, ..RollupRow::default() });
+            let amount = transaction.amount.unwrap_or(0.0);
+
+            match transaction.tx_type.as_str() {
+                "deposit" => row.deposits += amount,
+                "withdrawal" => row.withdrawals += amount,
+                "dispute" => row.disputes_opened += 1,
+                "resolve" | "chargeback" => row.disputes_closed += 1,
+                _ => {},
+            }
+            row.closing_balance = client.total;
+        }
+
+        fn on_savepoint(&mut self, savepoint_id : TxId) {
+            self.savepoints.take(savepoint_id, (self.rows.clone(), self.last_total.clone(), self.last_timestamp));
+        }
+
+        fn on_rollback(&mut self, transaction : &TransactionInfo, _clients : &HashMap<ClientId, ClientData>) {
+            if let Some((rows, last_total, last_timestamp)) = self.savepoints.restore(transaction.tx_id) {
+                (self.rows, self.last_total, self.last_timestamp) = (rows, last_total, last_timestamp);
+            }
+        }
+    }
+
+
+    // Enabled by "--risk-report", counts the deposits and chargebacks of each client, like the card networks' monitoring programs.
+    #[derive(Default)]
+    pub(crate) struct RiskMonitor {
+        pub(crate) counts: HashMap<ClientId, (u64, u64)>, // client -> (deposits, chargebacks)
+        pub(crate) savepoints: Savepoints<HashMap<ClientId, (u64, u64)>>,
+    }
+
+    impl EngineObserver for RiskMonitor {
+        fn on_applied(&mut self, transaction : &TransactionInfo, _client : &ClientData) {
+            if transaction.tx_type == "deposit" {
+                self.counts.entry(transaction.client_id).or_default().0 += 1;
+            }
+        }
+
+        fn on_chargeback(&mut self, transaction : &TransactionInfo, _client : &ClientData) {
+            self.counts.entry(transaction.client_id).or_default().1 += 1;
+        }
+
+        fn on_savepoint(&mut self, savepoint_id : TxId) {
+            self.savepoints.take(savepoint_id, self.counts.clone());
+        }
+
+        fn on_rollback(&mut self, transaction : &TransactionInfo, _clients : &HashMap<ClientId, ClientData>) {
+            if let Some(counts) = self.savepoints.restore(transaction.tx_id) {
+                self.counts = counts;
+            }
+        }
+    }
+
+
+    // Enabled by "--negative-report", remembers which transactions drove each account's available balance below zero:
+    // the one crossing it, and any taking it further down. The list starts over once the balance recovers.
+    #[derive(Default)]
+    pub(crate) struct NegativeBalances {
+        pub(crate) available: HashMap<ClientId, f64>, // each client's available balance after its latest applied row
+        pub(crate) drivers: NegativeDrivers,
+        pub(crate) savepoints: Savepoints<(HashMap<ClientId, f64>, NegativeDrivers)>,
+    }
+
+    pub(crate) type NegativeDrivers = HashMap<ClientId, Vec<String>>; // client -> "type:tx" of the transactions, in order
+
+    impl EngineObserver for NegativeBalances {
+        fn on_applied(&mut self, transaction : &TransactionInfo, client : &ClientData) {
+            let previous = self.available.insert(transaction.client_id, client.available).unwrap_or(0.0);
+
+            if client.available >= 0.0 {
+                self.drivers.remove(&transaction.client_id);
+            }
+            else if client.available < previous {
+                self.drivers.entry(transaction.client_id).or_default().push(format!("{}:{}", transaction.tx_type, transaction.tx_id));
+            }
+        }
+
+        fn on_savepoint(&mut self, savepoint_id : TxId) {
+            self.savepoints.take(savepoint_id, (self.available.clone(), self.drivers.clone()));
+        }
+
+        fn on_rollback(&mut self, transaction : &TransactionInfo, _clients : &HashMap<ClientId, ClientData>) {
+            if let Some((available, drivers)) = self.savepoints.restore(transaction.tx_id) {
+                (self.available, self.drivers) = (available, drivers);
+            }
+        }
+    }
+
+
+    // Enabled by "--id-report" and "--history", collects the IDs of every deposit, withdrawal and hold read, whether applied or not,
+    // since a gap between them usually means rows were lost upstream.
+    #[derive(Default)]
+    pub(crate) struct IdAnalysis {
+        pub(crate) ids: BTreeSet<TxId>,
+        pub(crate) out_of_order: Vec<(TxId, TxId)>, // (tx ID, highest ID before it)
+        pub(crate) highest: Option<TxId>,
+    }
+
+    impl IdAnalysis {
+        pub(crate) fn record(&mut self, transaction : &TransactionInfo) {
+            if !matches!(transaction.tx_type.as_str(), "deposit" | "withdrawal" | "hold") {
+                return;
+            }
+
+            self.ids.insert(transaction.tx_id);
+
+            match self.highest {
+                Some(highest) if transaction.tx_id < highest => self.out_of_order.push((transaction.tx_id, highest)),
+                _ => self.highest = Some(transaction.tx_id),
+            }
+        }
+    }
+
+    impl EngineObserver for IdAnalysis {
+        fn on_applied(&mut self, transaction : &TransactionInfo, _client : &ClientData) {
+            self.record(transaction);
+        }
+
+        fn on_rejected(&mut self, transaction : &TransactionInfo, _reason : &(dyn Error + 'static)) {
+            self.record(transaction);
+        }
+    }
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{engine::*, outputs::*, sources::*, testing::*};
+
+        fn rollup(period : Period, csv_text : &str) -> Vec<(ClientId, String, [f64; 4], [u64; 2])> {
+            let mut rollup = Rollup::new(period);
+            process_transactions(&Options::default(), &mut StagedOutputs::default(), &mut Rows(rows(csv_text).into()), HashMap::new(), &mut [], &mut [&mut rollup]).unwrap();
+
+            rollup.rows.into_iter()
+                .map(|((client_id, period), row)| (client_id, period, [row.opening_balance, row.deposits, row.withdrawals, row.closing_balance], [row.disputes_opened, row.disputes_closed]))
+                .collect()
+        }
+
+        #[test]
+        fn rollup_carries_the_closing_balance_into_the_next_period() {
+            let rows = rollup(Period::Monthly, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,2024-01-05
+                withdrawal,1,2,30,2024-01-20
+                withdrawal,1,3,500,2024-01-21
+                dispute,1,1,,2024-01-25
+                resolve,1,1,,2024-02-02
+                deposit,1,4,10,2024-03-31T23:59:59Z
+                deposit,2,5,5,2024-02-10
+            ");
+
+            assert_eq!(rows, [
+                (1, "2024-01".to_string(), [0.0, 100.0, 30.0, 70.0], [1, 0]),
+                (1, "2024-02".to_string(), [70.0, 0.0, 0.0, 70.0], [0, 1]),
+                (1, "2024-03".to_string(), [70.0, 10.0, 0.0, 80.0], [0, 0]),
+                (2, "2024-02".to_string(), [0.0, 5.0, 0.0, 5.0], [0, 0]),
+            ], "rejected rows aren't rolled up");
+        }
+
+        #[test]
+        fn undated_rows_count_towards_the_latest_period() {
+            let rows = rollup(Period::Daily, "\
+                type,client,tx,amount,timestamp
+                deposit,1,1,50,
+                deposit,1,2,100,2024-01-05T10:00:00Z
+                withdrawal,1,3,20,
+                deposit,1,4,1,2024-01-06T00:00:00Z
+            ");
+
+            assert_eq!(rows, [
+                (1, "2024-01-05".to_string(), [50.0, 100.0, 20.0, 130.0], [0, 0]),
+                (1, "2024-01-06".to_string(), [130.0, 1.0, 0.0, 131.0], [0, 0]),
+            ], "an undated row before the first timestamp is only part of the opening balance");
+        }
+
+        #[test]
+        fn outbox_publishes_the_applied_rows_and_the_rejected_dispute_steps() {
+            let path = temp_file("outbox.ndjson", "");
+            let mut outbox = Outbox::create(&path, None, &[]).unwrap();
+            let input = rows("\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,10
+                withdrawal,1,2,500,20
+                dispute,1,1,,30
+                resolve,1,9,,40
+            ");
+            process_transactions(&Options::default(), &mut StagedOutputs::default(), &mut Rows(input.into()), HashMap::new(), &mut [], &mut [&mut outbox]).unwrap();
+            outbox.finish().unwrap();
+            let events = fs::read_to_string(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            let events: Vec<&str> = events.lines().collect();
+            assert_eq!(events, [
+                r#"{"tx":1,"type":"deposit","client":1,"amount":100,"timestamp":10,"outcome":"applied","code":null,"code_name":null,"reason":null,"available_change":100,"held_change":0,"total_change":100,"available":100,"held":0,"total":100,"locked":false}"#,
+                r#"{"tx":1,"type":"dispute","client":1,"amount":null,"timestamp":30,"outcome":"applied","code":null,"code_name":null,"reason":null,"available_change":-100,"held_change":100,"total_change":0,"available":0,"held":100,"total":100,"locked":true}"#,
+                r#"{"tx":9,"type":"resolve","client":1,"amount":null,"timestamp":40,"outcome":"rejected","code":"E4001","code_name":"TX_NOT_FOUND","reason":"Error! There is no transaction to resolve! Ignoring.","available_change":0,"held_change":0,"total_change":0,"available":0,"held":100,"total":100,"locked":true}"#,
+            ], "the rejected withdrawal isn't published");
+        }
+
+        #[test]
+        fn redacted_clients_keep_the_whole_hash() {
+            let redactor = Redactor { salt: 7 };
+            let tag = redactor.client(1);
+
+            assert_eq!(tag.len(), 17, "{}", tag);
+            assert_eq!(tag, redactor.client(1));
+            assert_ne!(tag, redactor.client(2));
+            assert_ne!(tag, Redactor { salt: 8 }.client(1));
+        }
+
+        #[test]
+        fn outbox_strings_are_valid_json() {
+            assert_eq!(json_string("Zé \"Tó\"\\\n"), r#""Zé \"Tó\"\\\u000a""#);
+        }
+
+        #[test]
+        fn outbox_numbers_are_rounded_and_non_finite_ones_are_null() {
+            assert_eq!((json_number(Some(0.12345)), json_number(Some(f64::NAN)), json_number(None)), ("0.1235".to_string(), "null".to_string(), "null".to_string()));
+        }
+
+        #[test]
+        fn malformed_rows_are_counted_and_kept_apart_from_the_rejections() {
+            let path = temp_file("quarantine.csv", "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,-2,10\npayout,1,3,5\nwithdrawal,1,4,50\ndeposit,1,5\n");
+            let (mut summary, mut quarantine) = (RunSummary::default(), Quarantine::default());
+            process_transactions(&Options::default(), &mut StagedOutputs::default(), &mut CsvSource::open(&path).unwrap(), HashMap::new(), &mut [], &mut [&mut summary, &mut quarantine]).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!((summary.processed, summary.accepted, summary.rejected, summary.malformed), (3, 1, 2, 2));
+            assert_eq!(quarantine.malformed, [vec!["deposit", "1", "-2", "10"], vec!["deposit", "1", "5"]]);
+            assert_eq!(quarantine.records, [vec!["payout", "1", "3", "5"]], "only the unknown types are quarantined with the rejections");
+        }
+
+        #[test]
+        fn passthrough_columns_follow_their_rows_into_the_rejects_and_the_outbox() {
+            let columns = ["ref".to_string(), "notes".to_string()];
+            let options = Options { reports: SideReports { passthrough_columns: columns.to_vec(), ..SideReports::default() }, ..Options::default() };
+            let path = temp_file("passthrough.ndjson", "");
+            let (mut outbox, mut rejects) = (Outbox::create(&path, None, &columns).unwrap(), RejectsReport::default());
+            let input = rows("\
+                type,client,tx,amount,ref,channel
+                deposit,1,1,100,A-1,web
+                withdrawal,1,2,500,A-2,web
+                dispute,1,1,,,
+            ");
+            process_transactions(&options, &mut StagedOutputs::default(), &mut Rows(input.into()), HashMap::new(), &mut [], &mut [&mut outbox, &mut rejects]).unwrap();
+            outbox.finish().unwrap();
+            let events = fs::read_to_string(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(rejects.rows.len(), 1);
+            assert_eq!(rejects.rows[0][2..].iter().map(String::as_str).collect::<Vec<_>>(), ["2", "500", "", "E2003", "INSUFFICIENT_FUNDS", "Error! Attempting to withdraw with insufficient balance. Ignoring.", "", "", "", "A-2", ""]);
+
+            let passthrough: Vec<&str> = events.lines().map(|event| &event[event.find(",\"passthrough\"").unwrap()..]).collect();
+            assert_eq!(passthrough, [r#","passthrough":{"ref":"A-1","notes":""}}"#, r#","passthrough":{"ref":"","notes":""}}"#], "a column missing from the input is empty");
+        }
+
+        #[test]
+        fn outbox_publishes_the_funds_released_by_an_expired_hold() {
+            let mut options = Options::default();
+            options.config.holds.expiry_seconds = Some(60);
+            let path = temp_file("outbox-holds.ndjson", "");
+            let (mut outbox, mut summary) = (Outbox::create(&path, None, &[]).unwrap(), RunSummary::default());
+            let input = rows("\
+                type,client,tx,amount,timestamp
+                deposit,1,1,100,1000
+                hold,1,2,30,1000
+                deposit,1,3,5,1100
+            ");
+            process_transactions(&options, &mut StagedOutputs::default(), &mut Rows(input.into()), HashMap::new(), &mut [], &mut [&mut outbox, &mut summary]).unwrap();
+            outbox.finish().unwrap();
+            let events = fs::read_to_string(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            let events: Vec<&str> = events.lines().collect();
+            assert_eq!(events[2], r#"{"tx":2,"type":"hold_expiry","client":1,"amount":30,"timestamp":1100,"outcome":"applied","code":null,"code_name":null,"reason":null,"available_change":30,"held_change":-30,"total_change":0,"available":100,"held":0,"total":100,"locked":false}"#);
+            assert!(events[3].contains(r#""type":"deposit","client":1,"amount":5,"timestamp":1100"#) && events[3].contains(r#""available_change":5,"#));
+            assert_eq!(summary.processed, 3, "the expiry isn't a row");
+        }
+    }
@@ -0,0 +1,686 @@
+// The command line: what it asks for, and the reference files it names.
+
+use std::{error::Error, io::IsTerminal, env, collections::{BTreeMap, HashMap}};
+use csv::Trim;
+use crate::{clock::*, config::*, lang::*, outputs::*, types::*};
+
+
+
+//* Structs *//
+
+    // What the command line asked for, grouped by the part of the run each setting is for.
+    #[derive(Debug, Default)]
+    pub(crate) struct Options {
+        pub(crate) command: CommandOptions,
+        pub(crate) input: InputOptions,
+        pub(crate) report: ReportOptions,
+        pub(crate) display: DisplayOptions,
+        pub(crate) data: ReferenceData,
+        pub(crate) reports: SideReports,
+        pub(crate) live: LiveOutputs,
+        pub(crate) config: EngineConfig,
+    }
+
+    // The command to run, and the settings only some of them take.
+    #[derive(Debug, Default)]
+    pub(crate) struct CommandOptions {
+        pub(crate) command: Command,
+        pub(crate) period: Period, // of the rollup
+        pub(crate) top_by: TopMetric,
+        pub(crate) top_count: Option<usize>, // how many clients "top" lists, "--n"
+        pub(crate) top_report: Option<String>, // the report "top" ranks instead of processing a file, "--report"
+        pub(crate) diff_reports: Option<(String, String)>, // the earlier and later reports "diff" compares
+        pub(crate) verify_report: Option<String>, // the report "rebuild" checks the rebuilt balances against, "--verify"
+        pub(crate) undo_count: usize, // how many of the last applied events "rebuild" leaves out, "--undo"
+        pub(crate) baseline: Option<EngineConfig>, // the policy "whatif" compares the "--config" one against, the defaults when missing
+    }
+
+    // Where the transactions are read from, which of them are applied, and how long they're kept.
+    #[derive(Debug, Default)]
+    pub(crate) struct InputOptions {
+        pub(crate) file_path: String,
+        pub(crate) prior_report: Option<String>, // the report "merge" starts from
+        pub(crate) admin_input: Option<String>, // the operator's file of admin rows, merged into the input by timestamp
+        pub(crate) as_of: Option<i64>, // rows past this date are rejected, and scheduled transactions still pending are applied up to it
+        pub(crate) retain_transactions: Option<Retention>, // None keeps every transaction for the whole run
+        pub(crate) batch_size: BatchSize,
+        pub(crate) read_ahead: Option<usize>, // in batches
+        pub(crate) strict: bool, // reject rows carrying columns the engine doesn't know
+    }
+
+    // The report written at the end of the run.
+    #[derive(Debug, Default)]
+    pub(crate) struct ReportOptions {
+        pub(crate) format: ReportFormat,
+        pub(crate) filter: ReportFilter, // which accounts make it into the report
+        pub(crate) output: Option<String>, // where to write the report instead of stdout
+        pub(crate) split_rows: Option<usize>, // accounts per file, when splitting the "--output" into numbered files
+        pub(crate) atomic: bool, // only write the report if the run passes the invariants and the "[acceptance]" thresholds
+    }
+
+    // What's written for people to read, on stderr and in the HTML report.
+    #[derive(Debug, Default)]
+    pub(crate) struct DisplayOptions {
+        pub(crate) visualize: bool, // print the ASCII charts to stderr after the run
+        pub(crate) color: ColorChoice,
+        pub(crate) lang: Lang, // of the human-readable outputs
+        pub(crate) verbose: bool, // log rejections, locks and chargebacks to stderr
+        pub(crate) redact: bool, // hash client IDs and truncate amounts in everything written to stderr
+    }
+
+    // The files about the clients themselves, loaded before the run.
+    #[derive(Debug, Default)]
+    pub(crate) struct ReferenceData {
+        pub(crate) blocklist: HashMap<ClientId, String>, // blocked client -> reason
+        pub(crate) kyc: Option<HashMap<ClientId, KycStatus>>, // KYC status per client, when a "--kyc" file is given
+        pub(crate) clients_meta: Option<ClientsMeta>, // name, country and segment per client, when a "--clients-meta" file is given
+        pub(crate) control: Option<BTreeMap<String, ControlTotal>>, // expected totals per transaction type, when a "--control" file is given
+        pub(crate) client_limits: HashMap<ClientId, ClientLimits>,
+    }
+
+    // The other files written once the run is done, each one only when asked for.
+    #[derive(Debug, Default)]
+    pub(crate) struct SideReports {
+        pub(crate) flags_report: Option<String>, // where to list the transactions held back for review
+        pub(crate) compliance_report: Option<String>, // where to list the transactions of blocked clients
+        pub(crate) rejects: Option<String>, // where to list every rejected transaction, with its code
+        pub(crate) quarantine: Option<String>, // where to keep the rows of unknown types
+        pub(crate) malformed: Option<String>, // where to keep the rows that couldn't be decoded
+        pub(crate) operator_report: Option<String>, // where to write the balances of the operator accounts
+        pub(crate) wallets_report: Option<String>, // where to write the per-wallet balances
+        pub(crate) held_report: Option<String>, // where to write the aging of the held funds
+        pub(crate) open_disputes: Option<String>, // where to write the transactions still under dispute
+        pub(crate) risk_report: Option<String>, // where to write the chargeback ratio of each client
+        pub(crate) locked_report: Option<String>, // where to write the locked accounts
+        pub(crate) negative_report: Option<String>, // where to write the accounts with a negative available balance
+        pub(crate) id_report: Option<String>, // where to write the tx ID gaps
+        pub(crate) history: Option<String>, // the tx ID ranges of the earlier runs, checked for overlaps and appended to
+        pub(crate) outbox: Option<String>, // where to publish an event per applied transaction, as NDJSON
+        pub(crate) passthrough_columns: Vec<String>, // input columns copied into the rejects file and the outbox events, "--passthrough-columns"
+        pub(crate) ordered_ids: bool, // the upstream guarantees increasing tx IDs, so the ID report lists those out of order too
+    }
+
+    // The files written while the transactions are processed.
+    #[derive(Debug, Default)]
+    pub(crate) struct LiveOutputs {
+        pub(crate) eod_report: Option<String>, // where to write the closing balances of every day
+        pub(crate) eod_dir: Option<String>, // directory for a closing balances file per day
+        pub(crate) report: Option<String>, // where to write the report while processing, "--live-report"
+        pub(crate) every: LiveInterval, // "--live-every"
+        pub(crate) delta: bool, // only write the accounts that changed, to numbered files, "--live-delta"
+    }
+
+
+    // Per-client overrides of the "[limits]" section, loaded from the "--limits" file.
+    #[derive(Debug, Default)]
+    pub(crate) struct ClientLimits {
+        pub(crate) reserve: Option<f64>,
+        pub(crate) tier: Tier,
+    }
+
+
+    // KYC status per client, from the "--kyc" file. Clients missing from the file are unverified.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub(crate) enum KycStatus {
+        Verified,
+        Unverified,
+    }
+
+
+    // Who a client is, from the "--clients-meta" file, joined into the extended and HTML reports and the outbox.
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub(crate) struct ClientMeta {
+        pub(crate) name: String,
+        pub(crate) country: String,
+        pub(crate) segment: String,
+    }
+
+    pub(crate) type ClientsMeta = HashMap<ClientId, ClientMeta>;
+
+
+    // The row count and sum of amounts expected for a transaction type by a "--control" file.
+    #[derive(Debug, Clone, Copy)]
+    pub(crate) struct ControlTotal {
+        pub(crate) count: u64,
+        pub(crate) amount: Option<f64>, // not checked when the control file leaves it empty
+    }
+
+
+    // What the run outputs, selected by an optional subcommand before the file path.
+    #[derive(Debug, Default, PartialEq)]
+    pub(crate) enum Command {
+        #[default]
+        Report, // the account balances, in the "--format" of choice
+        Rollup, // "rollup": per-client statement totals for each "--period"
+        Top, // "top": the clients ranking highest "--by" a metric
+        Merge, // "merge": the report, starting from the balances of a prior report
+        Whatif, // "whatif": the aggregate impact of the "--config" policy against the "--baseline" one
+        Diff, // "diff": the fields of the accounts that differ between two reports
+        Rebuild, // "rebuild": the report, from the balances of the events of an outbox
+        Compact, // "compact": the events of an outbox "rebuild" still needs
+    }
+
+
+    // What "top" ranks the clients by.
+    #[derive(Debug, Default, PartialEq, Clone, Copy)]
+    pub(crate) enum TopMetric {
+        #[default]
+        Held,
+        Total,
+        Chargebacks,
+    }
+
+    pub(crate) const DEFAULT_TOP_COUNT : usize = 10;
+
+
+    // How many parsed rows the reader thread sends to the engine at once, "--batch-size".
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub(crate) enum BatchSize {
+        Fixed(usize),
+        Auto, // measured over the start of the input
+    }
+
+    impl Default for BatchSize {
+        fn default() -> Self {
+            BatchSize::Fixed(256)
+        }
+    }
+
+    // How long transactions stay in the index, "--retain": a number of transactions, or of seconds with an "s" suffix.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub(crate) enum Retention {
+        Transactions(usize), // the most indexed at once
+        Seconds(i64), // since they were indexed, by the time of the input
+    }
+
+
+    // How many batches the reader thread can get ahead of the engine, "--read-ahead".
+    pub(crate) const DEFAULT_READ_AHEAD : usize = 16;
+
+
+    #[derive(Debug, Default, PartialEq, Clone, Copy)]
+    pub(crate) enum Period {
+        Daily,
+        #[default]
+        Monthly,
+    }
+
+
+    #[derive(Debug, Default, PartialEq, Clone, Copy)]
+    pub(crate) enum ColorChoice {
+        #[default]
+        Auto, // only color streams attached to a terminal
+        Always,
+        Never,
+    }
+
+    impl ColorChoice {
+        pub(crate) fn enabled_for(self, stream : &impl IsTerminal) -> bool {
+            match self {
+                ColorChoice::Auto => stream.is_terminal(),
+                ColorChoice::Always => true,
+                ColorChoice::Never => false,
+            }
+        }
+    }
+
+
+    #[derive(Debug, Default, PartialEq)]
+    pub(crate) enum ReportFormat {
+        #[default]
+        Csv,
+        Extended, // CSV with extra columns for support triage
+        Html,
+    }
+
+
+
+//* Parsing *//
+
+    pub(crate) const USAGE: &str = "Error! Incorrect call, the usage is 'cargo run -- [command] <file-path> [options] > <output-destination>'. The options are listed in the README.";
+
+    // Parses the command line arguments into the options for this run.
+    // The input file path is the only positional argument, besides an optional subcommand before it. Everything else is an optional flag.
+    pub(crate) fn parse_args() -> Result<Options, Box<dyn Error>> {
+
+        let mut options = Options::default();
+        let mut positional = Vec::new();
+
+        // "--flag=value" is accepted as well as "--flag value"
+        let mut args = env::args().skip(1).flat_map(|arg| {
+            match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => vec![flag.to_string(), value.to_string()],
+                _ => vec![arg],
+            }
+        });
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--retain" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    let error = "Error! --retain expects a transaction count, or a number of seconds followed by 's'.";
+                    options.input.retain_transactions = Some(match value.strip_suffix('s') {
+                        Some(seconds) => Retention::Seconds(seconds.parse().ok().filter(|seconds| *seconds > 0).ok_or(error)?),
+                        None => Retention::Transactions(value.parse().map_err(|_| error)?),
+                    });
+                },
+
+                "--period" => {
+                    options.command.period = match args.next().ok_or(USAGE)?.as_str() {
+                        "daily" => Period::Daily,
+                        "monthly" => Period::Monthly,
+                        _ => return Err("Error! --period expects 'daily' or 'monthly'.".into()),
+                    };
+                },
+
+                "--by" => {
+                    options.command.top_by = match args.next().ok_or(USAGE)?.as_str() {
+                        "held" => TopMetric::Held,
+                        "total" => TopMetric::Total,
+                        "chargebacks" => TopMetric::Chargebacks,
+                        _ => return Err("Error! --by expects 'held', 'total' or 'chargebacks'.".into()),
+                    };
+                },
+
+                "--n" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.command.top_count = Some(value.parse().map_err(|_| "Error! --n expects a number of clients.")?);
+                },
+
+                "--report" => options.command.top_report = Some(args.next().ok_or(USAGE)?),
+                "--verify" => options.command.verify_report = Some(args.next().ok_or(USAGE)?),
+                "--undo" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.command.undo_count = value.parse().map_err(|_| "Error! --undo expects a number of events.")?;
+                },
+
+                "--batch-size" => {
+                    options.input.batch_size = match args.next().ok_or(USAGE)?.as_str() {
+                        "auto" => BatchSize::Auto,
+                        value => BatchSize::Fixed(value.parse().ok().filter(|rows| *rows > 0).ok_or("Error! --batch-size expects 'auto' or a positive number of rows.")?),
+                    };
+                },
+                "--read-ahead" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.input.read_ahead = Some(value.parse().ok().filter(|batches| *batches > 0).ok_or("Error! --read-ahead expects a positive number of batches.")?);
+                },
+
+                "--output" => options.report.output = Some(args.next().ok_or(USAGE)?),
+                "--split-rows" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.report.split_rows = Some(value.parse().ok().filter(|rows| *rows > 0).ok_or("Error! --split-rows expects a positive number of rows.")?);
+                },
+
+                "--only-locked" => options.report.filter.only_locked = true,
+                "--nonzero" => options.report.filter.nonzero = true,
+                "--min-total" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.report.filter.min_total = Some(value.parse().map_err(|_| "Error! --min-total expects an amount.")?);
+                },
+
+                "--as-of" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.input.as_of = Some(parse_timestamp(&value).ok_or("Error! --as-of expects unix seconds or an ISO 8601 date.")?);
+                },
+
+                "--format" => {
+                    options.report.format = match args.next().ok_or(USAGE)?.as_str() {
+                        "csv" => ReportFormat::Csv,
+                        "extended" => ReportFormat::Extended,
+                        "html" => ReportFormat::Html,
+                        _ => return Err("Error! --format expects 'csv', 'extended' or 'html'.".into()),
+                    };
+                },
+
+                "--viz" => options.display.visualize = true,
+
+                "--strict" => options.input.strict = true,
+                "--atomic" => options.report.atomic = true,
+
+                "--verbose" => options.display.verbose = true,
+                "--redact" => options.display.redact = true,
+
+                "--flags-report" => options.reports.flags_report = Some(args.next().ok_or(USAGE)?),
+
+                "--blocklist" => options.data.blocklist = load_blocklist(&args.next().ok_or(USAGE)?)?,
+
+                "--kyc" => options.data.kyc = Some(load_kyc(&args.next().ok_or(USAGE)?)?),
+                "--clients-meta" => options.data.clients_meta = Some(load_clients_meta(&args.next().ok_or(USAGE)?)?),
+                "--control" => options.data.control = Some(load_control_totals(&args.next().ok_or(USAGE)?)?),
+
+                "--compliance-report" => options.reports.compliance_report = Some(args.next().ok_or(USAGE)?),
+                "--rejects" => options.reports.rejects = Some(args.next().ok_or(USAGE)?),
+                "--passthrough-columns" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.reports.passthrough_columns = value.split(',').map(|column| column.trim().to_string()).filter(|column| !column.is_empty()).collect();
+                },
+                "--admin-input" => options.input.admin_input = Some(args.next().ok_or(USAGE)?),
+                "--quarantine" => options.reports.quarantine = Some(args.next().ok_or(USAGE)?),
+                "--malformed" => options.reports.malformed = Some(args.next().ok_or(USAGE)?),
+
+                "--operator-report" => options.reports.operator_report = Some(args.next().ok_or(USAGE)?),
+                "--wallets-report" => options.reports.wallets_report = Some(args.next().ok_or(USAGE)?),
+                "--eod-report" => options.live.eod_report = Some(args.next().ok_or(USAGE)?),
+                "--eod-dir" => options.live.eod_dir = Some(args.next().ok_or(USAGE)?),
+                "--live-report" => options.live.report = Some(args.next().ok_or(USAGE)?),
+                "--live-every" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    let error = "Error! --live-every expects a positive number of rows, or of seconds followed by 's'.";
+                    options.live.every = match value.strip_suffix('s') {
+                        Some(seconds) => LiveInterval::Seconds(seconds.parse().ok().filter(|seconds| *seconds > 0).ok_or(error)?),
+                        None => LiveInterval::Rows(value.parse().ok().filter(|rows| *rows > 0).ok_or(error)?),
+                    };
+                },
+                "--live-delta" => options.live.delta = true,
+                "--held-report" => options.reports.held_report = Some(args.next().ok_or(USAGE)?),
+                "--open-disputes" => options.reports.open_disputes = Some(args.next().ok_or(USAGE)?),
+                "--risk-report" => options.reports.risk_report = Some(args.next().ok_or(USAGE)?),
+                "--locked-report" => options.reports.locked_report = Some(args.next().ok_or(USAGE)?),
+                "--negative-report" => options.reports.negative_report = Some(args.next().ok_or(USAGE)?),
+                "--id-report" => options.reports.id_report = Some(args.next().ok_or(USAGE)?),
+                "--history" => options.reports.history = Some(args.next().ok_or(USAGE)?),
+                "--outbox" => options.reports.outbox = Some(args.next().ok_or(USAGE)?),
+                "--ordered-ids" => options.reports.ordered_ids = true,
+
+                "--limits" => options.data.client_limits = load_client_limits(&args.next().ok_or(USAGE)?)?,
+
+                "--config" => options.config = load_config(&args.next().ok_or(USAGE)?)?,
+                "--baseline" => options.command.baseline = Some(load_config(&args.next().ok_or(USAGE)?)?),
+
+                "--color" => {
+                    options.display.color = match args.next().ok_or(USAGE)?.as_str() {
+                        "auto" => ColorChoice::Auto,
+                        "always" => ColorChoice::Always,
+                        "never" => ColorChoice::Never,
+                        _ => return Err("Error! --color expects 'auto', 'always' or 'never'.".into()),
+                    };
+                },
+                "--lang" => {
+                    options.display.lang = match args.next().ok_or(USAGE)?.as_str() {
+                        "en" => Lang::En,
+                        "pt" => Lang::Pt,
+                        _ => return Err("Error! --lang expects 'en' or 'pt'.".into()),
+                    };
+                },
+
+                _ if positional.len() < 3 && !arg.starts_with("--") => positional.push(arg),
+
+                _ => return Err(USAGE.into()),
+            }
+        }
+
+        let file_path = match positional.as_slice() {
+            [command] if command == "top" && options.command.top_report.is_some() => {
+                options.command.command = Command::Top;
+                String::new() // the report is ranked as it is, there's no file to process
+            },
+            [file_path] => file_path.clone(),
+            [command, file_path] => {
+                options.command.command = match command.as_str() {
+                    "rollup" => Command::Rollup,
+                    "top" => Command::Top,
+                    "whatif" => Command::Whatif,
+                    "rebuild" => Command::Rebuild,
+                    "compact" => Command::Compact,
+                    _ => return Err(format!("Error! Unknown command '{}'.", command).into()),
+                };
+                file_path.clone()
+            },
+            [command, before, after] if command == "diff" => {
+                options.command.command = Command::Diff;
+                options.command.diff_reports = Some((before.clone(), after.clone()));
+                String::new() // both reports are compared as they are, there's no file to process
+            },
+            [command, prior_report, file_path] if command == "merge" => {
+                options.command.command = Command::Merge;
+                options.input.prior_report = Some(prior_report.clone());
+                file_path.clone()
+            },
+            _ => return Err(USAGE.into()),
+        };
+
+        options.input.file_path = file_path;
+
+        if options.command.top_report.is_some() && !(options.command.command == Command::Top && options.input.file_path.is_empty()) {
+            return Err("Error! --report only applies to the top command, in place of the file path.".into());
+        }
+        if options.command.top_report.is_some() && options.command.top_by == TopMetric::Chargebacks {
+            return Err("Error! Reports don't keep the chargebacks, so they can only be ranked --by held or total.".into());
+        }
+
+        if options.report.output.is_some() && (!matches!(options.command.command, Command::Report | Command::Merge | Command::Rebuild) || options.report.format == ReportFormat::Html) {
+            return Err("Error! --output only applies to the csv and extended reports.".into());
+        }
+        if options.report.output.as_deref().is_some_and(|path| path.ends_with(".gz")) {
+            return Err("Error! Compressed output isn't supported, --output expects an uncompressed CSV path, which can be compressed once written.".into());
+        }
+        if options.command.baseline.is_some() && options.command.command != Command::Whatif {
+            return Err("Error! --baseline only applies to the whatif command.".into());
+        }
+        if options.command.verify_report.is_some() && options.command.command != Command::Rebuild {
+            return Err("Error! --verify only applies to the rebuild command.".into());
+        }
+        if options.command.undo_count > 0 && options.command.command != Command::Rebuild {
+            return Err("Error! --undo only applies to the rebuild command.".into());
+        }
+        if options.command.command == Command::Rebuild && options.report.format != ReportFormat::Csv {
+            return Err("Error! The outbox only has the balances and locks, so rebuild only writes the csv report.".into());
+        }
+        if options.report.split_rows.is_some() && options.report.output.is_none() {
+            return Err("Error! --split-rows needs an --output path to number the files after.".into());
+        }
+
+        Ok(options)
+    }
+
+
+    // Reads the blocked clients from a CSV file with a "client" column and an optional "reason" column.
+    pub(crate) fn load_blocklist(path : &str) -> Result<HashMap<ClientId, String>, Box<dyn Error>> {
+
+        #[derive(serde::Deserialize)]
+        struct BlocklistEntry {
+            client: ClientId,
+            reason: Option<String>,
+        }
+
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+            .map_err(|e| format!("Error! Could not read blocklist '{}': {}", path, e))?;
+
+        let mut blocklist = HashMap::new();
+
+        for row in reader.deserialize() {
+            let entry: BlocklistEntry = row?;
+            let reason = entry.reason.filter(|r| !r.is_empty()).unwrap_or_else(|| "blocklist".to_string());
+            blocklist.insert(entry.client, reason);
+        }
+
+        Ok(blocklist)
+    }
+
+
+    // Reads the metadata of each client from a CSV file with a "client" column and optional "name", "country" and "segment" columns.
+    pub(crate) fn load_clients_meta(path : &str) -> Result<ClientsMeta, Box<dyn Error>> {
+
+        // the fields are spelled out, since a flattened struct would read "123" as a number and refuse it as a name
+        #[derive(serde::Deserialize)]
+        struct MetaEntry {
+            client: ClientId,
+            #[serde(default)]
+            name: String,
+            #[serde(default)]
+            country: String,
+            #[serde(default)]
+            segment: String,
+        }
+
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+            .map_err(|e| format!("Error! Could not read client metadata '{}': {}", path, e))?;
+
+        let mut clients_meta = HashMap::new();
+
+        for row in reader.deserialize() {
+            let entry: MetaEntry = row?;
+            clients_meta.insert(entry.client, ClientMeta { name: entry.name, country: entry.country, segment: entry.segment });
+        }
+
+        Ok(clients_meta)
+    }
+
+
+    // Reads the expected totals of each transaction type from a CSV file with "type", "count" and "amount" columns,
+    // as partners send them along with their files. An empty amount isn't checked.
+    pub(crate) fn load_control_totals(path : &str) -> Result<BTreeMap<String, ControlTotal>, Box<dyn Error>> {
+
+        #[derive(serde::Deserialize)]
+        struct ControlEntry {
+            #[serde(rename = "type")]
+            tx_type: String,
+            count: u64,
+            amount: Option<f64>,
+        }
+
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+            .map_err(|e| format!("Error! Could not read control totals '{}': {}", path, e))?;
+
+        let mut control = BTreeMap::new();
+
+        for row in reader.deserialize() {
+            let entry: ControlEntry = row?;
+            control.insert(entry.tx_type.to_lowercase(), ControlTotal { count: entry.count, amount: entry.amount });
+        }
+
+        Ok(control)
+    }
+
+
+    // Reads the KYC status of each client from a CSV file with "client" and "status" ("verified" or "unverified") columns.
+    pub(crate) fn load_kyc(path : &str) -> Result<HashMap<ClientId, KycStatus>, Box<dyn Error>> {
+
+        #[derive(serde::Deserialize)]
+        struct KycEntry {
+            client: ClientId,
+            status: String,
+        }
+
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+            .map_err(|e| format!("Error! Could not read KYC statuses '{}': {}", path, e))?;
+
+        let mut kyc = HashMap::new();
+
+        for row in reader.deserialize() {
+            let entry: KycEntry = row?;
+            let status = match entry.status.as_str() {
+                "verified" => KycStatus::Verified,
+                "unverified" => KycStatus::Unverified,
+                _ => return Err(format!("Error! Invalid KYC status '{}' for client {}.", entry.status, entry.client).into()),
+            };
+            kyc.insert(entry.client, status);
+        }
+
+        Ok(kyc)
+    }
+
+
+    // Reads the per-client limits from a CSV file with a "client" column, followed by a column per overridden limit.
+    // An empty cell falls back to the "[limits]" section of the config.
+    pub(crate) fn load_client_limits(path : &str) -> Result<HashMap<ClientId, ClientLimits>, Box<dyn Error>> {
+
+        #[derive(serde::Deserialize)]
+        struct LimitsEntry {
+            client: ClientId,
+            reserve: Option<f64>,
+            tier: Option<String>,
+        }
+
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+            .map_err(|e| format!("Error! Could not read limits '{}': {}", path, e))?;
+
+        let mut client_limits = HashMap::new();
+
+        for row in reader.deserialize() {
+            let entry: LimitsEntry = row?;
+            let tier = match entry.tier.as_deref() {
+                None | Some("") => Tier::default(),
+                Some(tier) => tier.parse().map_err(|e| format!("Error! Invalid limits for client {}: {}", entry.client, e))?,
+            };
+            client_limits.insert(entry.client, ClientLimits { reserve: entry.reserve, tier });
+        }
+
+        Ok(client_limits)
+    }
+
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::fs;
+        use crate::testing::*;
+
+        #[test]
+        fn clients_meta_keeps_numeric_looking_values_as_text() {
+            let path = temp_file("clients-meta.csv", "client,name,country,segment\n1,123,PT,1\n2,Ana,,\n");
+
+            let meta = load_clients_meta(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(meta[&1], ClientMeta { name: "123".to_string(), country: "PT".to_string(), segment: "1".to_string() });
+            assert_eq!(meta[&2], ClientMeta { name: "Ana".to_string(), ..ClientMeta::default() });
+        }
+
+        #[test]
+        fn clients_meta_columns_are_optional() {
+            let path = temp_file("clients-meta-short.csv", "client,segment\n7,007\n");
+
+            let meta = load_clients_meta(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(meta[&7], ClientMeta { segment: "007".to_string(), ..ClientMeta::default() });
+        }
+
+        #[test]
+        fn blocklist_reasons_default_to_blocklist() {
+            let path = temp_file("blocklist.csv", "client, reason\n13, sanctions\n14,\n");
+
+            let blocklist = load_blocklist(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(blocklist, HashMap::from([(13, "sanctions".to_string()), (14, "blocklist".to_string())]));
+        }
+
+        #[test]
+        fn client_limits_leave_empty_cells_to_the_config() {
+            let path = temp_file("limits.csv", "client,reserve,tier\n1,25,premium\n2,,\n");
+
+            let limits = load_client_limits(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!((limits[&1].reserve, limits[&1].tier), (Some(25.0), Tier::Premium));
+            assert_eq!((limits[&2].reserve, limits[&2].tier), (None, Tier::Basic));
+        }
+
+        #[test]
+        fn kyc_statuses_must_be_known() {
+            let path = temp_file("kyc.csv", "client,status\n1,verified\n2, unverified\n");
+            let kyc = load_kyc(&path).unwrap();
+            fs::write(&path, "client,status\n1,verified\n3,pending\n").unwrap();
+            let err = load_kyc(&path).unwrap_err().to_string();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(kyc, HashMap::from([(1, KycStatus::Verified), (2, KycStatus::Unverified)]));
+            assert_eq!(err, "Error! Invalid KYC status 'pending' for client 3.");
+        }
+
+        #[test]
+        fn control_totals_are_read_per_lowercase_type() {
+            let path = temp_file("control.csv", "type,count,amount\nDeposit,2,15.5\nwithdrawal,1,\n");
+            let control = load_control_totals(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(control.len(), 2);
+            assert_eq!((control["deposit"].count, control["deposit"].amount), (2, Some(15.5)));
+            assert_eq!((control["withdrawal"].count, control["withdrawal"].amount), (1, None), "an empty amount isn't checked");
+        }
+    }
@@ -0,0 +1,1473 @@
+// Everything the run writes out: the report and its sinks, and the side reports.
+
+use std::{error::Error, io::{self, Write}, fs, collections::{BTreeMap, HashMap}, time::Instant};
+use csv::Trim;
+use crate::{clock::*, config::*, lang::*, observers::*, options::*, sources::*, types::*};
+
+
+
+//* Reports *//
+
+    pub(crate) fn write_rollup(rollup : &Rollup) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(io::stdout());
+
+        writer.write_record(["client", "period", "opening_balance", "deposits", "withdrawals", "disputes_opened", "disputes_closed", "closing_balance"])?;
+        for ((client_id, period), row) in &rollup.rows {
+            writer.write_record([
+                client_id.to_string(),
+                period.clone(),
+                format_amount(row.opening_balance),
+                format_amount(row.deposits),
+                format_amount(row.withdrawals),
+                row.disputes_opened.to_string(),
+                row.disputes_closed.to_string(),
+                format_amount(row.closing_balance),
+            ])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Ranks the clients by the chosen metric, highest first, ties by client ID. Chargebacks are counted by the risk monitor.
+    pub(crate) fn write_top(
+        client_data : &HashMap<ClientId, ClientData>,
+        risk : &RiskMonitor,
+        by : TopMetric,
+        count : usize,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let chargebacks = |client_id : &ClientId| risk.counts.get(client_id).map_or(0, |(_, chargebacks)| *chargebacks);
+
+        let mut rows: Vec<(ClientId, f64)> = client_data.iter()
+            .map(|(client_id, client)| {
+                let value = match by {
+                    TopMetric::Held => client.held,
+                    TopMetric::Total => client.total,
+                    TopMetric::Chargebacks => chargebacks(client_id) as f64,
+                };
+                (*client_id, value)
+            })
+            .collect();
+        rows.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let metric = match by {
+            TopMetric::Held => "held",
+            TopMetric::Total => "total",
+            TopMetric::Chargebacks => "chargebacks",
+        };
+
+        let mut writer = csv::Writer::from_writer(io::stdout());
+
+        writer.write_record(["rank", "client", metric])?;
+        for (rank, (client_id, value)) in rows.into_iter().take(count).enumerate() {
+            writer.write_record([(rank + 1).to_string(), client_id.to_string(), format_amount(value)])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // A field of an account whose value differs between two reports, for "diff".
+    // An account missing from one of the reports has its fields empty there.
+    #[derive(Debug, PartialEq)]
+    pub(crate) struct FieldChange {
+        pub(crate) client: ClientId,
+        pub(crate) field: &'static str,
+        pub(crate) before: String,
+        pub(crate) after: String,
+    }
+
+
+    // Compares the balances as they're written, at 4 decimals, so the reports of two identical runs never differ.
+    // Listed by client ID, then in the order of the report's columns.
+    pub(crate) fn diff_reports(
+        before : &HashMap<ClientId, ClientData>,
+        after : &HashMap<ClientId, ClientData>,
+    ) -> Vec<FieldChange> {
+
+        let fields = |client : Option<&ClientData>| -> [String; 4] {
+            match client {
+                Some(client) => [format_amount(client.available), format_amount(client.held), format_amount(client.total), client.is_locked().to_string()],
+                None => Default::default(),
+            }
+        };
+
+        let mut client_ids: Vec<ClientId> = before.keys().chain(after.keys()).copied().collect();
+        client_ids.sort();
+        client_ids.dedup();
+
+        let mut changes = Vec::new();
+        for client_id in client_ids {
+            let (before_fields, after_fields) = (fields(before.get(&client_id)), fields(after.get(&client_id)));
+            for (i, field) in ["available", "held", "total", "locked"].into_iter().enumerate() {
+                if before_fields[i] != after_fields[i] {
+                    changes.push(FieldChange { client: client_id, field, before: before_fields[i].clone(), after: after_fields[i].clone() });
+                }
+            }
+        }
+
+        changes
+    }
+
+
+    pub(crate) fn write_diff(changes : &[FieldChange]) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(io::stdout());
+
+        writer.write_record(["client", "field", "before", "after"])?;
+        for change in changes {
+            writer.write_record([&change.client.to_string(), change.field, &change.before, &change.after])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // The events are written as they were read, so a compacted outbox is still an outbox.
+    pub(crate) fn write_events(events : &[OutboxEvent]) -> Result<(), Box<dyn Error>> {
+        let mut writer = io::BufWriter::new(io::stdout());
+        for event in events {
+            writeln!(writer, "{}", event.line)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Writes the chargeback ratio of every client, highest first, flagging the ones above the configured threshold.
+    pub(crate) fn write_risk_report(
+        path : &str,
+        risk : &RiskMonitor,
+        risk_rules : &RiskRules,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut rows: Vec<(ClientId, u64, u64, f64)> = risk.counts.iter()
+            .map(|(client_id, (deposits, chargebacks))| {
+                let ratio = if *deposits == 0 {0.0} else {*chargebacks as f64 / *deposits as f64};
+                (*client_id, *deposits, *chargebacks, ratio)
+            })
+            .collect();
+        rows.sort_by(|a, b| b.3.total_cmp(&a.3).then(a.0.cmp(&b.0)));
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["client", "deposits", "chargebacks", "chargeback_ratio", "flagged"])?;
+        for (client_id, deposits, chargebacks, ratio) in rows {
+            let flagged = risk_rules.chargeback_ratio_threshold.is_some_and(|threshold| ratio > threshold);
+            writer.write_record([
+                client_id.to_string(),
+                deposits.to_string(),
+                chargebacks.to_string(),
+                format_amount(ratio),
+                flagged.to_string(),
+            ])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    pub(crate) fn write_negative_report(
+        path : &str,
+        client_data : &HashMap<ClientId, ClientData>,
+        negative : &NegativeBalances,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut clients: Vec<(&ClientId, &ClientData)> = client_data.iter().filter(|(_, client)| client.available < 0.0).collect();
+        clients.sort_by_key(|(client_id, _)| **client_id);
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["client", "available", "held", "total", "transactions"])?;
+        for (client_id, client) in clients {
+            writer.write_record([
+                client_id.to_string(),
+                format_amount(client.available),
+                format_amount(client.held),
+                format_amount(client.total),
+                negative.drivers.get(client_id).map(|drivers| drivers.join(";")).unwrap_or_default(),
+            ])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Writes the missing tx ID ranges between the lowest and highest IDs read ("gap", first and last missing ID),
+    // followed by the IDs lower than one read before them ("out_of_order", the ID and the higher one), if they're expected in order.
+    pub(crate) fn write_id_report(
+        path : &str,
+        analysis : &IdAnalysis,
+        ordered_ids : bool,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["kind", "from", "to"])?;
+
+        let mut previous: Option<TxId> = None;
+        for tx_id in &analysis.ids {
+            if let Some(previous) = previous.filter(|previous| tx_id - previous > 1) {
+                writer.write_record(["gap".to_string(), (previous + 1).to_string(), (tx_id - 1).to_string()])?;
+            }
+            previous = Some(*tx_id);
+        }
+
+        if ordered_ids {
+            for (tx_id, highest) in &analysis.out_of_order {
+                writer.write_record(["out_of_order".to_string(), tx_id.to_string(), highest.to_string()])?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Sorts the ranges and merges the ones overlapping or next to each other, so they can be binary searched.
+    pub(crate) fn merge_ranges(ranges : &mut Vec<(TxId, TxId)>) {
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(TxId, TxId)> = Vec::with_capacity(ranges.len());
+        for &(first, last) in ranges.iter() {
+            match merged.last_mut() {
+                Some((_, merged_last)) if first <= merged_last.saturating_add(1) => *merged_last = (*merged_last).max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+
+        *ranges = merged;
+    }
+
+
+    // The share of a run's tx IDs already processed by an earlier run above which it's most likely the same file sent again.
+    pub(crate) const HISTORY_OVERLAP_WARNING: f64 = 0.5;
+
+    // Checks the tx IDs read against the ranges of the earlier runs in the "--history" file, then appends this run's ranges to it.
+    // Returns a warning per earlier run the IDs overlap heavily with. The file has a row per range ("run", "file", "first", "last"),
+    // "run" being the start of the run in unix seconds.
+    pub(crate) fn record_run_history(
+        path : &str,
+        file_path : &str,
+        run : i64,
+        analysis : &IdAnalysis,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+
+        #[derive(serde::Deserialize)]
+        struct HistoryEntry {
+            run: i64,
+            file: String,
+            first: TxId,
+            last: TxId,
+        }
+
+        let mut earlier_runs: BTreeMap<(i64, String), Vec<(TxId, TxId)>> = BTreeMap::new();
+
+        let exists = fs::metadata(path).is_ok();
+        if exists {
+            let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+                .map_err(|e| format!("Error! Could not read history '{}': {}", path, e))?;
+            for row in reader.deserialize() {
+                let entry: HistoryEntry = row?;
+                earlier_runs.entry((entry.run, entry.file)).or_default().push((entry.first, entry.last));
+            }
+        }
+
+        let mut warnings = Vec::new();
+
+        for ((earlier_run, earlier_file), ranges) in &mut earlier_runs {
+            merge_ranges(ranges);
+
+            // the range that could hold an ID is the last one starting at or before it
+            let overlapping = analysis.ids
+                .iter()
+                .filter(|tx_id| {
+                    let next = ranges.partition_point(|(first, _)| first <= tx_id);
+                    next > 0 && ranges[next - 1].1 >= **tx_id
+                })
+                .count();
+            let share = overlapping as f64 / analysis.ids.len().max(1) as f64;
+
+            if share > HISTORY_OVERLAP_WARNING {
+                warnings.push(format!(
+                    "Warning! {:.0}% of the tx IDs of this run were already processed by the run of '{}' on {}. Was the file sent again?",
+                    share * 100.0,
+                    earlier_file,
+                    format_date(earlier_run.div_euclid(86_400)),
+                ));
+            }
+        }
+
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Error! Could not write history '{}': {}", path, e))?;
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+
+        if !exists {
+            writer.write_record(["run", "file", "first", "last"])?;
+        }
+
+        // consecutive IDs are stored as a single range
+        let mut range: Option<(TxId, TxId)> = None;
+        for tx_id in &analysis.ids {
+            range = match range {
+                Some((first, last)) if *tx_id == last + 1 => Some((first, *tx_id)),
+                Some((first, last)) => {
+                    writer.write_record([run.to_string(), file_path.to_string(), first.to_string(), last.to_string()])?;
+                    Some((*tx_id, *tx_id))
+                },
+                None => Some((*tx_id, *tx_id)),
+            };
+        }
+        if let Some((first, last)) = range {
+            writer.write_record([run.to_string(), file_path.to_string(), first.to_string(), last.to_string()])?;
+        }
+
+        writer.flush()?;
+        Ok(warnings)
+    }
+
+
+    // Lists the locked accounts with everything keeping them locked.
+    pub(crate) fn write_locked_report(
+        path : &str,
+        client_data : &HashMap<ClientId, ClientData>,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut clients: Vec<(&ClientId, &ClientData)> = client_data.iter().filter(|(_, client)| client.is_locked()).collect();
+        clients.sort_by_key(|(client_id, _)| **client_id);
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["client", "available", "held", "total", "lock_reasons"])?;
+        for (client_id, client) in clients {
+            writer.write_record([
+                client_id.to_string(),
+                format_amount(client.available),
+                format_amount(client.held),
+                format_amount(client.total),
+                client.lock_reasons(),
+            ])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Both review reports share the same layout: the transaction, followed by why it was held back.
+    pub(crate) fn write_review_report(path : &str, rows : &[[String; 6]]) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["type", "client", "tx", "amount", "timestamp", "reason"])?;
+        for row in rows {
+            writer.write_record(row)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+
+
+
+    pub(crate) fn write_rejects(path : &str, passthrough_columns : &[String], rows : &[Vec<String>]) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_path(path)?;
+
+        let mut header = vec!["type", "client", "tx", "amount", "timestamp", "code", "code_name", "reason", "file", "line", "byte"];
+        header.extend(passthrough_columns.iter().map(String::as_str));
+        writer.write_record(&header)?;
+        for row in rows {
+            writer.write_record(row)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // The quarantined records are written as they were read, under the header of the input file.
+    // Malformed records may not have as many fields as the header, so the lengths aren't enforced.
+    pub(crate) fn write_quarantine(path : &str, input_path : &str, records : &[csv::StringRecord]) -> Result<(), Box<dyn Error>> {
+        let headers = csv::Reader::from_path(input_path)?.headers()?.clone();
+        let mut writer = csv::WriterBuilder::new().flexible(true).from_path(path)?;
+
+        writer.write_record(&headers)?;
+        for record in records {
+            writer.write_record(record)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+
+//* Outputs *//
+
+    // The files written while processing, which only stand once the run is committed: a failed or aborted run removes them.
+    // They're written under a ".partial" name and renamed on commit, so nothing picks up the output of a run that didn't complete.
+    // The live report is the exception, since it's meant to be read while processing, so it's only removed.
+    #[derive(Default)]
+    pub(crate) struct StagedOutputs {
+        pub(crate) partial: Vec<(String, String)>, // (written to, renamed to on commit)
+        pub(crate) live: Vec<String>,
+    }
+
+    impl StagedOutputs {
+        pub(crate) fn stage(&mut self, path : &str) -> String {
+            let partial = format!("{}.partial", path);
+            self.partial.push((partial.clone(), path.to_string()));
+            partial
+        }
+
+        pub(crate) fn track(&mut self, path : &str) {
+            if !self.live.iter().any(|live| live == path) {
+                self.live.push(path.to_string());
+            }
+        }
+
+        pub(crate) fn commit(self) -> Result<(), Box<dyn Error>> {
+            for (partial, path) in self.partial {
+                fs::rename(&partial, &path).map_err(|e| format!("Error! Could not move '{}' to '{}': {}", partial, path, e))?;
+            }
+            Ok(())
+        }
+
+        // Best effort, the run is failing already.
+        pub(crate) fn discard(self) {
+            for path in self.partial.iter().map(|(partial, _)| partial).chain(&self.live) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+
+    // End-of-day settlement: once a row is timestamped on a later (UTC) day than the previous ones,
+    // the closing balances of every client are written for the day that ended, as well as for the last day at the end of the input.
+    // Both outputs are optional: a single report with a "date" column, and a file per day in a directory.
+    pub(crate) struct EndOfDay {
+        pub(crate) report: Option<csv::Writer<fs::File>>,
+        pub(crate) dir: Option<String>,
+        pub(crate) day: Option<i64>, // days since the epoch of the latest timestamped row
+    }
+
+    impl EndOfDay {
+        pub(crate) fn new(live : &LiveOutputs, staged : &mut StagedOutputs) -> Result<Self, Box<dyn Error>> {
+            let report = match &live.eod_report {
+                Some(path) => {
+                    let mut writer = csv::Writer::from_path(staged.stage(path))?;
+                    writer.write_record(["date", "client", "available", "held", "total", "locked"])?;
+                    Some(writer)
+                },
+                None => None,
+            };
+
+            if let Some(dir) = &live.eod_dir {
+                fs::create_dir_all(dir)?;
+            }
+
+            Ok(EndOfDay { report, dir: live.eod_dir.clone(), day: None })
+        }
+
+        pub(crate) fn advance(&mut self, timestamp : i64, client_data_map : &HashMap<ClientId, ClientData>, staged : &mut StagedOutputs) -> Result<(), Box<dyn Error>> {
+            let day = timestamp.div_euclid(86_400);
+
+            if self.day.is_some_and(|current| day > current) {
+                self.close_day(client_data_map, staged)?;
+            }
+            if self.day.is_none_or(|current| day > current) {
+                self.day = Some(day);
+            }
+
+            Ok(())
+        }
+
+        pub(crate) fn close_day(&mut self, client_data_map : &HashMap<ClientId, ClientData>, staged : &mut StagedOutputs) -> Result<(), Box<dyn Error>> {
+            let Some(day) = self.day else { return Ok(()) };
+            if self.report.is_none() && self.dir.is_none() {
+                return Ok(());
+            }
+
+            let date = format_date(day);
+            let mut client_ids: Vec<&ClientId> = client_data_map.keys().collect();
+            client_ids.sort();
+
+            let mut day_file = match &self.dir {
+                Some(dir) => {
+                    let mut writer = csv::Writer::from_path(staged.stage(&format!("{}/{}.csv", dir, date)))?;
+                    writer.write_record(["client", "available", "held", "total", "locked"])?;
+                    Some(writer)
+                },
+                None => None,
+            };
+
+            for client_id in client_ids {
+                let client = &client_data_map[client_id];
+                let record = [
+                    client_id.to_string(),
+                    format_amount(client.available),
+                    format_amount(client.held),
+                    format_amount(client.total),
+                    client.is_locked().to_string(),
+                ];
+
+                if let Some(writer) = &mut self.report {
+                    writer.write_field(&date)?;
+                    writer.write_record(&record)?;
+                }
+                if let Some(writer) = &mut day_file {
+                    writer.write_record(&record)?;
+                }
+            }
+
+            if let Some(writer) = &mut self.report {
+                writer.flush()?;
+            }
+            if let Some(mut writer) = day_file {
+                writer.flush()?;
+            }
+
+            Ok(())
+        }
+    }
+
+
+    // How often "--live-report" is written, "--live-every": a number of rows, or of seconds with an "s" suffix.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub(crate) enum LiveInterval {
+        Rows(usize),
+        Seconds(u64),
+    }
+
+    impl Default for LiveInterval {
+        fn default() -> Self {
+            LiveInterval::Rows(10_000)
+        }
+    }
+
+
+    // Enabled by "--live-report", writes the report while processing continues, for inputs that take a while or never end.
+    // The full report replaces the file each time, through a temporary file, so readers never see half of one.
+    // With "--live-delta", each time only the accounts that changed since the previous one are written, to numbered files.
+    pub(crate) struct LiveReport<'a> {
+        pub(crate) path: Option<String>,
+        pub(crate) every: LiveInterval,
+        pub(crate) delta: bool,
+        pub(crate) extended: bool,
+        pub(crate) meta: Option<&'a ClientsMeta>,
+        pub(crate) rows: usize, // since the latest write
+        pub(crate) written_at: Instant,
+        pub(crate) parts: usize,
+        pub(crate) written: HashMap<ClientId, ([f64; 3], bool)>, // what each account looked like in the latest write, for the deltas
+    }
+
+    impl<'a> LiveReport<'a> {
+        pub(crate) fn new(options : &'a Options) -> Self {
+            LiveReport {
+                path: options.live.report.clone(),
+                every: options.live.every,
+                delta: options.live.delta,
+                extended: options.report.format == ReportFormat::Extended,
+                meta: options.data.clients_meta.as_ref(),
+                rows: 0,
+                written_at: Instant::now(),
+                parts: 0,
+                written: HashMap::new(),
+            }
+        }
+
+        // Called before each row, so what is written reflects every row read so far.
+        pub(crate) fn tick(&mut self, client_data_map : &HashMap<ClientId, ClientData>, staged : &mut StagedOutputs) -> Result<(), Box<dyn Error>> {
+            if self.path.is_none() {
+                return Ok(());
+            }
+
+            let due = match self.every {
+                LiveInterval::Rows(rows) => self.rows >= rows,
+                LiveInterval::Seconds(seconds) => self.written_at.elapsed().as_secs() >= seconds,
+            };
+            if due {
+                self.write(client_data_map, staged)?;
+            }
+
+            self.rows += 1;
+            Ok(())
+        }
+
+        // At the end of the input, the rows since the latest write are written too.
+        pub(crate) fn finish(&mut self, client_data_map : &HashMap<ClientId, ClientData>, staged : &mut StagedOutputs) -> Result<(), Box<dyn Error>> {
+            if self.path.is_some() && self.rows > 0 {
+                self.write(client_data_map, staged)?;
+            }
+            Ok(())
+        }
+
+        pub(crate) fn write(&mut self, client_data_map : &HashMap<ClientId, ClientData>, staged : &mut StagedOutputs) -> Result<(), Box<dyn Error>> {
+            let Some(path) = &self.path else { return Ok(()) };
+
+            let mut client_ids: Vec<&ClientId> = client_data_map.keys().collect();
+            client_ids.sort();
+
+            if self.delta {
+                let changed: Vec<&ClientId> = client_ids
+                    .into_iter()
+                    .filter(|client_id| {
+                        let client = &client_data_map[client_id];
+                        self.written.get(client_id) != Some(&([client.available, client.held, client.total], client.is_locked()))
+                    })
+                    .collect();
+
+                if !changed.is_empty() {
+                    self.parts += 1;
+                    let part_path = numbered_path(path, self.parts);
+                    let file = fs::File::create(&part_path).map_err(|e| format!("Error! Could not create '{}': {}", part_path, e))?;
+                    staged.track(&part_path);
+                    let mut sink = CsvSink::new(file, self.extended, self.meta)?;
+                    for client_id in changed {
+                        let client = &client_data_map[client_id];
+                        sink.write_account(*client_id, client)?;
+                        self.written.insert(*client_id, ([client.available, client.held, client.total], client.is_locked()));
+                    }
+                    sink.finish()?;
+                }
+            } else {
+                let temporary_path = format!("{}.tmp", path);
+                let file = fs::File::create(&temporary_path).map_err(|e| format!("Error! Could not create '{}': {}", temporary_path, e))?;
+                let mut sink = CsvSink::new(file, self.extended, self.meta)?;
+                for client_id in client_ids {
+                    sink.write_account(*client_id, &client_data_map[client_id])?;
+                }
+                sink.finish()?;
+                fs::rename(&temporary_path, path)?;
+                staged.track(path);
+            }
+
+            self.rows = 0;
+            self.written_at = Instant::now();
+            Ok(())
+        }
+    }
+
+
+    // Where the report goes, one account at a time. Other formats or transports can be plugged in by implementing it,
+    // "CsvSink" is the default.
+    pub(crate) trait OutputSink {
+        fn write_account(&mut self, client_id : ClientId, client : &ClientData) -> Result<(), Box<dyn Error>>;
+        fn finish(&mut self) -> Result<(), Box<dyn Error>>;
+    }
+
+
+    // The extended report adds the reasons each account is locked and the owners of the account, both separated by ";",
+    // followed by the client's name, country and segment when there's a "--clients-meta" file. With colors enabled, locked accounts and negative balances are highlighted for manual review.
+    // Each record is formatted into a buffer kept across accounts, so writing an account doesn't allocate.
+    pub(crate) struct CsvSink<'a, W: Write> {
+        pub(crate) writer: csv::Writer<W>,
+        pub(crate) extended: bool,
+        pub(crate) meta: Option<&'a ClientsMeta>,
+        pub(crate) record: RecordBuffer,
+    }
+
+
+    // The fields of a record, formatted one after the other into the same string, and the offset each one ends at.
+    #[derive(Default)]
+    pub(crate) struct RecordBuffer {
+        pub(crate) text: String,
+        pub(crate) ends: Vec<usize>,
+    }
+
+    impl RecordBuffer {
+        pub(crate) fn clear(&mut self) {
+            self.text.clear();
+            self.ends.clear();
+        }
+
+        pub(crate) fn push(&mut self, value : impl std::fmt::Display) -> std::fmt::Result {
+            use std::fmt::Write as _;
+            self.push_with(|out| write!(out, "{}", value))
+        }
+
+        pub(crate) fn push_with(&mut self, format : impl FnOnce(&mut String) -> std::fmt::Result) -> std::fmt::Result {
+            format(&mut self.text)?;
+            self.ends.push(self.text.len());
+            Ok(())
+        }
+
+        pub(crate) fn fields(&self) -> impl Iterator<Item = &str> {
+            let starts = std::iter::once(0).chain(self.ends.iter().copied());
+            starts.zip(self.ends.iter().copied()).map(|(start, end)| &self.text[start..end])
+        }
+    }
+
+    impl<'a, W: Write> CsvSink<'a, W> {
+        pub(crate) fn new(output : W, extended : bool, meta : Option<&'a ClientsMeta>) -> Result<Self, Box<dyn Error>> {
+            let mut writer = csv::Writer::from_writer(output);
+
+            // create the header
+            let mut header = vec!["client", "available", "held", "total", "locked"];
+            if extended {
+                header.push("lock_reasons");
+                header.push("owners");
+                header.extend(["rejected_insufficient_funds", "rejected_locked", "duplicate_ids"]);
+                if meta.is_some() {
+                    header.extend(["name", "country", "segment"]);
+                }
+            }
+            writer.write_record(&header)?;
+
+            Ok(CsvSink { writer, extended, meta, record: RecordBuffer::default() })
+        }
+    }
+
+    impl<W: Write> OutputSink for CsvSink<'_, W> {
+        fn write_account(&mut self, client_id : ClientId, client : &ClientData) -> Result<(), Box<dyn Error>> {
+
+            let record = &mut self.record;
+
+            record.clear();
+            record.push(client_id)?;
+            record.push(round_amount(client.available))?;
+            record.push(round_amount(client.held))?;
+            record.push(round_amount(client.total))?;
+            record.push(client.is_locked())?;
+
+            if self.extended {
+                record.push_with(|out| client.write_lock_reasons(out))?;
+                record.push_with(|out| client.write_owner_set(client_id, out))?;
+                record.push(client.stats.insufficient_funds)?;
+                record.push(client.stats.locked)?;
+                record.push(client.stats.duplicate_ids)?;
+
+                if let Some(meta) = self.meta {
+                    let client_meta = meta.get(&client_id);
+                    record.push(client_meta.map_or("", |m| m.name.as_str()))?;
+                    record.push(client_meta.map_or("", |m| m.country.as_str()))?;
+                    record.push(client_meta.map_or("", |m| m.segment.as_str()))?;
+                }
+            }
+
+            for field in record.fields() {
+                self.writer.write_field(field)?;
+            }
+            self.writer.write_record(None::<&[u8]>)?;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+            self.writer.flush()?;
+            Ok(())
+        }
+    }
+
+
+    // Enabled by "--split-rows", starts a new numbered file every "rows_per_file" accounts, each with its own header,
+    // so every file stays under the size limits of the delivery. "report.csv" is split into "report.1.csv", "report.2.csv"...
+    pub(crate) struct SplitCsvSink<'a> {
+        pub(crate) path: String,
+        pub(crate) rows_per_file: usize,
+        pub(crate) extended: bool,
+        pub(crate) meta: Option<&'a ClientsMeta>,
+        pub(crate) part: usize,
+        pub(crate) rows: usize, // written to the current part
+        pub(crate) current: Option<CsvSink<'a, fs::File>>,
+    }
+
+    impl<'a> SplitCsvSink<'a> {
+        pub(crate) fn new(path : &str, rows_per_file : usize, extended : bool, meta : Option<&'a ClientsMeta>) -> Self {
+            SplitCsvSink { path: path.to_string(), rows_per_file, extended, meta, part: 0, rows: 0, current: None }
+        }
+
+        pub(crate) fn start_part(&mut self) -> Result<(), Box<dyn Error>> {
+            if let Some(mut current) = self.current.take() {
+                current.finish()?;
+            }
+
+            self.part += 1;
+            self.rows = 0;
+
+            let path = numbered_path(&self.path, self.part);
+            let file = fs::File::create(&path).map_err(|e| format!("Error! Could not create '{}': {}", path, e))?;
+            self.current = Some(CsvSink::new(file, self.extended, self.meta)?);
+            Ok(())
+        }
+    }
+
+    // "report.csv" numbered 2 is "report.2.csv".
+    pub(crate) fn numbered_path(path : &str, number : usize) -> String {
+        match path.rsplit_once('.') {
+            Some((stem, extension)) if !extension.contains('/') => format!("{}.{}.{}", stem, number, extension),
+            _ => format!("{}.{}", path, number),
+        }
+    }
+
+    impl OutputSink for SplitCsvSink<'_> {
+        fn write_account(&mut self, client_id : ClientId, client : &ClientData) -> Result<(), Box<dyn Error>> {
+            if self.current.is_none() || self.rows == self.rows_per_file {
+                self.start_part()?;
+            }
+            self.rows += 1;
+
+            match self.current.as_mut() {
+                Some(current) => current.write_account(client_id, client),
+                None => Ok(()),
+            }
+        }
+
+        // An empty report still gets its first file, with just the header.
+        fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+            if self.part == 0 {
+                self.start_part()?;
+            }
+
+            match self.current.as_mut() {
+                Some(current) => current.finish(),
+                None => Ok(()),
+            }
+        }
+    }
+
+
+    // The "--only-locked", "--min-total" and "--nonzero" flags, trimming the report down to the accounts of interest.
+    #[derive(Debug, Default)]
+    pub(crate) struct ReportFilter {
+        pub(crate) only_locked: bool,
+        pub(crate) min_total: Option<f64>,
+        pub(crate) nonzero: bool, // at least one of the balances isn't zero
+    }
+
+    impl ReportFilter {
+        pub(crate) fn keeps(&self, client : &ClientData) -> bool {
+            (!self.only_locked || client.is_locked())
+                && self.min_total.is_none_or(|min_total| client.total >= min_total)
+                && (!self.nonzero || client.available != 0.0 || client.held != 0.0 || client.total != 0.0)
+        }
+    }
+
+
+    // Passes on the accounts the filter keeps to another sink.
+    pub(crate) struct FilteredSink<'a, S: OutputSink> {
+        pub(crate) sink: S,
+        pub(crate) filter: &'a ReportFilter,
+    }
+
+    impl<S: OutputSink> OutputSink for FilteredSink<'_, S> {
+        fn write_account(&mut self, client_id : ClientId, client : &ClientData) -> Result<(), Box<dyn Error>> {
+            if !self.filter.keeps(client) {
+                return Ok(());
+            }
+            self.sink.write_account(client_id, client)
+        }
+
+        fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+            self.sink.finish()
+        }
+    }
+
+
+    // Receives the client data map as an input, then sanitizes the data before exporting to the target path. 
+    // The report goes to stdout, unless an "--output" path is given, which may be split into several files.
+    pub(crate) fn write_csv(
+        client_data : HashMap<ClientId, ClientData>,
+        extended : bool,
+        filter : &ReportFilter,
+        output : Option<&str>,
+        split_rows : Option<usize>,
+        meta : Option<&ClientsMeta>,
+    ) -> Result<(), Box<dyn Error>> {
+
+        match (output, split_rows) {
+            (Some(path), Some(rows_per_file)) => {
+                let mut sink = FilteredSink { sink: SplitCsvSink::new(path, rows_per_file, extended, meta), filter };
+                write_report(&mut sink, &client_data)
+            },
+            (Some(path), None) => {
+                let file = fs::File::create(path).map_err(|e| format!("Error! Could not create '{}': {}", path, e))?;
+                let mut sink = FilteredSink { sink: CsvSink::new(file, extended, meta)?, filter };
+                write_report(&mut sink, &client_data)
+            },
+            (None, _) => {
+                let mut sink = FilteredSink { sink: CsvSink::new(io::stdout(), extended, meta)?, filter };
+                write_report(&mut sink, &client_data)
+            },
+        }
+    }
+
+
+    pub(crate) fn write_report(
+        sink : &mut dyn OutputSink,
+        client_data : &HashMap<ClientId, ClientData>,
+    ) -> Result<(), Box<dyn Error>> {
+
+        for (client_id, client) in client_data {
+            sink.write_account(*client_id, client)?;
+        }
+
+        sink.finish()
+    }
+
+
+    // Writes the operator accounts to their own CSV file, since they don't fit the per-client report.
+    pub(crate) fn write_operator_report(
+        path : &str,
+        operator : &OperatorAccounts,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["account", "balance"])?;
+        for (account, balance) in operator.balances() {
+            writer.write_record([account.to_string(), format_amount(balance)])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Lists the transactions still under dispute at the end of the run, by tx ID.
+    pub(crate) fn write_open_disputes(
+        path : &str,
+        transactions_map : &HashMap<TxId, Transaction>,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut disputed: Vec<(&TxId, &Transaction)> = transactions_map.iter()
+            .filter(|(_, te)| te.dispute_status == DisputeStatus::UnderDispute)
+            .collect();
+        disputed.sort_by_key(|(tx_id, _)| **tx_id);
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["tx", "client", "amount", "type"])?;
+        for (tx_id, te) in disputed {
+            writer.write_record([tx_id.to_string(), te.client_id.to_string(), format_amount(te.held), te.tx_type.clone()])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Lists every amount still held, from open disputes and holds, oldest first so the ones open for too long stand out.
+    // The age is measured up to the latest timestamp of the input, and is left empty for entries that weren't timestamped.
+    pub(crate) fn write_held_report(
+        path : &str,
+        state : &EngineState,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let disputes = state.transactions.iter()
+            .filter(|(_, te)| te.dispute_status == DisputeStatus::UnderDispute)
+            .map(|(tx_id, te)| ("dispute", *tx_id, te.client_id, te.held, te.disputed_at));
+
+        let holds = state.holds.iter()
+            .filter(|(_, hold)| hold.status == HoldStatus::Open)
+            .map(|(tx_id, hold)| ("hold", *tx_id, hold.client_id, hold.amount, hold.placed_at));
+
+        let mut rows: Vec<_> = disputes.chain(holds)
+            .map(|(kind, tx_id, client_id, amount, opened_at)| {
+                let age = opened_at.zip(state.latest_timestamp).map(|(opened_at, now)| now.saturating_sub(opened_at));
+                (age, kind, tx_id, client_id, amount, opened_at)
+            })
+            .collect();
+
+        // oldest first, then the undated ones by tx ID
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(a.2.cmp(&b.2)));
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["kind", "client", "tx", "amount", "opened_at", "age_seconds"])?;
+        for (age, kind, tx_id, client_id, amount, opened_at) in rows {
+            writer.write_record([
+                kind.to_string(),
+                client_id.to_string(),
+                tx_id.to_string(),
+                format_amount(amount),
+                opened_at.map(|t| t.to_string()).unwrap_or_default(),
+                age.map(|a| a.to_string()).unwrap_or_default(),
+            ])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Writes a row per client wallet. The lock status is the client's, since locks apply to every wallet.
+    pub(crate) fn write_wallets_report(
+        path : &str,
+        client_data : &HashMap<ClientId, ClientData>,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut writer = csv::Writer::from_path(path)?;
+
+        writer.write_record(["client", "wallet", "available", "held", "total", "locked"])?;
+        for (client_id, client) in client_data {
+            for (wallet, balance) in &client.wallets {
+                writer.write_record([
+                    client_id.to_string(),
+                    wallet.clone(),
+                    format_amount(balance.available),
+                    format_amount(balance.held),
+                    format_amount(balance.total),
+                    client.is_locked().to_string(),
+                ])?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+
+    // Renders the same data as write_csv as a standalone HTML page, along with the run summary.
+    // Everything is inlined (styles and the sorting script), so the file can be attached to a ticket as is.
+    pub(crate) fn write_html(
+        client_data : HashMap<ClientId, ClientData>,
+        operator : &OperatorAccounts,
+        summary : &RunSummary,
+        lang : Lang,
+        meta : Option<&ClientsMeta>,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut clients: Vec<(ClientId, ClientData)> = client_data.into_iter().collect();
+        clients.sort_by_key(|(client_id, _)| *client_id);
+
+        let locked_count = clients.iter().filter(|(_, client)| client.is_locked()).count();
+
+        let mut out = io::stdout().lock();
+
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html lang=\"{}\"><head><meta charset=\"utf-8\"><title>{}</title>", lang.text("lang"), lang.text("accounts-report"))?;
+        writeln!(out, "<style>{}</style></head><body>", HTML_STYLE)?;
+
+        writeln!(out, "<h1>{}</h1>", lang.text("accounts-report"))?;
+        writeln!(out, "<table class=\"summary\">")?;
+        writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", lang.text("summary-processed"), summary.processed)?;
+        writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", lang.text("summary-accepted"), summary.accepted)?;
+        writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", lang.text("summary-rejected"), summary.rejected)?;
+        writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", lang.text("summary-clients"), clients.len())?;
+        writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", lang.text("summary-locked"), locked_count)?;
+        writeln!(out, "</table>")?;
+
+        writeln!(out, "<table class=\"sortable\"><thead><tr>")?;
+        let mut headers = vec!["column-client", "column-available", "column-held", "column-total", "column-locked"];
+        if meta.is_some() {
+            headers.extend(["column-name", "column-country", "column-segment"]);
+        }
+        for header in headers {
+            writeln!(out, "<th>{}</th>", lang.text(header))?;
+        }
+        writeln!(out, "</tr></thead><tbody>")?;
+
+        for (client_id, client) in &clients {
+            let row_class = if client.is_locked() {" class=\"locked\""} else {""};
+
+            write!(
+                out,
+                "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>",
+                row_class,
+                client_id,
+                format_amount(client.available),
+                format_amount(client.held),
+                format_amount(client.total),
+                client.is_locked(),
+            )?;
+            if let Some(meta) = meta {
+                let client_meta = meta.get(client_id).cloned().unwrap_or_default();
+                for value in [client_meta.name, client_meta.country, client_meta.segment] {
+                    write!(out, "<td>{}</td>", html_escape(&value))?;
+                }
+            }
+            writeln!(out, "</tr>")?;
+        }
+
+        writeln!(out, "</tbody></table>")?;
+
+        writeln!(out, "<h2>{}</h2>", lang.text("operator-accounts"))?;
+        writeln!(out, "<table class=\"summary\">")?;
+        for (account, balance) in operator.balances() {
+            writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", account, format_amount(balance))?;
+        }
+        writeln!(out, "</table>")?;
+
+        writeln!(out, "<script>{}</script>", HTML_SORT_SCRIPT)?;
+        writeln!(out, "</body></html>")?;
+
+        out.flush()?;
+        Ok(())
+    }
+
+
+    // Escapes text coming from input files, so it can't break the markup.
+    pub(crate) fn html_escape(value : &str) -> String {
+        value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+    }
+
+
+    pub(crate) const HTML_STYLE: &str = "\
+        body { font-family: sans-serif; margin: 2em; } \
+        table { border-collapse: collapse; margin-bottom: 2em; } \
+        th, td { border: 1px solid #ccc; padding: 4px 12px; text-align: right; } \
+        .sortable th { cursor: pointer; background: #eee; } \
+        tr.locked { background: #fdd; }";
+
+    // Clicking a header sorts the table by that column, numerically when possible. Clicking it again reverses the order.
+    pub(crate) const HTML_SORT_SCRIPT: &str = "\
+        document.querySelectorAll('table.sortable th').forEach(function (th, column) { \
+            th.addEventListener('click', function () { \
+                var body = th.closest('table').tBodies[0]; \
+                var rows = Array.from(body.rows); \
+                var ascending = th.dataset.order !== 'asc'; \
+                th.dataset.order = ascending ? 'asc' : 'desc'; \
+                rows.sort(function (a, b) { \
+                    var x = a.cells[column].textContent, y = b.cells[column].textContent; \
+                    var order = isNaN(x) || isNaN(y) ? x.localeCompare(y) : x - y; \
+                    return ascending ? order : -order; \
+                }); \
+                rows.forEach(function (row) { body.appendChild(row); }); \
+            }); \
+        });";
+
+
+
+
+
+    // Prints ASCII bar charts of the run to stderr, as a quick visual sanity check next to the report.
+    // Covers the top 10 clients by total balance, how held funds are distributed, and why transactions were rejected.
+    pub(crate) fn write_viz(
+        client_data : &HashMap<ClientId, ClientData>,
+        summary : &RunSummary,
+        color : bool,
+        redactor : Option<&Redactor>,
+        lang : Lang,
+    ) -> Result<(), Box<dyn Error>> {
+
+        let mut out = io::stderr().lock();
+
+        let mut top_clients: Vec<(&ClientId, &ClientData)> = client_data.iter().collect();
+        top_clients.sort_by(|(a_id, a), (b_id, b)| b.total.total_cmp(&a.total).then(a_id.cmp(b_id)));
+
+        let top_rows: Vec<(String, f64)> = top_clients
+            .iter()
+            .take(10)
+            .map(|(client_id, client)| {
+                let label = format!("{} {}", lang.text("label-client"), redact_client(redactor, **client_id));
+                (if client.is_locked() {paint(&label, RED, color)} else {label}, redact_amount(redactor, client.total))
+            })
+            .collect();
+
+        write_bar_chart(&mut out, lang.text("chart-top-clients"), &top_rows, color)?;
+
+
+        // bucket upper bounds of the held funds histogram, the last bucket catches everything above
+        let bounds = [0.0f64, 10.0, 100.0, 1_000.0, 10_000.0];
+        let mut held_buckets = [0u64; 6];
+
+        for client in client_data.values() {
+            let bucket = bounds.iter().position(|bound| client.held <= *bound).unwrap_or(bounds.len());
+            held_buckets[bucket] += 1;
+        }
+
+        let labels = ["0", "0 - 10", "10 - 100", "100 - 1k", "1k - 10k", "> 10k"];
+        let held_rows: Vec<(String, f64)> = labels
+            .iter()
+            .zip(held_buckets)
+            .map(|(label, count)| (label.to_string(), count as f64))
+            .collect();
+
+        write_bar_chart(&mut out, lang.text("chart-held-funds"), &held_rows, color)?;
+
+
+        let mut reason_rows: Vec<(String, f64)> = summary.reject_reasons
+            .iter()
+            .map(|(reason, count)| (reason.clone(), *count as f64))
+            .collect();
+        reason_rows.sort_by(|(a_reason, a), (b_reason, b)| b.total_cmp(a).then(a_reason.cmp(b_reason)));
+
+        write_bar_chart(&mut out, lang.text("chart-reject-reasons"), &reason_rows, color)?;
+
+        out.flush()?;
+        Ok(())
+    }
+
+
+
+//* Auxiliary Functions *//
+
+    // Draws one horizontal bar per row, scaled so the largest absolute value fills the chart width.
+    // Negative values are drawn with dashes, and highlighted when colors are enabled.
+    pub(crate) fn write_bar_chart(
+        out : &mut impl Write,
+        title : &str,
+        rows : &[(String, f64)],
+        color : bool,
+    ) -> Result<(), Box<dyn Error>> {
+
+        const WIDTH: f64 = 40.0;
+
+        writeln!(out, "{}", title)?;
+
+        if rows.is_empty() {
+            writeln!(out, "  (none)\n")?;
+            return Ok(());
+        }
+
+        let label_width = rows.iter().map(|(label, _)| visible_len(label)).max().unwrap_or(0);
+        let max_value = rows.iter().map(|(_, value)| value.abs()).fold(0.0f64, f64::max);
+
+        for (label, value) in rows {
+            let length = if max_value > 0.0 {(value.abs() / max_value * WIDTH).round() as usize} else {0};
+            let bar = if *value < 0.0 {"-".repeat(length)} else {"#".repeat(length)};
+            let padding = " ".repeat(label_width - visible_len(label));
+            let bar_padding = " ".repeat(WIDTH as usize - length);
+
+            writeln!(out, "  {}{} | {}{} {}", label, padding, paint_if_negative(&bar, *value, color), bar_padding, paint_amount(*value, color))?;
+        }
+
+        writeln!(out)?;
+        Ok(())
+    }
+
+
+    pub(crate) const RED: &str = "\x1b[31m";
+    pub(crate) const YELLOW: &str = "\x1b[33m";
+    pub(crate) const RESET: &str = "\x1b[0m";
+
+    // Wraps the text in an ANSI color, or leaves it untouched when colors are disabled.
+    pub(crate) fn paint(text : &str, color_code : &str, enabled : bool) -> String {
+        if enabled {format!("{}{}{}", color_code, text, RESET)} else {text.to_string()}
+    }
+
+    pub(crate) fn paint_if_negative(text : &str, amount : f64, enabled : bool) -> String {
+        paint(text, YELLOW, enabled && amount < 0.0)
+    }
+
+    pub(crate) fn paint_amount(amount : f64, enabled : bool) -> String {
+        paint_if_negative(&format_amount(amount), amount, enabled)
+    }
+
+    // Length of the text as displayed, ignoring the ANSI color codes added by paint.
+    pub(crate) fn visible_len(text : &str) -> usize {
+        text.replace(RED, "").replace(YELLOW, "").replace(RESET, "").chars().count()
+    }
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{engine::*, testing::*};
+
+        #[test]
+        fn operator_report_lists_each_account() {
+            let path = temp_file("operator.csv", "");
+            write_operator_report(&path, &OperatorAccounts { escrow: 10.0, losses: 0.12345, fees: 0.0 }).unwrap();
+            let report = fs::read_to_string(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(report, "account,balance\nescrow,10\nlosses,0.1235\nfees,0\n");
+        }
+
+        #[test]
+        fn wallets_report_has_a_row_per_wallet() {
+            let mut client = ClientData::default();
+            client.adjust("main", 10.0, 0.0);
+            client.adjust("bonus", 5.0, 2.0);
+            client.frozen = true;
+
+            let path = temp_file("wallets.csv", "");
+            write_wallets_report(&path, &HashMap::from([(7, client)])).unwrap();
+            let report = fs::read_to_string(&path).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(report, "client,wallet,available,held,total,locked\n7,bonus,5,2,7,true\n7,main,10,0,10,true\n");
+        }
+
+        #[test]
+        fn end_of_day_closes_each_day_as_the_input_moves_past_it() {
+            let report = temp_file("eod.csv", "");
+            let dir = temp_file("eod-dir", "");
+            fs::remove_file(&dir).unwrap();
+
+            let mut staged = StagedOutputs::default();
+            let live = LiveOutputs { eod_report: Some(report.clone()), eod_dir: Some(dir.clone()), ..LiveOutputs::default() };
+            let mut end_of_day = EndOfDay::new(&live, &mut staged).unwrap();
+
+            let mut clients = funded(2, 10.0);
+            end_of_day.advance(parse_timestamp("2024-03-01T10:00:00Z").unwrap(), &clients, &mut staged).unwrap();
+            clients.extend(funded(1, 5.0));
+            end_of_day.advance(parse_timestamp("2024-03-01T23:00:00Z").unwrap(), &clients, &mut staged).unwrap();
+            end_of_day.advance(parse_timestamp("2024-03-03T08:00:00Z").unwrap(), &clients, &mut staged).unwrap();
+            clients.get_mut(&1).unwrap().adjust(DEFAULT_WALLET, -5.0, 0.0);
+            end_of_day.advance(parse_timestamp("2024-03-02T12:00:00Z").unwrap(), &clients, &mut staged).unwrap();
+            end_of_day.close_day(&clients, &mut staged).unwrap();
+            drop(end_of_day);
+            staged.commit().unwrap();
+
+            let days = fs::read_to_string(&report).unwrap();
+            let last_day = fs::read_to_string(format!("{}/2024-03-03.csv", dir)).unwrap();
+            let first_day_exists = fs::exists(format!("{}/2024-03-01.csv", dir)).unwrap();
+            fs::remove_file(report).unwrap();
+            fs::remove_dir_all(dir).unwrap();
+
+            assert_eq!(days, "\
+                date,client,available,held,total,locked\n\
+                2024-03-01,1,5,0,5,false\n\
+                2024-03-01,2,10,0,10,false\n\
+                2024-03-03,1,0,0,0,false\n\
+                2024-03-03,2,10,0,10,false\n\
+            ", "a row dated before the current day doesn't move it back, and days without rows are skipped");
+            assert_eq!(last_day, "client,available,held,total,locked\n1,0,0,0,false\n2,10,0,10,false\n");
+            assert!(first_day_exists);
+        }
+
+        #[test]
+        fn staged_outputs_only_stand_once_committed() {
+            let (committed, discarded, live) = (temp_file("staged-committed.csv", ""), temp_file("staged-discarded.csv", ""), temp_file("staged-live.csv", "balances"));
+            for path in [&committed, &discarded] {
+                fs::remove_file(path).unwrap();
+            }
+
+            let mut staged = StagedOutputs::default();
+            fs::write(staged.stage(&committed), "rows").unwrap();
+            assert!(!fs::exists(&committed).unwrap(), "written under a partial name until committed");
+            staged.commit().unwrap();
+            assert_eq!(fs::read_to_string(&committed).unwrap(), "rows");
+
+            let mut staged = StagedOutputs::default();
+            let partial = staged.stage(&discarded);
+            fs::write(&partial, "rows").unwrap();
+            staged.track(&live);
+            staged.discard();
+            assert_eq!([&partial, &discarded, &live].map(|path| fs::exists(path).unwrap()), [false; 3]);
+            fs::remove_file(committed).unwrap();
+        }
+
+        #[test]
+        fn ranges_are_merged_when_they_overlap_or_touch() {
+            let mut ranges = vec![(10, 12), (1, 3), (4, 4), (11, 15), (20, 20), (2, 2)];
+            merge_ranges(&mut ranges);
+
+            assert_eq!(ranges, [(1, 4), (10, 15), (20, 20)]);
+        }
+
+        #[test]
+        fn run_history_warns_about_ids_mostly_processed_by_one_earlier_run() {
+            let path = temp_file("history.csv", "");
+            fs::remove_file(&path).unwrap();
+            let analysis = |ids : &[TxId]| IdAnalysis { ids: ids.iter().copied().collect(), ..IdAnalysis::default() };
+
+            assert!(record_run_history(&path, "monday.csv", 86_400, &analysis(&[1, 2, 3, 4, 5, 9])).unwrap().is_empty());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "run,file,first,last\n86400,monday.csv,1,5\n86400,monday.csv,9,9\n");
+
+            let half = record_run_history(&path, "tuesday.csv", 2 * 86_400, &analysis(&[3, 4, 5, 6, 7, 8])).unwrap();
+            assert!(half.is_empty(), "half of them isn't more than half");
+
+            let warnings = record_run_history(&path, "monday.csv", 3 * 86_400, &analysis(&[2, 3, 4, 9, 10])).unwrap();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(warnings, ["Warning! 80% of the tx IDs of this run were already processed by the run of 'monday.csv' on 1970-01-02. Was the file sent again?"]);
+        }
+
+        #[test]
+        fn record_buffer_hands_back_the_fields_it_was_formatted_into() {
+            let mut record = RecordBuffer::default();
+            record.push(7).unwrap();
+            record.push("").unwrap();
+            record.push_with(|out| { out.push_str("a;b"); Ok(()) }).unwrap();
+            record.push(round_amount(1.23456)).unwrap();
+
+            assert_eq!(record.fields().collect::<Vec<_>>(), ["7", "", "a;b", "1.2346"]);
+
+            let capacity = record.text.capacity();
+            record.clear();
+            record.push(8).unwrap();
+            assert_eq!((record.fields().collect::<Vec<_>>(), record.text.capacity()), (vec!["8"], capacity), "the buffer is reused");
+        }
+
+        #[test]
+        fn csv_sink_writes_each_account_from_the_buffer() {
+            let mut client = ClientData::default();
+            client.adjust("main", 10.123456, 2.5);
+            client.locks.insert(LockReason::OpenDispute(3));
+            client.owners.insert(9);
+            client.stats.insufficient_funds = 2;
+            let meta = HashMap::from([(1, ClientMeta { name: "Doe, Jane".to_string(), country: "PT".to_string(), segment: String::new() })]);
+
+            let mut sink = CsvSink::new(Vec::new(), true, Some(&meta)).unwrap();
+            sink.write_account(1, &client).unwrap();
+            let capacity = sink.record.text.capacity();
+            sink.write_account(1, &client).unwrap();
+            assert_eq!(sink.record.text.capacity(), capacity, "the second account reuses the buffer of the first");
+
+            let row = "1,10.1235,2.5,12.6235,true,dispute:3,1;9,2,0,0,\"Doe, Jane\",PT,\n";
+            let report = String::from_utf8(sink.writer.into_inner().unwrap()).unwrap();
+            assert_eq!(report, format!("client,available,held,total,locked,lock_reasons,owners,rejected_insufficient_funds,rejected_locked,duplicate_ids,name,country,segment\n{}{}", row, row));
+        }
+
+        #[test]
+        fn live_deltas_only_have_the_accounts_that_changed_since_the_last_write() {
+            let path = temp_file("live.csv", "");
+            fs::remove_file(&path).unwrap();
+            let options = Options { live: LiveOutputs { report: Some(path.clone()), every: LiveInterval::Rows(2), delta: true, ..LiveOutputs::default() }, ..Options::default() };
+
+            let input = rows("type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\ndeposit,1,3,1\ndeposit,3,4,2\ndeposit,2,5,1\n");
+            let mut staged = StagedOutputs::default();
+            process_transactions(&options, &mut staged, &mut Rows(input.into()), HashMap::new(), &mut [], &mut []).unwrap();
+
+            let parts: Vec<String> = (1..=3).map(|part| fs::read_to_string(numbered_path(&path, part)).unwrap()).collect();
+            assert!(!fs::exists(numbered_path(&path, 4)).unwrap());
+            staged.discard();
+
+            let header = "client,available,held,total,locked\n";
+            assert_eq!(parts, [
+                format!("{}1,10,0,10,false\n2,5,0,5,false\n", header),
+                format!("{}1,11,0,11,false\n3,2,0,2,false\n", header),
+                format!("{}2,6,0,6,false\n", header),
+            ]);
+        }
+
+        #[test]
+        fn live_report_is_replaced_as_a_whole() {
+            let path = temp_file("live-full.csv", "");
+            let options = Options { live: LiveOutputs { report: Some(path.clone()), every: LiveInterval::Rows(2), ..LiveOutputs::default() }, ..Options::default() };
+
+            let input = rows("type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\nwithdrawal,1,3,4\n");
+            let mut staged = StagedOutputs::default();
+            process_transactions(&options, &mut staged, &mut Rows(input.into()), HashMap::new(), &mut [], &mut []).unwrap();
+            let report = fs::read_to_string(&path).unwrap();
+
+            assert!(!fs::exists(format!("{}.tmp", path)).unwrap());
+            assert_eq!(report, "client,available,held,total,locked\n1,6,0,6,false\n2,5,0,5,false\n", "the last write is after the end of the input");
+            staged.discard();
+            assert!(!fs::exists(&path).unwrap(), "a failed run removes it");
+        }
+
+        #[test]
+        fn diff_lists_the_fields_that_changed_and_the_accounts_of_one_report_only() {
+            let mut before = funded(1, 10.0);
+            before.extend(funded(2, 5.0));
+            let mut after = funded(1, 10.00001); // the same at the 4 decimals of the report
+            after.extend(funded(3, 1.0));
+            let mut locked = ClientData { frozen: true, ..ClientData::default() };
+            locked.adjust(DEFAULT_WALLET, 4.0, 1.0);
+            after.insert(2, locked);
+
+            let change = |client : ClientId, field : &'static str, before : &str, after : &str| FieldChange { client, field, before: before.to_string(), after: after.to_string() };
+            assert_eq!(diff_reports(&before, &after), vec![
+                change(2, "available", "5", "4"),
+                change(2, "held", "0", "1"),
+                change(2, "locked", "false", "true"),
+                change(3, "available", "", "1"),
+                change(3, "held", "", "0"),
+                change(3, "total", "", "1"),
+                change(3, "locked", "", "false"),
+            ]);
+            assert!(diff_reports(&after, &after).is_empty());
+        }
+    }
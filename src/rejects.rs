@@ -0,0 +1,198 @@
+// Why rows are rejected: the errors and reject codes returned by the checks and handlers.
+
+use std::error::Error;
+use crate::{lang::*, types::*};
+
+
+
+//* Structs *//
+
+    // A transaction held back by a rule that wants a human to look at it, rather than a plain rejection.
+    // These are listed in the "--flags-report".
+    #[derive(Debug)]
+    pub(crate) struct FlaggedForReview {
+        pub(crate) code: RejectCode,
+        pub(crate) reason: String,
+    }
+
+    impl std::fmt::Display for FlaggedForReview {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "Flagged for review: {}", self.reason)
+        }
+    }
+
+    impl Error for FlaggedForReview {}
+
+
+    // Rejection of any transaction whose client is on the "--blocklist", regardless of the account's state.
+    // The client ID is left out of the message, so the rejections are counted per reason and logs can be redacted.
+    #[derive(Debug)]
+    pub(crate) struct BlockedClient {
+        pub(crate) reason: String,
+    }
+
+    impl std::fmt::Display for BlockedClient {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "Error! Client is blocklisted ({}). Ignoring.", self.reason)
+        }
+    }
+
+    impl Error for BlockedClient {}
+
+
+    // Rejection of a row whose type isn't known to the engine, so it can be told apart from the business rejections.
+    #[derive(Debug)]
+    pub(crate) struct UnknownType;
+
+    impl std::fmt::Display for UnknownType {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "Error! Transaction type is invalid. Ignoring.")
+        }
+    }
+
+    impl Error for UnknownType {}
+
+
+    // A row the source couldn't decode. Skipped and counted apart from the rejections, unless in strict mode.
+    #[derive(Debug)]
+    pub(crate) struct MalformedRow {
+        pub(crate) error: csv::Error,
+        pub(crate) origin: Option<RowOrigin>,
+    }
+
+    impl std::fmt::Display for MalformedRow {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "{}", self.error)
+        }
+    }
+
+    impl Error for MalformedRow {}
+
+
+    // Raised under the "error" race policy when a dispute is both resolved and charged back. It ends the run.
+    #[derive(Debug)]
+    pub(crate) struct DisputeRace {
+        pub(crate) tx_id: TxId,
+    }
+
+    impl std::fmt::Display for DisputeRace {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "Error! The dispute of transaction {} was both resolved and charged back.", self.tx_id)
+        }
+    }
+
+    impl Error for DisputeRace {}
+
+
+    // A stable code for a rejection reason, so alerting can match on it instead of on the message, which may change.
+    // Codes are never reused: a reason that goes away keeps its code retired.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub(crate) struct RejectCode {
+        pub(crate) id: &'static str,
+        pub(crate) name: &'static str,
+    }
+
+    impl std::fmt::Display for RejectCode {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "{} {}", self.id, self.name)
+        }
+    }
+
+    pub(crate) const fn code(id : &'static str, name : &'static str) -> RejectCode {
+        RejectCode { id, name }
+    }
+
+    // Grouped as E1xxx the row itself, E2xxx the account, E3xxx limits and rules, E4xxx disputes and refunds, E5xxx holds.
+    pub(crate) const DUPLICATE_TX: RejectCode = code("E1001", "DUPLICATE_TX");
+    pub(crate) const UNKNOWN_TYPE: RejectCode = code("E1002", "UNKNOWN_TYPE");
+    pub(crate) const MISSING_AMOUNT: RejectCode = code("E1003", "MISSING_AMOUNT");
+    pub(crate) const NON_POSITIVE_AMOUNT: RejectCode = code("E1004", "NON_POSITIVE_AMOUNT");
+    pub(crate) const UNRECOGNIZED_COLUMNS: RejectCode = code("E1005", "UNRECOGNIZED_COLUMNS");
+    pub(crate) const MISSING_TIMESTAMP: RejectCode = code("E1006", "MISSING_TIMESTAMP");
+    pub(crate) const TX_PRUNED: RejectCode = code("E1007", "TX_PRUNED");
+    pub(crate) const INVALID_RECURRENCE: RejectCode = code("E1008", "INVALID_RECURRENCE");
+    pub(crate) const UNKNOWN_SAVEPOINT: RejectCode = code("E1009", "UNKNOWN_SAVEPOINT");
+    pub(crate) const PAST_AS_OF: RejectCode = code("E1010", "PAST_AS_OF");
+    pub(crate) const TIMESTAMP_OUT_OF_RANGE: RejectCode = code("E1011", "TIMESTAMP_OUT_OF_RANGE");
+    pub(crate) const ADMIN_ONLY: RejectCode = code("E1012", "ADMIN_ONLY");
+    pub(crate) const NOT_ADMIN: RejectCode = code("E1013", "NOT_ADMIN");
+    pub(crate) const ACCOUNT_LOCKED: RejectCode = code("E2001", "ACCOUNT_LOCKED");
+    pub(crate) const UNKNOWN_ACCOUNT: RejectCode = code("E2002", "UNKNOWN_ACCOUNT");
+    pub(crate) const INSUFFICIENT_FUNDS: RejectCode = code("E2003", "INSUFFICIENT_FUNDS");
+    pub(crate) const ALREADY_FROZEN: RejectCode = code("E2004", "ALREADY_FROZEN");
+    pub(crate) const NOT_FROZEN: RejectCode = code("E2005", "NOT_FROZEN");
+    pub(crate) const NO_DISPUTE_LOCKS: RejectCode = code("E2006", "NO_DISPUTE_LOCKS");
+    pub(crate) const INVALID_JOINT_ACCOUNT: RejectCode = code("E2007", "INVALID_JOINT_ACCOUNT");
+    pub(crate) const AMOUNT_OVER_MAX: RejectCode = code("E3001", "AMOUNT_OVER_MAX");
+    pub(crate) const CLIENT_DENIED: RejectCode = code("E3002", "CLIENT_DENIED");
+    pub(crate) const TIER_LIMIT: RejectCode = code("E3003", "TIER_LIMIT");
+    pub(crate) const DAILY_LIMIT: RejectCode = code("E3004", "DAILY_LIMIT");
+    pub(crate) const RESERVE_REQUIRED: RejectCode = code("E3005", "RESERVE_REQUIRED");
+    pub(crate) const KYC_DEPOSIT_CAP: RejectCode = code("E3006", "KYC_DEPOSIT_CAP");
+    pub(crate) const KYC_WITHDRAWAL: RejectCode = code("E3007", "KYC_WITHDRAWAL");
+    pub(crate) const BLOCKLISTED: RejectCode = code("E3008", "BLOCKLISTED");
+    pub(crate) const FLAGGED_FOR_REVIEW: RejectCode = code("E3009", "FLAGGED_FOR_REVIEW");
+    pub(crate) const TX_NOT_FOUND: RejectCode = code("E4001", "TX_NOT_FOUND");
+    pub(crate) const CLIENT_MISMATCH: RejectCode = code("E4002", "CLIENT_MISMATCH");
+    pub(crate) const ALREADY_DISPUTED: RejectCode = code("E4003", "ALREADY_DISPUTED");
+    pub(crate) const NOT_DISPUTED: RejectCode = code("E4004", "NOT_DISPUTED");
+    pub(crate) const NOT_CHARGED_BACK: RejectCode = code("E4005", "NOT_CHARGED_BACK");
+    pub(crate) const TX_SETTLED: RejectCode = code("E4006", "TX_SETTLED");
+    pub(crate) const WITHDRAWAL_NOT_DISPUTABLE: RejectCode = code("E4007", "WITHDRAWAL_NOT_DISPUTABLE");
+    pub(crate) const REVERSAL_WINDOW: RejectCode = code("E4008", "REVERSAL_WINDOW");
+    pub(crate) const ORPHANED_TX: RejectCode = code("E4009", "ORPHANED_TX");
+    pub(crate) const INVALID_REFUND: RejectCode = code("E4010", "INVALID_REFUND");
+    pub(crate) const DISPUTE_SHORTFALL: RejectCode = code("E4011", "DISPUTE_SHORTFALL");
+    pub(crate) const DISPUTE_CAPPED: RejectCode = code("E4012", "DISPUTE_CAPPED"); // applied, but holding less than it asked for
+    pub(crate) const HOLD_NOT_FOUND: RejectCode = code("E5001", "HOLD_NOT_FOUND");
+    pub(crate) const HOLD_EXPIRED: RejectCode = code("E5002", "HOLD_EXPIRED");
+    pub(crate) const HOLD_SETTLED: RejectCode = code("E5003", "HOLD_SETTLED");
+    pub(crate) const INVALID_CAPTURE: RejectCode = code("E5004", "INVALID_CAPTURE");
+    pub(crate) const UNCLASSIFIED: RejectCode = code("E9999", "UNCLASSIFIED");
+
+    // A rejection by a handler or a rule, carrying its code so nothing has to be recovered from the wording of the message.
+    #[derive(Debug)]
+    pub(crate) struct Rejection {
+        pub(crate) code: RejectCode,
+        pub(crate) message: std::borrow::Cow<'static, str>,
+    }
+
+    impl Rejection {
+        pub(crate) fn new(code : RejectCode, message : impl Into<std::borrow::Cow<'static, str>>) -> Self {
+            Rejection { code, message: message.into() }
+        }
+    }
+
+    impl std::fmt::Display for Rejection {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(formatter, "{}", self.message)
+        }
+    }
+
+    impl Error for Rejection {}
+
+    // Every rejection carries its code, either as a "Rejection" or as its own error type.
+    pub(crate) fn reject_code(reason : &(dyn Error + 'static)) -> RejectCode {
+        if let Some(rejection) = reason.downcast_ref::<Rejection>() {
+            return rejection.code;
+        }
+        if reason.is::<UnknownType>() {
+            return UNKNOWN_TYPE;
+        }
+        if reason.is::<BlockedClient>() {
+            return BLOCKLISTED;
+        }
+        if let Some(flag) = reason.downcast_ref::<FlaggedForReview>() {
+            return flag.code;
+        }
+        UNCLASSIFIED
+    }
+
+    // The message of a rejection in the given language, by its code. Untranslated ones keep their original message.
+    pub(crate) fn reject_message(lang : Lang, reason : &(dyn Error + 'static)) -> String {
+        let code = reject_code(reason);
+        match (lang, code == UNCLASSIFIED) {
+            (Lang::En, _) | (_, true) => reason.to_string(),
+            _ => lang.lookup(code.id).map_or_else(|| reason.to_string(), str::to_string),
+        }
+    }
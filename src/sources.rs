@@ -0,0 +1,870 @@
+// Where the rows come from, and how they're decoded.
+
+use std::{error::Error, fs, sync::{mpsc, Arc}, thread, collections::{BTreeMap, HashMap}, time::{Duration, Instant}};
+use csv::Trim;
+use crate::{clock::*, observers::*, options::*, rejects::*, types::*};
+
+
+
+//* Control Totals *//
+
+    // Enabled by "--control", adds up the rows of each transaction type, applied or rejected, to check them against the control file.
+    // The rows are counted as they're read, under the type written in the file, so it's the partner's file that's checked:
+    // aliases aren't applied yet, and a recurring definition counts once, not as the occurrences it expands to.
+    #[derive(Default)]
+    pub(crate) struct ControlTotals {
+        pub(crate) actual: BTreeMap<String, (u64, f64)>, // type -> (rows, sum of their amounts)
+    }
+
+    // Counts the rows of a source into the control totals as they go through.
+    pub(crate) struct ControlCount<'a> {
+        pub(crate) rows: &'a mut dyn InputSource,
+        pub(crate) totals: &'a mut ControlTotals,
+    }
+
+    impl ControlTotals {
+        pub(crate) fn record(&mut self, transaction : &Transaction) {
+            let (count, amount) = self.actual.entry(transaction.tx_type.clone()).or_default();
+            *count += 1;
+            *amount += transaction.amount.unwrap_or(0.0);
+        }
+
+        // What doesn't match the expected totals, empty when everything does. Types missing from the control file aren't checked.
+        pub(crate) fn mismatches(&self, expected : &BTreeMap<String, ControlTotal>) -> Vec<String> {
+            let mut mismatches = Vec::new();
+
+            for (tx_type, expected) in expected {
+                let (count, amount) = self.actual.get(tx_type).copied().unwrap_or_default();
+                if count != expected.count {
+                    mismatches.push(format!("Expected {} {} rows, read {}.", expected.count, tx_type, count));
+                }
+                if let Some(expected_amount) = expected.amount.filter(|expected_amount| (amount - expected_amount).abs() > 0.00005) {
+                    mismatches.push(format!(
+                        "Expected {} rows adding up to {}, read {}.",
+                        tx_type,
+                        format_amount(expected_amount),
+                        format_amount(amount),
+                    ));
+                }
+            }
+
+            mismatches
+        }
+    }
+
+    impl InputSource for ControlCount<'_> {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            let row = self.rows.next_transaction();
+            if let Some(Ok(transaction)) = &row {
+                self.totals.record(transaction);
+            }
+            row
+        }
+    }
+
+
+
+//* Deserialization *//
+
+    // Partners add columns without warning, so instead of relying on the derived implementation
+    // every column that isn't part of the transaction is collected into "extra_fields".
+    // Whether those are acceptable is decided later (see "--strict"), not by the parser.
+    impl<'de> serde::Deserialize<'de> for Transaction {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer : D) -> Result<Self, D::Error> {
+            deserializer.deserialize_map(TransactionVisitor)
+        }
+    }
+
+
+    pub(crate) struct TransactionVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for TransactionVisitor {
+        type Value = Transaction;
+
+        fn expecting(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a transaction row with type, client, tx and amount columns")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map : A) -> Result<Transaction, A::Error> {
+            use serde::de::Error as _;
+
+            let mut tx_id = None;
+            let mut tx_type = None;
+            let mut client_id = None;
+            let mut amount = None;
+            let mut timestamp = None;
+            let mut value_date = None;
+            let mut wallet = None;
+            let mut owners = Vec::new();
+            let (mut kind, mut interval, mut count) = (None, None, None);
+            let mut extra_fields = HashMap::new();
+
+            while let Some(column) = map.next_key::<String>()? {
+                match column.as_str() {
+                    "tx" => tx_id = Some(map.next_value::<TxId>()?),
+                    "type" => tx_type = Some(map.next_value::<String>()?.to_lowercase()),
+                    "client" => client_id = Some(map.next_value::<ClientId>()?),
+                    "amount" => {
+                        amount = map.next_value::<Option<f64>>()?;
+                        // NaN compares false to every limit and infinity isn't an amount, so neither can reach the handlers
+                        if amount.is_some_and(|value| !value.is_finite()) {
+                            return Err(A::Error::custom("amount is not a finite number"));
+                        }
+                    },
+                    "timestamp" => {
+                        let value = map.next_value::<String>()?;
+                        if !value.is_empty() {
+                            timestamp = Some(parse_timestamp(&value).ok_or_else(|| A::Error::custom(format!("invalid timestamp '{}'", value)))?);
+                        }
+                    },
+                    "value_date" => {
+                        let value = map.next_value::<String>()?;
+                        if !value.is_empty() {
+                            value_date = Some(parse_timestamp(&value).ok_or_else(|| A::Error::custom(format!("invalid value date '{}'", value)))?);
+                        }
+                    },
+                    "owners" => {
+                        let value = map.next_value::<String>()?;
+                        for owner in value.split(';').map(str::trim).filter(|owner| !owner.is_empty()) {
+                            owners.push(owner.parse().map_err(|_| A::Error::custom(format!("invalid owner '{}'", owner)))?);
+                        }
+                    },
+                    "kind" => kind = Some(map.next_value::<String>()?.to_lowercase()).filter(|value| !value.is_empty()),
+                    "interval" => interval = map.next_value::<Option<i64>>()?,
+                    "count" => count = map.next_value::<Option<TxId>>()?,
+                    "wallet" => wallet = Some(map.next_value::<String>()?).filter(|value| !value.is_empty()),
+                    _ => {
+                        let value = map.next_value::<String>()?;
+                        extra_fields.insert(column, value);
+                    },
+                }
+            }
+
+            Ok(Transaction {
+                tx_id: tx_id.ok_or_else(|| A::Error::missing_field("tx"))?,
+                tx_type: tx_type.ok_or_else(|| A::Error::missing_field("type"))?,
+                client_id: client_id.ok_or_else(|| A::Error::missing_field("client"))?,
+                amount,
+                timestamp,
+                value_date,
+                wallet: wallet.unwrap_or_else(|| DEFAULT_WALLET.to_string()),
+                owners,
+                recurrence: kind.zip(interval).zip(count).map(|((kind, interval), count)| Recurrence { kind, interval, count }),
+                dispute_status: DisputeStatus::default(),
+                refunded: 0.0,
+                held: 0.0,
+                shortfall: 0.0,
+                disputed_at: None,
+                charged_back_at: None,
+                extra_fields,
+                origin: None,
+                admin: false,
+            })
+        }
+    }
+
+
+
+    // Reads the fields of a JSON object as the outbox writes its events: strings unescaped, null as None, and numbers,
+    // booleans and nested objects as they're written. None when the line isn't a single object.
+    pub(crate) fn read_json_fields(line : &str) -> Option<HashMap<String, Option<String>>> {
+        let mut chars = line.chars().peekable();
+        let mut fields = HashMap::new();
+
+        skip_whitespace(&mut chars);
+        if chars.next()? != '{' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        if chars.peek() == Some(&'}') {
+            chars.next();
+        } else {
+            loop {
+                skip_whitespace(&mut chars);
+                let key = read_json_string(&mut chars)?;
+                skip_whitespace(&mut chars);
+                if chars.next()? != ':' {
+                    return None;
+                }
+                skip_whitespace(&mut chars);
+
+                let value = match chars.peek()? {
+                    '"' => Some(read_json_string(&mut chars)?),
+                    '{' | '[' => Some(read_json_nested(&mut chars)?),
+                    _ => {
+                        let mut raw = String::new();
+                        while let Some(character) = chars.next_if(|c| !matches!(c, ',' | '}') && !c.is_whitespace()) {
+                            raw.push(character);
+                        }
+                        match raw.as_str() {
+                            "" => return None,
+                            "null" => None,
+                            _ => Some(raw),
+                        }
+                    },
+                };
+                fields.insert(key, value);
+
+                skip_whitespace(&mut chars);
+                match chars.next()? {
+                    ',' => continue,
+                    '}' => break,
+                    _ => return None,
+                }
+            }
+        }
+
+        skip_whitespace(&mut chars);
+        chars.next().is_none().then_some(fields)
+    }
+
+
+    type JsonChars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+    fn skip_whitespace(chars : &mut JsonChars) {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn read_json_string(chars : &mut JsonChars) -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+
+        let mut value = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(value),
+                '\\' => value.push(match chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => char::from_u32(u32::from_str_radix(&(0..4).map(|_| chars.next()).collect::<Option<String>>()?, 16).ok()?)?,
+                    escaped => escaped, // '"', '\\' and '/'
+                }),
+                character => value.push(character),
+            }
+        }
+    }
+
+    // Kept as written, brackets and all, since none of the fields read back from an event are nested.
+    fn read_json_nested(chars : &mut JsonChars) -> Option<String> {
+        let mut raw = String::new();
+        let mut depth = 0;
+        loop {
+            match *chars.peek()? {
+                '"' => raw.push_str(&json_string(&read_json_string(chars)?)),
+                character => {
+                    chars.next();
+                    raw.push(character);
+                    match character {
+                        '{' | '[' => depth += 1,
+                        '}' | ']' => depth -= 1,
+                        _ => {},
+                    }
+                    if depth == 0 {
+                        return Some(raw);
+                    }
+                },
+            }
+        }
+    }
+
+
+//* Sources *//
+
+    // Reads the balances of a report written by a previous run, in any of the CSV formats, for "merge".
+    // The funds of an account go to its main wallet, since the report doesn't split them, and a locked account
+    // is frozen, since the disputes behind its locks aren't known anymore. Co-owners aren't restored either.
+    pub(crate) fn load_report(path : &str) -> Result<HashMap<ClientId, ClientData>, Box<dyn Error>> {
+
+        #[derive(serde::Deserialize)]
+        struct ReportEntry {
+            client: ClientId,
+            available: f64,
+            held: f64,
+            total: f64,
+            locked: bool,
+        }
+
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)
+            .map_err(|e| format!("Error! Could not read report '{}': {}", path, e))?;
+
+        let mut client_data = HashMap::new();
+
+        for row in reader.deserialize() {
+            let entry: ReportEntry = row?;
+            // the report rounds each balance to 4 decimals on its own, so they can be a unit of the last one apart
+            if round_amount(entry.available + entry.held - entry.total).abs() > 0.0001 {
+                return Err(format!("Error! The balances of client {} in report '{}' don't add up.", entry.client, path).into());
+            }
+
+            let mut client = ClientData { frozen: entry.locked, ..ClientData::default() };
+            client.adjust(DEFAULT_WALLET, entry.available, entry.held);
+            client_data.insert(entry.client, client);
+        }
+
+        Ok(client_data)
+    }
+
+
+    // A report only has the balances, not the disputes, holds and locks behind them, so "merge" can only start from accounts
+    // that have none: a locked account or held funds couldn't be released or charged back afterwards.
+    pub(crate) fn check_settled(
+        path : &str,
+        client_data : HashMap<ClientId, ClientData>,
+    ) -> Result<HashMap<ClientId, ClientData>, Box<dyn Error>> {
+
+        let mut unsettled: Vec<&ClientId> = client_data.iter().filter(|(_, client)| client.is_locked() || client.held != 0.0).map(|(client_id, _)| client_id).collect();
+        unsettled.sort();
+
+        match unsettled.first() {
+            Some(client_id) => Err(format!(
+                "Error! Report '{}' has {} locked accounts or accounts with funds held, starting with client {}. The disputes and holds behind them aren't in the report, so they must be settled before merging.",
+                path, unsettled.len(), client_id,
+            ).into()),
+            None => Ok(client_data),
+        }
+    }
+
+
+    // An applied event of an outbox, with the balances and lock of its client after it, and the line it was read from.
+    pub(crate) struct OutboxEvent {
+        pub(crate) client_id: ClientId,
+        pub(crate) client: ClientData,
+        pub(crate) line: String,
+    }
+
+
+    // Reads back the applied events of an outbox, for "rebuild" and "compact".
+    // As with "merge", the funds go to the main wallet and a locked account is frozen.
+    pub(crate) fn read_outbox(path : &str) -> Result<Vec<OutboxEvent>, Box<dyn Error>> {
+        let lines = fs::read_to_string(path).map_err(|e| format!("Error! Could not read outbox '{}': {}", path, e))?;
+
+        let mut events = Vec::new();
+
+        for (i, line) in lines.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let invalid = || format!("Error! Line {} of outbox '{}' isn't an event with the balances of its client.", i + 1, path);
+            let fields = read_json_fields(line).ok_or_else(invalid)?;
+            // a rejected row changes nothing, and its client may not even have an account
+            if fields.get("outcome").cloned().flatten().as_deref() == Some("rejected") {
+                continue;
+            }
+            let field = |name : &str| fields.get(name).cloned().flatten().ok_or_else(invalid);
+            let amount = |name : &str| field(name)?.parse::<f64>().map_err(|_| invalid());
+
+            let client_id: ClientId = field("client")?.parse().map_err(|_| invalid())?;
+            let locked: bool = field("locked")?.parse().map_err(|_| invalid())?;
+            let (available, held, total) = (amount("available")?, amount("held")?, amount("total")?);
+            if round_amount(available + held - total).abs() > 0.0001 {
+                return Err(format!("Error! The balances of client {} on line {} of outbox '{}' don't add up.", client_id, i + 1, path).into());
+            }
+
+            let mut client = ClientData { frozen: locked, ..ClientData::default() };
+            client.adjust(DEFAULT_WALLET, available, held);
+            events.push(OutboxEvent { client_id, client, line: line.to_string() });
+        }
+
+        Ok(events)
+    }
+
+
+    // Rebuilds the balances of every account for "rebuild". Each event carries the balances of its client after it,
+    // so the last one of each client has them as they were at the end of the run. Leaving out the "undone" last events
+    // gives them as they were before those, and an account opened by them isn't rebuilt.
+    pub(crate) fn load_outbox(path : &str, undone : usize) -> Result<HashMap<ClientId, ClientData>, Box<dyn Error>> {
+        let mut events = read_outbox(path)?;
+        if undone > events.len() {
+            return Err(format!("Error! Outbox '{}' only has {} applied events, {} can't be undone.", path, events.len(), undone).into());
+        }
+        events.truncate(events.len() - undone);
+
+        Ok(events.into_iter().map(|event| (event.client_id, event.client)).collect())
+    }
+
+
+    // Keeps the last event of each client for "compact", in the order they were published,
+    // which is all "rebuild" needs to reproduce the balances.
+    pub(crate) fn compact_outbox(events : Vec<OutboxEvent>) -> Vec<OutboxEvent> {
+        let last: HashMap<ClientId, usize> = events.iter().enumerate().map(|(i, event)| (event.client_id, i)).collect();
+        events.into_iter().enumerate().filter(|(i, event)| last[&event.client_id] == *i).map(|(_, event)| event).collect()
+    }
+
+
+    // Where the transactions come from. The engine only ever asks for the next one, so other formats or transports
+    // can be plugged in by implementing this over their own decoder. "CsvSource" is the default.
+    // A row that can't be decoded is reported as a "MalformedRow" error, which the engine skips. Any other error ends the run.
+    pub(crate) type SourceError = Box<dyn Error + Send + Sync>; // sendable, so sources can run on another thread
+
+    pub(crate) trait InputSource {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>>;
+    }
+
+    impl<S: InputSource + ?Sized> InputSource for &mut S {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            (**self).next_transaction()
+        }
+    }
+
+
+    // Reads the transactions from a CSV file, trimming the whitespace around every field.
+    // The untrimmed record is kept as the row's origin.
+    pub(crate) struct CsvSource {
+        pub(crate) reader: csv::Reader<fs::File>,
+        pub(crate) headers: csv::StringRecord,
+        pub(crate) path: Arc<str>,
+    }
+
+    impl CsvSource {
+        pub(crate) fn open(path : &str) -> Result<Self, Box<dyn Error>> {
+            let mut reader = csv::ReaderBuilder::new().trim(Trim::Headers).from_path(path)
+                .map_err(|e| format!("Error! Could not read '{}': {}", path, e))?;
+            let headers = reader.headers()?.clone();
+
+            Ok(CsvSource { reader, headers, path: path.into() })
+        }
+    }
+
+    impl InputSource for CsvSource {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            let mut record = csv::StringRecord::new();
+
+            match self.reader.read_record(&mut record) {
+                Err(e) if e.is_io_error() => Some(Err(e.into())),
+                Err(e) => {
+                    let origin = e.position().map(|position| RowOrigin { file: self.path.clone(), line: position.line(), byte: position.byte(), record });
+                    Some(Err(MalformedRow { error: e, origin }.into()))
+                },
+                Ok(false) => None,
+                Ok(true) => {
+                    let mut trimmed = record.clone(); // remove whitespaces
+                    trimmed.trim();
+
+                    let (line, byte) = record.position().map_or((0, 0), |position| (position.line(), position.byte()));
+                    let origin = RowOrigin { file: self.path.clone(), line, byte, record };
+
+                    match trimmed.deserialize::<Transaction>(Some(&self.headers)) {
+                        Ok(mut transaction) => {
+                            transaction.origin = Some(origin);
+                            Some(Ok(transaction))
+                        },
+                        Err(e) => Some(Err(MalformedRow { error: e, origin: Some(origin) }.into())),
+                    }
+                },
+            }
+        }
+    }
+
+
+    // Interleaves the rows of the "--admin-input" file with those of the input, going by their timestamps: an admin row is applied
+    // right before the first input row timestamped after it. Undated admin rows follow the admin row before them, or lead the run.
+    // The admin rows left when the input runs out are applied at the end.
+    pub(crate) struct AdminMerge<S> {
+        pub(crate) rows: S,
+        pub(crate) admin: CsvSource,
+        pub(crate) next_row: Option<Result<Transaction, SourceError>>,
+        pub(crate) next_admin: Option<Result<Transaction, SourceError>>,
+    }
+
+    impl<S: InputSource> AdminMerge<S> {
+        pub(crate) fn new(rows : S, admin : CsvSource) -> Self {
+            AdminMerge { rows, admin, next_row: None, next_admin: None }
+        }
+    }
+
+    impl<S: InputSource> InputSource for AdminMerge<S> {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            if self.next_admin.is_none() {
+                self.next_admin = self.admin.next_transaction().map(|row| row.map(|transaction| Transaction { admin: true, ..transaction }));
+            }
+            if self.next_row.is_none() {
+                self.next_row = self.rows.next_transaction();
+            }
+
+            let admin_first = match (&self.next_admin, &self.next_row) {
+                (None, _) => false,
+                (Some(_), None) | (Some(Err(_)), _) => true,
+                (Some(Ok(_)), Some(Err(_))) => false,
+                (Some(Ok(admin)), Some(Ok(row))) => match admin.timestamp {
+                    Some(at) => row.timestamp.is_some_and(|timestamp| timestamp > at),
+                    None => true,
+                },
+            };
+
+            match admin_first {
+                true => self.next_admin.take(),
+                false => self.next_row.take(),
+            }
+        }
+    }
+
+
+    // Runs another source on its own thread, so parsing overlaps with the handlers instead of serializing with them.
+    // Rows are sent in batches, which keeps the channel overhead off the rows themselves. The channel is bounded to
+    // "--read-ahead" batches, so the parser can't run arbitrarily far ahead of the engine.
+    pub(crate) struct ThreadedSource {
+        pub(crate) batches: mpsc::Receiver<Vec<Result<Transaction, SourceError>>>,
+        pub(crate) batch: std::vec::IntoIter<Result<Transaction, SourceError>>,
+    }
+
+    impl ThreadedSource {
+        pub(crate) fn spawn<S: InputSource + Send + 'static>(mut source : S, batch_size : BatchSize, read_ahead : usize, verbose : bool) -> Self {
+            let (sender, batches) = mpsc::sync_channel(read_ahead);
+
+            thread::spawn(move || {
+                let mut tuner = BatchTuner::new(batch_size);
+
+                loop {
+                    let size = tuner.size();
+                    let started = Instant::now();
+
+                    let mut batch = Vec::with_capacity(size);
+                    while batch.len() < size {
+                        match source.next_transaction() {
+                            Some(row) => batch.push(row),
+                            None => break,
+                        }
+                    }
+
+                    let (rows, last) = (batch.len(), batch.len() < size);
+                    if rows == 0 || sender.send(batch).is_err() {
+                        break; // the input ended, or the engine stopped reading
+                    }
+
+                    // the time includes waiting for room in the channel, so a slow engine counts too
+                    if let Some(tuned) = tuner.record(rows, started.elapsed()) {
+                        if verbose {
+                            eprintln!("batch size auto-tuned to {} rows", tuned);
+                        }
+                    }
+                    if last {
+                        break;
+                    }
+                }
+            });
+
+            ThreadedSource { batches, batch: Vec::new().into_iter() }
+        }
+    }
+
+    impl InputSource for ThreadedSource {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            loop {
+                if let Some(row) = self.batch.next() {
+                    return Some(row);
+                }
+                self.batch = self.batches.recv().ok()?.into_iter();
+            }
+        }
+    }
+
+
+    // Under "--batch-size auto", tries each candidate size over its share of the first AUTO_TUNE_ROWS rows,
+    // and keeps the one with the highest throughput for the rest of the input.
+    pub(crate) const AUTO_TUNE_ROWS : usize = 1_000_000;
+    pub(crate) const AUTO_TUNE_CANDIDATES : [usize; 5] = [16, 64, 256, 1024, 4096];
+
+    pub(crate) struct BatchTuner {
+        pub(crate) size: usize,
+        pub(crate) trials: Option<Vec<(usize, Duration)>>, // rows sent and time taken per candidate, until one is picked
+    }
+
+    impl BatchTuner {
+        pub(crate) fn new(batch_size : BatchSize) -> Self {
+            match batch_size {
+                BatchSize::Fixed(size) => BatchTuner { size, trials: None },
+                BatchSize::Auto => BatchTuner { size: AUTO_TUNE_CANDIDATES[0], trials: Some(Vec::new()) },
+            }
+        }
+
+        pub(crate) fn size(&self) -> usize {
+            self.size
+        }
+
+        // Accounts for a batch, returning the picked size once the trials are over.
+        pub(crate) fn record(&mut self, rows : usize, elapsed : Duration) -> Option<usize> {
+            let trials = self.trials.as_mut()?;
+
+            let candidate = AUTO_TUNE_CANDIDATES.iter().position(|size| *size == self.size)?;
+            if trials.len() <= candidate {
+                trials.push((0, Duration::ZERO));
+            }
+            trials[candidate].0 += rows;
+            trials[candidate].1 += elapsed;
+
+            if trials[candidate].0 < AUTO_TUNE_ROWS / AUTO_TUNE_CANDIDATES.len() {
+                return None;
+            }
+            if let Some(next) = AUTO_TUNE_CANDIDATES.get(candidate + 1) {
+                self.size = *next;
+                return None;
+            }
+
+            let throughput = |(rows, elapsed) : &(usize, Duration)| *rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+            let best = trials.iter().enumerate().max_by(|(_, a), (_, b)| throughput(a).total_cmp(&throughput(b))).map_or(0, |(i, _)| i);
+
+            self.size = AUTO_TUNE_CANDIDATES[best];
+            self.trials = None;
+            Some(self.size)
+        }
+    }
+
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{checks::*, engine::*, outputs::*};
+        use crate::testing::*;
+
+        #[test]
+        fn admin_rows_are_merged_in_by_timestamp() {
+            let path = temp_file("admin-merge.csv", "type,client,tx,amount,timestamp\nfreeze,1,100,,15\nunfreeze,1,101,,\nfreeze,1,102,,30\nfreeze,1,103,,100\n");
+            let input = rows("type,client,tx,amount,timestamp\ndeposit,1,1,100,10\ndeposit,1,2,5,20\ndeposit,1,3,5,\ndeposit,1,4,5,40\n");
+            let mut merged = AdminMerge::new(Rows(input.into()), CsvSource::open(&path).unwrap());
+
+            let mut order = Vec::new();
+            while let Some(row) = merged.next_transaction() {
+                let transaction = row.unwrap();
+                order.push((transaction.tx_id, transaction.admin));
+            }
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(order, [(1, false), (100, true), (101, true), (2, false), (3, false), (102, true), (4, false), (103, true)]);
+        }
+
+        #[test]
+        fn merge_starts_from_the_prior_balances() {
+            let path = temp_file("prior-settled.csv", "client,available,held,total,locked\n1,100,0,100,false\n2,5.5,0,5.5,false\n");
+            let prior = check_settled(&path, load_report(&path).unwrap()).unwrap();
+            fs::remove_file(path).unwrap();
+
+            let mut rejections = Rejections::default();
+            let input = rows("type,client,tx,amount\nwithdrawal,1,1,30\ndeposit,2,2,1\nwithdrawal,2,3,10\n");
+            let state = process_transactions(&Options::default(), &mut StagedOutputs::default(), &mut Rows(input.into()), prior, &mut [], &mut [&mut rejections]).unwrap();
+
+            assert_eq!(rejections.0, [(3, INSUFFICIENT_FUNDS)]);
+            assert_eq!((state.clients[&1].total, state.clients[&2].total), (70.0, 6.5));
+        }
+
+        #[test]
+        fn merge_refuses_locked_or_held_accounts() {
+            let locked = temp_file("prior-locked.csv", "client,available,held,total,locked\n1,100,0,100,false\n3,0,0,0,true\n");
+            let held = temp_file("prior-held.csv", "client,available,held,total,locked\n2,60,40,100,false\n");
+
+            for (path, client) in [(&locked, "client 3"), (&held, "client 2")] {
+                let err = check_settled(path, load_report(path).unwrap()).unwrap_err().to_string();
+                assert!(err.contains(client) && err.contains("settled before merging"), "{}", err);
+                fs::remove_file(path).unwrap();
+            }
+        }
+
+        #[test]
+        fn unrecognized_columns_are_collected_on_the_transaction() {
+            let transaction = rows("type,client,tx,amount,channel,Note\ndeposit,1,1,10,web,first one\n").remove(0);
+
+            assert_eq!(transaction.amount, Some(10.0));
+            assert_eq!(transaction.extra_fields, HashMap::from([
+                ("channel".to_string(), "web".to_string()),
+                ("Note".to_string(), "first one".to_string()),
+            ]));
+        }
+
+        #[test]
+        fn threaded_source_keeps_the_order_of_the_rows() {
+            let input: Vec<Transaction> = (1..=1000).map(|tx_id| row(&format!("deposit,1,{},1,", tx_id))).collect();
+
+            for (batch_size, read_ahead) in [(1, 1), (7, 1), (256, 16), (5000, 2)] {
+                let mut threaded = ThreadedSource::spawn(Rows(input.clone().into()), BatchSize::Fixed(batch_size), read_ahead, false);
+                let mut order = Vec::new();
+                while let Some(row) = threaded.next_transaction() {
+                    order.push(row.unwrap().tx_id);
+                }
+
+                assert_eq!(order, (1..=1000).collect::<Vec<TxId>>(), "batches of {}", batch_size);
+            }
+        }
+
+        #[test]
+        fn threaded_source_passes_malformed_rows_on_in_place() {
+            let path = temp_file("threaded.csv", "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,x,10\ndeposit,1,3,10\n");
+            let mut threaded = ThreadedSource::spawn(CsvSource::open(&path).unwrap(), BatchSize::Fixed(2), 1, false);
+
+            let mut order = Vec::new();
+            while let Some(row) = threaded.next_transaction() {
+                order.push(row.map(|transaction| transaction.tx_id).map_err(|e| e.is::<MalformedRow>()));
+            }
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(order, [Ok(1), Err(true), Ok(3)]);
+        }
+
+        #[test]
+        fn batch_tuner_keeps_the_fastest_candidate() {
+            let share = AUTO_TUNE_ROWS / AUTO_TUNE_CANDIDATES.len();
+            let mut tuner = BatchTuner::new(BatchSize::Auto);
+            let mut picked = Vec::new();
+
+            for (size, millis) in AUTO_TUNE_CANDIDATES.into_iter().zip([500, 300, 100, 200, 400]) {
+                assert_eq!(tuner.size(), size);
+                picked.push(tuner.record(share / 2, Duration::from_millis(millis / 2)));
+                picked.push(tuner.record(share - share / 2, Duration::from_millis(millis / 2)));
+            }
+
+            assert_eq!(picked.iter().flatten().collect::<Vec<_>>(), [&256]);
+            assert_eq!((tuner.size(), tuner.record(1, Duration::from_secs(60))), (256, None), "the pick is final");
+
+            let mut fixed = BatchTuner::new(BatchSize::Fixed(32));
+            assert_eq!((fixed.record(AUTO_TUNE_ROWS, Duration::ZERO), fixed.size()), (None, 32));
+        }
+
+        #[test]
+        fn ids_past_the_built_width_are_malformed() {
+            let (tx_max, client_max) = (TxId::MAX as u128, ClientId::MAX as u128);
+            let path = temp_file("id-widths.csv", &format!(
+                "type,client,tx,amount,owners\ndeposit,{},{},1,\ndeposit,1,{},1,\ndeposit,{},1,1,\nopen,1,2,,{}\n",
+                client_max, tx_max, tx_max + 1, client_max + 1, client_max + 1,
+            ));
+            let mut source = CsvSource::open(&path).unwrap();
+
+            let mut parsed = Vec::new();
+            while let Some(row) = source.next_transaction() {
+                parsed.push(row.map(|transaction| (transaction.client_id as u128, transaction.tx_id as u128)).map_err(|e| e.is::<MalformedRow>()));
+            }
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(parsed, [Ok((client_max, tx_max)), Err(true), Err(true), Err(true)]);
+        }
+
+        #[test]
+        #[cfg(feature = "wide-ids")]
+        fn wide_ids_take_ids_past_the_spec() {
+            let transaction = rows("type,client,tx,amount\ndeposit,65536,4294967296,1\n").remove(0);
+
+            assert_eq!((transaction.client_id, transaction.tx_id), (65536, 4294967296));
+        }
+
+        #[test]
+        fn type_names_are_read_in_any_case() {
+            let types: Vec<String> = rows("type,client,tx,amount\nDeposit,1,1,1\nWITHDRAWAL,1,2,1\ndiSPute,1,1,\n").into_iter().map(|transaction| transaction.tx_type).collect();
+
+            assert_eq!(types, ["deposit", "withdrawal", "dispute"]);
+        }
+
+        #[test]
+        fn batch_tuner_compares_throughput_over_uneven_batches() {
+            let share = AUTO_TUNE_ROWS / AUTO_TUNE_CANDIDATES.len();
+            let mut tuner = BatchTuner::new(BatchSize::Auto);
+
+            // the first candidate's last batch runs past its share: it took the longest, but sent twice the rows
+            assert_eq!(tuner.record(share * 2, Duration::from_millis(150)), None);
+            for _ in 1..AUTO_TUNE_CANDIDATES.len() - 1 {
+                assert_eq!(tuner.record(share, Duration::from_millis(100)), None);
+            }
+
+            assert_eq!(tuner.record(share, Duration::from_millis(100)), Some(AUTO_TUNE_CANDIDATES[0]));
+        }
+
+        #[test]
+        fn control_totals_count_the_rows_read_under_their_written_type() {
+            let aliases = HashMap::from([("credit".to_string(), "deposit".to_string())]);
+            let input = rows("type,client,tx,amount\ncredit,1,1,10.5\ndeposit,1,2,4\nwithdrawal,1,3,100\ncredit,1,4,2\n");
+            let mut totals = ControlTotals::default();
+            process_transactions(
+                &Options::default(),
+                &mut StagedOutputs::default(),
+                &mut ControlCount { rows: &mut Rows(input.into()), totals: &mut totals },
+                HashMap::new(),
+                &mut [&mut TypeAliases { aliases: &aliases }],
+                &mut [],
+            ).unwrap();
+
+            assert_eq!(totals.actual, BTreeMap::from([
+                ("credit".to_string(), (2, 12.5)),
+                ("deposit".to_string(), (1, 4.0)),
+                ("withdrawal".to_string(), (1, 100.0)),
+            ]), "the rejected withdrawal is counted too");
+        }
+
+        #[test]
+        fn control_mismatches_list_the_counts_and_amounts_that_differ() {
+            let totals = ControlTotals { actual: BTreeMap::from([("deposit".to_string(), (3, 30.0)), ("withdrawal".to_string(), (1, 5.0)), ("dispute".to_string(), (1, 0.0))]) };
+            let expected = BTreeMap::from([
+                ("deposit".to_string(), ControlTotal { count: 3, amount: Some(30.00001) }),
+                ("withdrawal".to_string(), ControlTotal { count: 2, amount: Some(5.5) }),
+                ("refund".to_string(), ControlTotal { count: 1, amount: None }),
+            ]);
+
+            assert_eq!(totals.mismatches(&expected), [
+                "Expected 1 refund rows, read 0.",
+                "Expected 2 withdrawal rows, read 1.",
+                "Expected withdrawal rows adding up to 5.5, read 5.",
+            ], "amounts are compared to four decimal places, and types missing from the control file aren't checked");
+        }
+
+        #[test]
+        fn json_fields_are_read_as_the_outbox_writes_them() {
+            let fields = read_json_fields(r#"{"tx":7,"type":"dispute","amount":null,"reason":"Say \"hi\"\\\u000a","passthrough":{"ref":"}","n":[1]},"locked":true}"#).unwrap();
+            let field = |name : &str| fields[name].as_deref();
+            assert_eq!((field("tx"), field("type"), field("amount"), field("locked")), (Some("7"), Some("dispute"), None, Some("true")));
+            assert_eq!(field("reason"), Some("Say \"hi\"\\\n"));
+            assert_eq!(field("passthrough"), Some(r#"{"ref":"}","n":[1]}"#), "nested objects are kept as written");
+
+            assert_eq!(read_json_fields(" { } "), Some(HashMap::new()));
+            for invalid in ["", "{\"tx\":}", "{\"tx\":1", "{\"tx\":1}}", "[1]", "{tx:1}"] {
+                assert_eq!(read_json_fields(invalid), None, "{}", invalid);
+            }
+        }
+
+        #[test]
+        fn outbox_balances_are_those_of_the_last_applied_event_of_each_client() {
+            let path = temp_file("rebuild.ndjson", "\
+                {\"client\":1,\"outcome\":\"applied\",\"available\":100,\"held\":0,\"total\":100,\"locked\":false}
+                {\"client\":2,\"outcome\":\"rejected\",\"available\":0,\"held\":0,\"total\":0,\"locked\":false}
+                {\"client\":1,\"outcome\":\"applied\",\"available\":0,\"held\":100,\"total\":100,\"locked\":true}
+
+            ");
+            let client_data = load_outbox(&path, 0).unwrap();
+            fs::write(&path, "{\"client\":1,\"outcome\":\"applied\",\"available\":1,\"held\":0,\"total\":2,\"locked\":false}\n").unwrap();
+            let unbalanced = load_outbox(&path, 0).unwrap_err().to_string();
+            fs::write(&path, "{\"client\":1,\"outcome\":\"applied\",\"available\":null,\"held\":0,\"total\":0,\"locked\":false}\n").unwrap();
+            let missing = load_outbox(&path, 0).unwrap_err().to_string();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!(client_data.len(), 1, "a rejected row doesn't open an account");
+            let client = &client_data[&1];
+            assert_eq!((client.available, client.held, client.total, client.is_locked()), (0.0, 100.0, 100.0, true));
+            assert!(unbalanced.contains("don't add up"), "{}", unbalanced);
+            assert!(missing.contains("Line 1 of outbox"), "{}", missing);
+        }
+
+        #[test]
+        fn compacted_outbox_keeps_the_last_event_of_each_client_in_order() {
+            let event = |client_id : ClientId, line : &str| OutboxEvent { client_id, client: ClientData::default(), line: line.to_string() };
+            let events = vec![event(1, "a"), event(2, "b"), event(1, "c"), event(3, "d"), event(2, "e")];
+
+            let kept: Vec<String> = compact_outbox(events).into_iter().map(|event| event.line).collect();
+            assert_eq!(kept, ["c", "d", "e"]);
+        }
+
+        #[test]
+        fn undone_events_are_left_out_of_the_rebuilt_balances() {
+            let path = temp_file("undo.ndjson", "\
+                {\"client\":1,\"outcome\":\"applied\",\"available\":100,\"held\":0,\"total\":100,\"locked\":false}
+                {\"client\":2,\"outcome\":\"applied\",\"available\":5,\"held\":0,\"total\":5,\"locked\":false}
+                {\"client\":1,\"outcome\":\"applied\",\"available\":40,\"held\":0,\"total\":40,\"locked\":false}
+                {\"client\":1,\"outcome\":\"rejected\",\"available\":40,\"held\":0,\"total\":40,\"locked\":false}
+            ");
+            let one_undone = load_outbox(&path, 1).unwrap();
+            let two_undone = load_outbox(&path, 2).unwrap();
+            let too_many = load_outbox(&path, 4).unwrap_err().to_string();
+            fs::remove_file(path).unwrap();
+
+            assert_eq!((one_undone[&1].total, one_undone[&2].total), (100.0, 5.0), "the rejected event isn't one to undo");
+            assert_eq!((two_undone.len(), two_undone[&1].total), (1, 100.0), "the account opened by an undone event is gone");
+            assert!(too_many.contains("only has 3 applied events"), "{}", too_many);
+        }
+    }
@@ -0,0 +1,79 @@
+// Helpers shared by the unit tests of every module.
+
+use std::{error::Error, env, fs, process, collections::{HashMap, VecDeque}};
+use csv::Trim;
+use crate::{checks::*, engine::*, observers::*, options::*, outputs::*, rejects::*, sources::*, types::*};
+
+
+
+//* Tests *//
+
+    // Decodes the rows the way they'd be read from an input file, from text indented along with the test.
+    pub(crate) fn rows(csv_text : &str) -> Vec<Transaction> {
+        let lines: Vec<&str> = csv_text.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+        csv::ReaderBuilder::new().trim(Trim::All).from_reader(lines.join("\n").as_bytes())
+            .deserialize()
+            .map(|row| row.unwrap())
+            .collect()
+    }
+
+    pub(crate) fn row(csv_line : &str) -> Transaction {
+        rows(&format!("type,client,tx,amount,timestamp\n{}", csv_line)).remove(0)
+    }
+
+    pub(crate) fn funded(client_id : ClientId, available : f64) -> HashMap<ClientId, ClientData> {
+        let mut client = ClientData::default();
+        client.adjust(DEFAULT_WALLET, available, 0.0);
+        HashMap::from([(client_id, client)])
+    }
+
+    pub(crate) fn code_of(result : Result<(), Box<dyn Error>>) -> RejectCode {
+        reject_code(result.unwrap_err().as_ref())
+    }
+
+    pub(crate) struct Rows(pub(crate) VecDeque<Transaction>);
+
+    // Marks the admin rows as read from the "--admin-input" file.
+    pub(crate) fn as_operator(input : Vec<Transaction>) -> Vec<Transaction> {
+        input.into_iter().map(|transaction| Transaction { admin: ADMIN_TYPES.contains(&transaction.tx_type.as_str()), ..transaction }).collect()
+    }
+
+    impl InputSource for Rows {
+        fn next_transaction(&mut self) -> Option<Result<Transaction, SourceError>> {
+            self.0.pop_front().map(Ok)
+        }
+    }
+
+    // The code of every rejection, by tx ID.
+    #[derive(Default)]
+    pub(crate) struct Rejections(pub(crate) Vec<(TxId, RejectCode)>);
+
+    impl EngineObserver for Rejections {
+        fn on_rejected(&mut self, transaction : &TransactionInfo, reason : &(dyn Error + 'static)) {
+            self.0.push((transaction.tx_id, reject_code(reason)));
+        }
+    }
+
+    pub(crate) fn run(options : &Options, csv_text : &str) -> (EngineState, Vec<(TxId, RejectCode)>) {
+        run_rows(options, rows(csv_text))
+    }
+
+    pub(crate) fn run_rows(options : &Options, input : Vec<Transaction>) -> (EngineState, Vec<(TxId, RejectCode)>) {
+        let mut rejections = Rejections::default();
+        let state = process_transactions(
+            options,
+            &mut StagedOutputs::default(),
+            &mut Rows(input.into()),
+            HashMap::new(),
+            &mut [],
+            &mut [&mut rejections],
+        ).unwrap();
+        (state, rejections.0)
+    }
+
+    // Writes a file for the loaders to read, unique to the test.
+    pub(crate) fn temp_file(name : &str, contents : &str) -> String {
+        let path = env::temp_dir().join(format!("{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
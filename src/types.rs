@@ -0,0 +1,324 @@
+// The data the engine works on: transactions, accounts, and the state kept between rows.
+
+use std::{sync::Arc, collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}};
+use crate::{observers::*, rejects::*};
+
+
+
+//* Structs *//
+
+    // The CSV spec's ID widths. With the "wide-ids" feature they're widened for larger ID spaces,
+    // and the deserializer rejects IDs out of range for whichever width is built.
+    #[cfg(not(feature = "wide-ids"))]
+    pub(crate) type TxId = u32;
+    #[cfg(not(feature = "wide-ids"))]
+    pub(crate) type ClientId = u16;
+
+    #[cfg(feature = "wide-ids")]
+    pub(crate) type TxId = u64;
+    #[cfg(feature = "wide-ids")]
+    pub(crate) type ClientId = u32;
+
+
+    // Deserialized by hand (see below), so columns that aren't recognized end up in "extra_fields" instead of being dropped.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Transaction {
+        pub(crate) tx_id: TxId, // "tx" column. Redundant, since it's already the key of the dictionary. Could be upgraded for performance.
+        pub(crate) tx_type: String, // "type" column. Due to Rust naming conventions, this field cannot be called "type".
+        pub(crate) client_id: ClientId, // "client" column
+        pub(crate) amount: Option<f64>, // Option since some transaction types don't have values for "amount"
+        pub(crate) timestamp: Option<i64>, // optional "timestamp" column, in unix seconds once parsed
+        pub(crate) value_date: Option<i64>, // optional "value_date" column, parsed like "timestamp". Rows dated later are applied then
+        pub(crate) wallet: String, // optional "wallet" column, the client's main wallet when missing or empty
+        pub(crate) owners: Vec<ClientId>, // optional "owners" column of "open" rows, client IDs separated by ";"
+        pub(crate) recurrence: Option<Recurrence>, // "kind", "interval" and "count" columns of "recurring" rows
+        pub(crate) dispute_status: DisputeStatus, // internal, never read from the input
+        pub(crate) refunded: f64, // internal, how much of a deposit was refunded so far
+        pub(crate) held: f64, // internal, what its dispute holds: the net amount, unless the shortfall policy capped it
+        pub(crate) shortfall: f64, // internal, what the operator covers of its dispute under the "clamp" shortfall policy
+        pub(crate) disputed_at: Option<i64>, // internal, timestamp of the dispute row, if it had one
+        pub(crate) charged_back_at: Option<i64>, // internal, timestamp of the chargeback row, if it had one
+        pub(crate) extra_fields: HashMap<String, String>, // unrecognized column -> value
+        pub(crate) origin: Option<RowOrigin>, // internal, set by the source and taken out before the transaction is stored
+        pub(crate) admin: bool, // internal, set for the rows of the "--admin-input" file
+    }
+
+    // Where a transaction was read from, so rejections can point at the input record itself.
+    #[derive(Debug, Clone)]
+    pub(crate) struct RowOrigin {
+        pub(crate) file: Arc<str>, // the path it was opened from
+        pub(crate) line: u64,
+        pub(crate) byte: u64, // offset of the record's start in the file
+        pub(crate) record: csv::StringRecord, // the record as it was read, before trimming
+    }
+
+    // A "recurring" row stands for "count" transactions of the given kind, "interval" seconds apart.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Recurrence {
+        pub(crate) kind: String, // "deposit" or "withdrawal"
+        pub(crate) interval: i64,
+        pub(crate) count: TxId,
+    }
+
+    // The most occurrences a single "recurring" row can expand to, as they are all queued at once.
+    pub(crate) const MAX_RECURRENCE : TxId = 10_000;
+
+
+    impl Transaction {
+        // The part of the amount still subject to disputes and reversals, once refunds are taken out.
+        pub(crate) fn net_amount(&self) -> f64 {
+            self.amount.unwrap_or(0.0) - self.refunded
+        }
+    }
+
+
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct ClientData {
+        pub(crate) available: f64,
+        pub(crate) held: f64,
+        pub(crate) total: f64,
+        pub(crate) locks: BTreeSet<LockReason>, // There can be more than one simultaneous lock, the account is locked while any remain
+        pub(crate) frozen: bool, // Set by a chargeback. Unlike locks it doesn't go away on its own, only through a reversal or an admin
+        pub(crate) wallets: BTreeMap<String, WalletBalance>, // The same funds split per wallet. The fields above are their sum
+        pub(crate) owners: BTreeSet<ClientId>, // Co-owners of a joint account, besides the client whose ID the account has
+        pub(crate) stats: ClientStats, // Rejections support gets asked about, listed in the extended report
+    }
+
+    impl ClientData {
+        // Moves funds within a wallet, keeping the client's totals in sync. The total changes by the sum of both amounts.
+        pub(crate) fn adjust(&mut self, wallet : &str, available : f64, held : f64) {
+            self.available += available;
+            self.held += held;
+            self.total += available + held;
+
+            let balance = self.wallets.entry(wallet.to_string()).or_default();
+            balance.available += available;
+            balance.held += held;
+            balance.total += available + held;
+        }
+
+        pub(crate) fn wallet_available(&self, wallet : &str) -> f64 {
+            self.wallets.get(wallet).map_or(0.0, |balance| balance.available)
+        }
+
+        pub(crate) fn is_locked(&self) -> bool {
+            self.frozen || !self.locks.is_empty()
+        }
+
+        // Everything keeping the account locked, as listed in the extended report.
+        pub(crate) fn lock_reasons(&self) -> String {
+            let mut reasons = String::new();
+            let _ = self.write_lock_reasons(&mut reasons);
+            reasons
+        }
+
+        pub(crate) fn write_lock_reasons(&self, out : &mut impl std::fmt::Write) -> std::fmt::Result {
+            let mut separator = "";
+            for lock in &self.locks {
+                write!(out, "{}{}", separator, lock)?;
+                separator = ";";
+            }
+            if self.frozen {
+                write!(out, "{}chargeback_freeze", separator)?;
+            }
+            Ok(())
+        }
+
+        // Everyone owning the account, as listed in the extended report.
+        pub(crate) fn write_owner_set(&self, client_id : ClientId, out : &mut impl std::fmt::Write) -> std::fmt::Result {
+            write!(out, "{}", client_id)?;
+            for owner in &self.owners {
+                write!(out, ";{}", owner)?;
+            }
+            Ok(())
+        }
+    }
+
+
+    // One of the client's labeled balances (main, savings, bonus...), from the "wallet" column.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct WalletBalance {
+        pub(crate) available: f64,
+        pub(crate) held: f64,
+        pub(crate) total: f64,
+    }
+
+    pub(crate) const DEFAULT_WALLET: &str = "main";
+
+
+    // Per-client counts of the rejections behind most customer complaints. Rejections of unknown clients aren't counted.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct ClientStats {
+        pub(crate) insufficient_funds: u64, // withdrawals rejected for a lack of available funds
+        pub(crate) locked: u64, // any transaction rejected because the account is locked
+        pub(crate) duplicate_ids: u64,
+    }
+
+    impl ClientStats {
+        pub(crate) fn record(&mut self, transaction : &TransactionInfo, code : RejectCode) {
+            match code {
+                INSUFFICIENT_FUNDS if transaction.tx_type == "withdrawal" => self.insufficient_funds += 1,
+                ACCOUNT_LOCKED => self.locked += 1,
+                DUPLICATE_TX => self.duplicate_ids += 1,
+                _ => {},
+            }
+        }
+    }
+
+
+    // Why an account is temporarily locked. Ordered so the reasons are always listed the same way in the reports.
+    // The permanent chargeback freeze is tracked separately, by "ClientData::frozen".
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub(crate) enum LockReason {
+        OpenDispute(TxId), // tx ID of the disputed transaction, removed once it's resolved or charged back
+        AdminFreeze, // set and cleared by the "freeze" and "unfreeze" admin rows
+    }
+
+    impl std::fmt::Display for LockReason {
+        fn fmt(&self, formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                LockReason::OpenDispute(tx_id) => write!(formatter, "dispute:{}", tx_id),
+                LockReason::AdminFreeze => write!(formatter, "admin_freeze"),
+            }
+        }
+    }
+
+
+    #[derive(Debug, Default, Clone, PartialEq)]
+    pub(crate) enum DisputeStatus {
+        #[default]
+        NotDisputed,
+        UnderDispute,
+        Resolved,
+        ChargedBack,
+        Reversed, // undone by a "reversal" row, can no longer be disputed
+        ChargebackReversed, // the merchant won the representment, final
+    } 
+
+
+    // Internal accounts of the operator, holding the counter-side of the client balance movements that don't
+    // come from the input itself, so the report shows where disputed money actually went.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct OperatorAccounts {
+        pub(crate) escrow: f64, // funds held by open disputes
+        pub(crate) losses: f64, // funds paid out by chargebacks, net of chargeback reversals
+        pub(crate) fees: f64, // chargeback fees collected from clients
+    }
+
+
+    impl OperatorAccounts {
+        pub(crate) fn balances(&self) -> [(&'static str, f64); 3] {
+            [("escrow", self.escrow), ("losses", self.losses), ("fees", self.fees)]
+        }
+    }
+
+
+    // Everything the engine knows after processing the input.
+    #[derive(Debug, Default)]
+    pub(crate) struct EngineState {
+        pub(crate) clients: HashMap<ClientId, ClientData>,
+        pub(crate) transactions: HashMap<TxId, Transaction>,
+        pub(crate) holds: HashMap<TxId, Hold>,
+        pub(crate) operator: OperatorAccounts,
+        pub(crate) latest_timestamp: Option<i64>, // the end of the input, as far as time-based reports go
+        pub(crate) still_scheduled: usize, // future-dated transactions that were never applied
+    }
+
+
+    // The engine state captured by a "savepoint" row, restored by a "rollback_to" row with the same tx ID.
+    // Everything the rules look at is kept, so the rows after a rollback are validated as if the reverted ones never happened.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Savepoint {
+        pub(crate) transactions: HashMap<TxId, Transaction>,
+        pub(crate) clients: HashMap<ClientId, ClientData>,
+        pub(crate) operator: OperatorAccounts,
+        pub(crate) holds: HashMap<TxId, Hold>,
+        pub(crate) holds_expiry_queue: VecDeque<TxId>,
+        pub(crate) joint_owners: HashMap<ClientId, ClientId>,
+        pub(crate) transactions_order: VecDeque<(TxId, Option<i64>)>,
+        pub(crate) pruned_ids: HashSet<TxId>,
+        pub(crate) withdrawal_ids: HashSet<TxId>,
+        pub(crate) velocity_windows: HashMap<ClientId, VecDeque<(i64, f64)>>,
+        pub(crate) daily_withdrawals: HashMap<ClientId, (i64, f64)>,
+        pub(crate) kyc_deposits: HashMap<ClientId, f64>,
+        pub(crate) scheduled: BTreeMap<(i64, usize), Transaction>, // the scheduler's queue, so what was released after the savepoint is pending again
+    }
+
+
+    // The states captured by "savepoint" rows, in the order they were taken, by the tx ID of their row.
+    // Taking a savepoint again replaces it. Rolling back to one drops the savepoints taken after it, but it can be rolled back to again.
+    // Used by the engine and by the observers that keep state derived from the applied transactions.
+    pub(crate) struct Savepoints<T>(pub(crate) Vec<(TxId, T)>);
+
+    impl<T> Default for Savepoints<T> {
+        fn default() -> Self {
+            Savepoints(Vec::new())
+        }
+    }
+
+    impl<T: Clone> Savepoints<T> {
+        pub(crate) fn take(&mut self, savepoint_id : TxId, state : T) {
+            self.0.retain(|(id, _)| *id != savepoint_id);
+            self.0.push((savepoint_id, state));
+        }
+
+        pub(crate) fn restore(&mut self, savepoint_id : TxId) -> Option<T> {
+            let position = self.0.iter().position(|(id, _)| *id == savepoint_id)?;
+            self.0.truncate(position + 1);
+            Some(self.0[position].1.clone())
+        }
+    }
+
+
+    // A card pre-authorization: the amount sits in "held" until it's captured, released, or expires.
+    #[derive(Debug, Clone)]
+    pub(crate) struct Hold {
+        pub(crate) client_id: ClientId,
+        pub(crate) wallet: String,
+        pub(crate) placed_at: Option<i64>,
+        pub(crate) amount: f64,
+        pub(crate) expires_at: Option<i64>, // only set when both the hold and the config have a notion of time
+        pub(crate) status: HoldStatus,
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) enum HoldStatus {
+        Open,
+        Captured,
+        Released,
+        Expired,
+    }
+
+
+
+//* Auxiliary Functions *//
+
+    // This formats an f64 with 4 digits of precision
+    pub(crate) fn format_amount(amount : f64) -> String {
+        round_amount(amount).to_string()
+    }
+
+    pub(crate) fn round_amount(amount : f64) -> f64 {
+        (amount * 10_000.0).round() / 10_000.0
+    }
+
+
+//* Tests *//
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn lock_reasons_are_listed_in_order_with_the_freeze_last() {
+            let mut client = ClientData::default();
+            assert_eq!(client.lock_reasons(), "");
+
+            client.frozen = true;
+            client.locks.extend([LockReason::AdminFreeze, LockReason::OpenDispute(12), LockReason::OpenDispute(3)]);
+
+            assert_eq!(client.lock_reasons(), "dispute:3;dispute:12;admin_freeze;chargeback_freeze");
+            assert!(client.is_locked());
+        }
+    }
@@ -0,0 +1,233 @@
+// Runs the binary over the fixtures, comparing what it writes with the expected files next to them.
+
+use std::{fs, path::Path, process::Command};
+
+
+// Accounts are written in no particular order, so the rows are compared sorted, under the same header.
+fn sorted_rows(csv_text : &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = csv_text.lines().collect();
+    lines[1..].sort_unstable();
+    lines
+}
+
+
+fn run(args : &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kraken-andre-santos"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+
+// Runs the binary, returning what it wrote to stdout and to stderr.
+fn run_with_stderr(args : &[&str]) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_kraken-andre-santos"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+
+// Runs the binary expecting it to fail, returning what it wrote to stderr.
+fn run_failing(args : &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kraken-andre-santos"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    String::from_utf8(output.stderr).unwrap()
+}
+
+
+fn fixture(name : &str) -> String {
+    fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)).unwrap()
+}
+
+
+#[test]
+fn holds_are_captured_released_and_expired() {
+    let rejects = std::env::temp_dir().join(format!("holds-rejects-{}.csv", std::process::id()));
+    let report = run(&[
+        "tests/fixtures/holds.csv",
+        "--config", "tests/fixtures/holds.toml",
+        "--rejects", rejects.to_str().unwrap(),
+    ]);
+
+    assert_eq!(sorted_rows(&report), sorted_rows(&fixture("holds.expected.csv")));
+    assert_eq!(fs::read_to_string(&rejects).unwrap(), fixture("holds.rejects.expected.csv"));
+    fs::remove_file(rejects).unwrap();
+}
+
+
+#[test]
+fn holds_never_expire_without_an_expiry() {
+    let report = run(&["tests/fixtures/holds.csv"]);
+
+    assert_eq!(sorted_rows(&report), sorted_rows(&fixture("holds.no-expiry.expected.csv")));
+}
+
+
+#[test]
+fn report_is_plain_csv_even_with_color_always() {
+    let report = run(&["tests/fixtures/holds.csv", "--color", "always", "--format", "extended"]);
+
+    assert!(!report.contains('\x1b'), "{:?}", report);
+}
+
+
+#[test]
+fn admin_rows_are_only_taken_from_the_admin_input() {
+    let rejects = std::env::temp_dir().join(format!("admin-rejects-{}.csv", std::process::id()));
+    let report = run(&[
+        "tests/fixtures/admin.csv",
+        "--admin-input", "tests/fixtures/admin.admin.csv",
+        "--rejects", rejects.to_str().unwrap(),
+    ]);
+
+    assert_eq!(sorted_rows(&report), sorted_rows(&fixture("admin.expected.csv")));
+    assert_eq!(fs::read_to_string(&rejects).unwrap(), fixture("admin.rejects.expected.csv"));
+    fs::remove_file(rejects).unwrap();
+}
+
+
+#[test]
+fn rollbacks_are_only_taken_from_the_admin_input() {
+    let rejects = std::env::temp_dir().join(format!("savepoints-rejects-{}.csv", std::process::id()));
+    let outbox = std::env::temp_dir().join(format!("savepoints-outbox-{}.ndjson", std::process::id()));
+    let report = run(&[
+        "tests/fixtures/savepoints.csv",
+        "--admin-input", "tests/fixtures/savepoints.admin.csv",
+        "--rejects", rejects.to_str().unwrap(),
+        "--outbox", outbox.to_str().unwrap(),
+    ]);
+
+    assert_eq!(sorted_rows(&report), sorted_rows(&fixture("savepoints.expected.csv")));
+    assert_eq!(fs::read_to_string(&rejects).unwrap(), fixture("savepoints.rejects.expected.csv"));
+    assert_eq!(fs::read_to_string(&outbox).unwrap(), fixture("savepoints.outbox.expected.ndjson"));
+    fs::remove_file(rejects).unwrap();
+    fs::remove_file(outbox).unwrap();
+}
+
+
+#[test]
+fn merge_applies_the_transactions_to_the_prior_balances() {
+    let report = run(&["merge", "tests/fixtures/merge.prior.csv", "tests/fixtures/merge.csv"]);
+
+    assert_eq!(sorted_rows(&report), sorted_rows(&fixture("merge.expected.csv")));
+}
+
+
+#[test]
+fn merge_outbox_changes_are_from_the_prior_balances() {
+    let outbox = std::env::temp_dir().join(format!("merge-outbox-{}.ndjson", std::process::id()));
+    run(&["merge", "tests/fixtures/merge.prior.csv", "tests/fixtures/merge.csv", "--outbox", outbox.to_str().unwrap()]);
+    let events = fs::read_to_string(&outbox).unwrap();
+    fs::remove_file(outbox).unwrap();
+    let events: Vec<&str> = events.lines().collect();
+
+    assert!(events[0].contains("\"available_change\":-30,\"held_change\":0,\"total_change\":-30,\"available\":70,"), "{}", events[0]);
+    assert!(events[1].contains("\"available_change\":1,\"held_change\":0,\"total_change\":1,\"available\":6.5,"), "{}", events[1]);
+    assert!(events[2].contains("\"available_change\":7,\"held_change\":0,\"total_change\":7,\"available\":7,"), "a new account still starts from zero: {}", events[2]);
+}
+
+
+#[test]
+fn merge_refuses_a_prior_report_with_locked_accounts() {
+    let stderr = run_failing(&["merge", "tests/fixtures/merge.locked.csv", "tests/fixtures/merge.csv"]);
+
+    assert!(stderr.contains("starting with client 2"), "{}", stderr);
+}
+
+
+#[test]
+fn diff_lists_the_account_fields_that_differ_between_two_reports() {
+    let changes = run(&["diff", "tests/fixtures/diff.before.csv", "tests/fixtures/diff.after.csv"]);
+    assert_eq!(changes, fixture("diff.expected.csv"));
+
+    let unchanged = run(&["diff", "tests/fixtures/diff.before.csv", "tests/fixtures/diff.before.csv"]);
+    assert_eq!(unchanged, "client,field,before,after\n");
+}
+
+
+#[test]
+fn rebuild_writes_the_report_from_the_balances_of_the_outbox() {
+    let outbox = std::env::temp_dir().join(format!("rebuild-outbox-{}.ndjson", std::process::id()));
+    let report = std::env::temp_dir().join(format!("rebuild-report-{}.csv", std::process::id()));
+    run(&[
+        "tests/fixtures/holds.csv",
+        "--config", "tests/fixtures/holds.toml",
+        "--outbox", outbox.to_str().unwrap(),
+        "--output", report.to_str().unwrap(),
+    ]);
+
+    let rebuilt = run(&["rebuild", outbox.to_str().unwrap(), "--verify", report.to_str().unwrap()]);
+    assert_eq!(sorted_rows(&rebuilt), sorted_rows(&fixture("holds.expected.csv")));
+
+    let stderr = run_failing(&["rebuild", outbox.to_str().unwrap(), "--verify", "tests/fixtures/diff.before.csv"]);
+    assert!(stderr.contains("client=2 field=held report=1 rebuilt=0"), "{}", stderr);
+    assert!(stderr.contains("don't match report 'tests/fixtures/diff.before.csv'"), "{}", stderr);
+
+    let stderr = run_failing(&["tests/fixtures/holds.csv", "--verify", report.to_str().unwrap()]);
+    assert!(stderr.contains("--verify only applies to the rebuild command"), "{}", stderr);
+    fs::remove_file(outbox).unwrap();
+    fs::remove_file(report).unwrap();
+}
+
+
+#[test]
+fn compacted_outbox_rebuilds_the_same_report() {
+    let outbox = std::env::temp_dir().join(format!("compact-outbox-{}.ndjson", std::process::id()));
+    let compacted = std::env::temp_dir().join(format!("compact-compacted-{}.ndjson", std::process::id()));
+    run(&["tests/fixtures/holds.csv", "--config", "tests/fixtures/holds.toml", "--outbox", outbox.to_str().unwrap()]);
+
+    let (events, stderr) = run_with_stderr(&["compact", outbox.to_str().unwrap()]);
+    assert!(stderr.contains("events=9 kept=2"), "{}", stderr);
+    assert!(events.lines().all(|event| fs::read_to_string(&outbox).unwrap().lines().any(|line| line == event)), "events are kept as written");
+    fs::write(&compacted, events).unwrap();
+
+    let rebuilt = run(&["rebuild", compacted.to_str().unwrap()]);
+    assert_eq!(sorted_rows(&rebuilt), sorted_rows(&fixture("holds.expected.csv")));
+    fs::remove_file(outbox).unwrap();
+    fs::remove_file(compacted).unwrap();
+}
+
+
+#[test]
+fn rebuild_with_undo_has_the_balances_from_before_the_last_events() {
+    let input = std::env::temp_dir().join(format!("undo-input-{}.csv", std::process::id()));
+    let outbox = std::env::temp_dir().join(format!("undo-outbox-{}.ndjson", std::process::id()));
+    let rows = "type,client,tx,amount\ndeposit,1,1,100\ndeposit,2,2,50\nwithdrawal,1,3,30\ndeposit,3,4,10\n";
+    fs::write(&input, format!("{}dispute,1,1,\ndeposit,2,5,5\n", rows)).unwrap();
+    run(&[input.to_str().unwrap(), "--outbox", outbox.to_str().unwrap()]);
+    fs::write(&input, rows).unwrap();
+    let before = run(&[input.to_str().unwrap()]);
+
+    assert_eq!(sorted_rows(&run(&["rebuild", outbox.to_str().unwrap(), "--undo", "2"])), sorted_rows(&before));
+    assert!(run_failing(&["rebuild", outbox.to_str().unwrap(), "--undo", "7"]).contains("only has 6 applied events"));
+    assert!(run_failing(&[input.to_str().unwrap(), "--undo", "1"]).contains("--undo only applies to the rebuild command"));
+    fs::remove_file(input).unwrap();
+    fs::remove_file(outbox).unwrap();
+}
+
+
+#[test]
+fn as_of_keeps_the_rows_timestamped_exactly_at_its_date() {
+    let input = std::env::temp_dir().join(format!("as-of-boundary-{}.csv", std::process::id()));
+    fs::write(&input, "type,client,tx,amount,timestamp\ndeposit,1,1,10,2024-02-29T23:59:59Z\ndeposit,1,2,20,2024-03-01T00:00:00Z\ndeposit,1,3,40,2024-03-01T00:00:01Z\n").unwrap();
+    let at_midnight = run(&[input.to_str().unwrap(), "--as-of", "2024-03-01"]);
+    let before_midnight = run(&[input.to_str().unwrap(), "--as-of", "2024-02-29T23:59:59Z"]);
+    fs::remove_file(input).unwrap();
+
+    assert_eq!(sorted_rows(&at_midnight), vec!["client,available,held,total,locked", "1,30,0,30,false"]);
+    assert_eq!(sorted_rows(&before_midnight), vec!["client,available,held,total,locked", "1,10,0,10,false"]);
+}